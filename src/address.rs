@@ -0,0 +1,150 @@
+use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
+use ed25519_dalek::VerifyingKey;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+/// Number of bytes of the hash of the public key kept in an address.
+pub const ADDRESS_LEN: usize = 20;
+
+/// First byte of every encoded address, so that addresses can't be mistaken for other base58
+/// strings.
+const ADDRESS_VERSION: u8 = 0x3c;
+
+/// The address of an account: the first 20 bytes of the SHA-256 hash of its ed25519 public key.
+///
+/// Its text form is the base58check encoding of the hash, which detects typos.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Address([u8; ADDRESS_LEN]);
+
+impl Address {
+    pub fn from_public_key(key: &VerifyingKey) -> Self {
+        Self::from_hash_of(key.as_bytes())
+    }
+
+    /// Returns the address made of the first 20 bytes of the SHA-256 hash of `data`.
+    pub(crate) fn from_hash_of(data: &[u8]) -> Self {
+        let hash = Sha256::digest(data);
+        let mut bytes = [0; ADDRESS_LEN];
+        bytes.copy_from_slice(&hash[..ADDRESS_LEN]);
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; ADDRESS_LEN] {
+        &self.0
+    }
+}
+
+impl From<&VerifyingKey> for Address {
+    fn from(key: &VerifyingKey) -> Self {
+        Self::from_public_key(key)
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let encoded = bs58::encode(self.0).with_check_version(ADDRESS_VERSION).into_string();
+        write!(f, "{encoded}")
+    }
+}
+
+impl Debug for Address {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+impl FromStr for Address {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let decoded = bs58::decode(s)
+            .with_check(Some(ADDRESS_VERSION))
+            .into_vec()
+            .map_err(|e| match e {
+                bs58::decode::Error::InvalidCharacter { character, index } => AddressError::InvalidCharacter { character, index },
+                bs58::decode::Error::NonAsciiCharacter { index } => AddressError::InvalidCharacter { character: s[index..].chars().next().unwrap_or('?'), index },
+                bs58::decode::Error::InvalidVersion { .. } => AddressError::WrongVersion,
+                _ => AddressError::InvalidChecksum,
+            })?;
+
+        // The decoded bytes start with the version
+        let bytes: [u8; ADDRESS_LEN] = decoded[1..]
+            .try_into()
+            .map_err(|_| AddressError::InvalidLength(decoded.len() - 1))?;
+        Ok(Self(bytes))
+    }
+}
+
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Reasons why a text is not a valid address.
+#[derive(Debug, PartialEq)]
+pub enum AddressError {
+    /// The text contains a character that is not used by base58
+    InvalidCharacter { character: char, index: usize },
+    /// The checksum does not match: the address was mistyped
+    InvalidChecksum,
+    /// The text is a base58check string, but not an address
+    WrongVersion,
+    /// The address does not hold a hash of the expected length
+    InvalidLength(usize),
+}
+
+impl Display for AddressError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AddressError::InvalidCharacter { character, index } => write!(f, "Invalid character '{character}' at position {index}"),
+            AddressError::InvalidChecksum => write!(f, "Invalid checksum"),
+            AddressError::WrongVersion => write!(f, "Not an address"),
+            AddressError::InvalidLength(len) => write!(f, "Expected {ADDRESS_LEN} bytes, found {len}"),
+        }
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+#[cfg(test)]
+mod tests {
+    use crate::address::{Address, AddressError};
+    use crate::client::Client;
+
+    #[test]
+    fn test_address_round_trip() {
+        let address = Client::new().address();
+        let text = address.to_string();
+        assert_eq!(Ok(address), text.parse());
+
+        let as_json = serde_json::to_string(&address).unwrap();
+        assert_eq!(format!("\"{text}\""), as_json);
+        assert_eq!(address, serde_json::from_str::<Address>(&as_json).unwrap());
+    }
+
+    #[test]
+    fn test_invalid_addresses_are_refused() {
+        let text = Client::new().address().to_string();
+
+        // Change a single character
+        let last = text.chars().last().unwrap();
+        let typo = format!("{}{}", &text[..text.len() - 1], if last == '2' { '3' } else { '2' });
+        assert_eq!(Err(AddressError::InvalidChecksum), typo.parse::<Address>());
+
+        // '0' is not part of the base58 alphabet
+        assert_eq!(Err(AddressError::InvalidCharacter { character: '0', index: 0 }), format!("0{text}").parse::<Address>());
+
+        // A valid base58check string, which is not an address
+        let other = bs58::encode([0u8; 20]).with_check_version(0).into_string();
+        assert_eq!(Err(AddressError::WrongVersion), other.parse::<Address>());
+    }
+}
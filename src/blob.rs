@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use serde::{Deserialize, Serialize};
+use sha256::digest;
+use crate::signed_transaction::Payload;
+use crate::transaction::push_field;
+
+/// Maximum length, in bytes, of a chunk.
+pub const MAX_CHUNK_LEN: usize = 8 * 1024;
+
+/// Maximum number of chunks of a blob, which can therefore hold up to 8 MiB.
+pub const MAX_BLOB_CHUNKS: usize = 1024;
+
+/// Reasons for a chunk or a manifest to be refused, or for a blob to be unavailable.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BlobError {
+    /// The chunk is empty, or longer than `MAX_CHUNK_LEN`
+    InvalidChunk,
+    /// The manifest lists no chunk, or more than `MAX_BLOB_CHUNKS`
+    TooManyChunks,
+    /// The chunk, or the manifest, is already stored
+    AlreadyStored,
+    /// The manifest lists a chunk that was not stored before it
+    MissingChunk,
+    /// The size of the manifest is not the sum of the lengths of its chunks
+    SizeMismatch,
+    /// No manifest was stored for this blob
+    UnknownBlob,
+    /// The reassembled blob does not have the digest of its manifest
+    Corrupted,
+}
+
+impl std::error::Error for BlobError {}
+
+impl Display for BlobError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlobError::InvalidChunk => write!(f, "invalid chunk"),
+            BlobError::TooManyChunks => write!(f, "too many chunks"),
+            BlobError::AlreadyStored => write!(f, "already stored"),
+            BlobError::MissingChunk => write!(f, "missing chunk"),
+            BlobError::SizeMismatch => write!(f, "size mismatch"),
+            BlobError::UnknownBlob => write!(f, "unknown blob"),
+            BlobError::Corrupted => write!(f, "corrupted blob"),
+        }
+    }
+}
+
+/// The description of a blob stored in chunks: the chunks are referenced by their SHA-256
+/// digest, and the whole blob by its own.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    /// SHA-256 digest of the blob, in hex
+    pub digest: String,
+    /// Length of the blob, in bytes
+    pub size: u64,
+    /// Digests of the chunks, in order
+    pub chunks: Vec<String>,
+}
+
+impl Manifest {
+    pub(crate) fn push_bytes(&self, bytes: &mut Vec<u8>) {
+        push_field(bytes, self.digest.as_bytes());
+        bytes.extend_from_slice(&self.size.to_be_bytes());
+        bytes.extend_from_slice(&(self.chunks.len() as u32).to_be_bytes());
+        for chunk in &self.chunks {
+            push_field(bytes, chunk.as_bytes());
+        }
+    }
+}
+
+/// Splits a blob in chunks, and returns them with their manifest.
+///
+/// Identical chunks are only stored once, and the chunks must be written on the chain before the
+/// manifest, either in earlier blocks or earlier in the same block.
+pub fn split(blob: &[u8]) -> (Vec<Vec<u8>>, Manifest) {
+    let chunks: Vec<Vec<u8>> = blob.chunks(MAX_CHUNK_LEN).map(<[u8]>::to_vec).collect();
+    let manifest = Manifest {
+        digest: digest(blob),
+        size: blob.len() as u64,
+        chunks: chunks.iter().map(digest).collect(),
+    };
+    (chunks, manifest)
+}
+
+/// Where a chunk is stored on the main chain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct ChunkLocation {
+    /// Height of the block holding the chunk
+    pub height: u64,
+    pub len: usize,
+}
+
+/// The chunks and manifests stored on the main chain.
+///
+/// Chunks are addressed by their digest, so the same content is only stored once. A manifest is
+/// only accepted once all its chunks are stored, so that every blob listed can be reassembled.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BlobStore {
+    chunks: HashMap<String, ChunkLocation>,
+    manifests: HashMap<String, Manifest>,
+}
+
+impl BlobStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn chunk(&self, digest: &str) -> Option<ChunkLocation> {
+        self.chunks.get(digest).copied()
+    }
+
+    /// Returns the manifest of the blob of the given digest.
+    pub fn manifest(&self, digest: &str) -> Option<&Manifest> {
+        self.manifests.get(digest)
+    }
+
+    /// Checks that the chunk or the manifest of the payload can be stored. Other payloads are
+    /// always accepted.
+    pub fn check(&self, payload: &Payload) -> Result<(), BlobError> {
+        self.check_with_pending(payload, |_| None)
+    }
+
+    /// Checks the payload like `check`, but a manifest may also list the chunks for which
+    /// `pending` gives a length, such as the chunks waiting in a mempool.
+    pub fn check_with_pending(&self, payload: &Payload, pending: impl Fn(&str) -> Option<usize>) -> Result<(), BlobError> {
+        match payload {
+            Payload::Chunk(data) => {
+                if data.is_empty() || data.len() > MAX_CHUNK_LEN {
+                    return Err(BlobError::InvalidChunk);
+                }
+                if self.chunks.contains_key(&digest(data.as_slice())) {
+                    return Err(BlobError::AlreadyStored);
+                }
+                Ok(())
+            }
+            Payload::Manifest(manifest) => {
+                if manifest.chunks.is_empty() || manifest.chunks.len() > MAX_BLOB_CHUNKS {
+                    return Err(BlobError::TooManyChunks);
+                }
+                if self.manifests.contains_key(&manifest.digest) {
+                    return Err(BlobError::AlreadyStored);
+                }
+                let mut size: u64 = 0;
+                for chunk in &manifest.chunks {
+                    let len = self.chunks
+                        .get(chunk)
+                        .map(|location| location.len)
+                        .or_else(|| pending(chunk))
+                        .ok_or(BlobError::MissingChunk)?;
+                    size += len as u64;
+                }
+                if size != manifest.size {
+                    return Err(BlobError::SizeMismatch);
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Stores the chunk or the manifest of the payload, written in a block of the given height.
+    /// Nothing changes if it is refused.
+    pub fn apply(&mut self, payload: &Payload, height: u64) -> Result<(), BlobError> {
+        self.check(payload)?;
+        match payload {
+            Payload::Chunk(data) => {
+                self.chunks.insert(digest(data.as_slice()), ChunkLocation { height, len: data.len() });
+            }
+            Payload::Manifest(manifest) => {
+                self.manifests.insert(manifest.digest.clone(), manifest.clone());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blob::{split, BlobError, MAX_CHUNK_LEN};
+    use crate::blockchain::Blockchain;
+    use crate::client::Client;
+    use crate::signed_transaction::Payload;
+    use crate::transaction::{Transaction, TransactionError};
+
+    #[test]
+    fn test_blob_is_reassembled() {
+        let mut alice = Client::new();
+        let mut chain = Blockchain::new();
+        // The chunks differ, otherwise they would be stored once
+        let blob: Vec<u8> = (0..3 * MAX_CHUNK_LEN + 10).map(|i| (i % 251) as u8).collect();
+        let (chunks, manifest) = split(&blob);
+        assert_eq!(4, chunks.len());
+
+        // The manifest can't be written before its chunks
+        let early = alice.sign_payload(Payload::Manifest(manifest.clone()), 0).into();
+        assert_eq!(Err(TransactionError::Blob(BlobError::MissingChunk)), chain.check_transaction(&early));
+
+        // Chunks are spread over two blocks, the second one ending with the manifest
+        let mut txs: Vec<Transaction> = chunks.into_iter().map(|chunk| alice.sign_payload(Payload::Chunk(chunk), 0).into()).collect();
+        let mut second = txs.split_off(2);
+        chain.add_test_block(txs);
+        second.push(alice.sign_payload(Payload::Manifest(manifest.clone()), 0).into());
+        chain.add_test_block(second);
+
+        assert_eq!(Ok(blob), chain.get_blob(&manifest.digest));
+        assert_eq!(Err(BlobError::UnknownBlob), chain.get_blob("unknown"));
+    }
+
+    #[test]
+    fn test_invalid_chunks_and_manifests_are_refused() {
+        let mut alice = Client::new();
+        let mut chain = Blockchain::new();
+        let oversized = alice.sign_payload(Payload::Chunk(vec![0; MAX_CHUNK_LEN + 1]), 0).into();
+        assert_eq!(Err(TransactionError::Blob(BlobError::InvalidChunk)), chain.check_transaction(&oversized));
+
+        let (chunks, mut manifest) = split(b"hello");
+        let chunk = alice.sign_payload(Payload::Chunk(chunks[0].clone()), 0);
+        chain.add_test_block(vec![chunk.into()]);
+        let again = alice.sign_payload(Payload::Chunk(chunks[0].clone()), 0).into();
+        assert_eq!(Err(TransactionError::Blob(BlobError::AlreadyStored)), chain.check_transaction(&again));
+
+        manifest.size += 1;
+        let lying = alice.sign_payload(Payload::Manifest(manifest), 0).into();
+        assert_eq!(Err(TransactionError::Blob(BlobError::SizeMismatch)), chain.check_transaction(&lying));
+    }
+}
@@ -1,14 +1,70 @@
+use crate::address::Address;
+use crate::coinbase::Coinbase;
+use crate::contract::ContractState;
+use crate::merkle::merkle_root;
 use crate::simple_transaction::SimpleTransaction;
+use crate::transaction::{Transaction, TransactionError};
+use crate::validity::unix_time;
 use std::fmt::{Debug, Formatter};
 use serde::{Deserialize, Serialize};
 use sha256::digest;
 
+/// Maximum size, in bytes, of the transactions of a block.
+pub const MAX_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Nonce of the genesis block, found for a difficulty of 5 zeros.
+const GENESIS_NONCE: u64 = 1296772;
+
+/// The part of a block covered by its hash.
+///
+/// It commits to the transactions of the block through their Merkle root, so that a client
+/// holding only the headers of the chain can check that a transaction was written in a block.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub previous_hash: Option<String>,
+    /// Root of the Merkle tree of the bytes of the transactions
+    pub merkle_root: String,
+    pub nonce: u64,
+    pub timestamp: u64,
+    pub state_root: String,
+}
+
+impl BlockHeader {
+    /// Returns the bytes hashed to obtain the hash of the block.
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend_from_slice(self.merkle_root.as_bytes());
+        bytes.extend_from_slice(&self.nonce.to_le_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_le_bytes());
+        bytes.extend_from_slice(self.state_root.as_bytes());
+        if let Some(hash) = &self.previous_hash {
+            bytes.extend_from_slice(hash.as_bytes());
+        }
+        bytes
+    }
+
+    pub fn hash(&self) -> String {
+        digest(self.bytes())
+    }
+
+    pub fn is_hash_valid(&self, difficulty: usize) -> bool {
+        let start_pattern = String::from_utf8(vec![b'0'; difficulty]).unwrap();
+        self.hash().starts_with(&start_pattern)
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Block {
-    transactions: SimpleTransaction,
+    transactions: Vec<Transaction>,
     previous_hash: Option<String>,
     nonce: u64,
     index_in_chain: u64,
+    /// Time at which the block was created, in seconds since the Unix epoch
+    #[serde(default)]
+    timestamp: u64,
+    /// Hash of the state of the contracts after the transactions of the block
+    state_root: String,
 }
 
 impl Block {
@@ -16,21 +72,39 @@ impl Block {
     /// This is a block with no data and with a nonce computed for a difficulty of 5 zeros.
     pub fn genesis() -> Self {
         Self {
-            transactions: SimpleTransaction::from_str(""),
-            // This nonce was generated for a difficulty of 5 zeros
-            nonce: 1293653,
+            transactions: vec![SimpleTransaction::from_str("").into()],
+            nonce: GENESIS_NONCE,
             index_in_chain: 0,
-            previous_hash: None
+            previous_hash: None,
+            timestamp: 0,
+            state_root: ContractState::new().root(),
         }
     }
 
-    /// Build a new block located after the given block.
-    pub fn new_after_block(data: SimpleTransaction, previous: &Block) -> Self {
+    /// Build a new block located after the given block, holding a single transaction.
+    pub fn new_after_block(data: impl Into<Transaction>, previous: &Block) -> Self {
+        Self::with_transactions(vec![data.into()], previous)
+    }
+
+    /// Build a new block located after the given block, holding a coinbase which claims no reward
+    /// for the miner, then a single transaction.
+    pub fn new_rewarded_after_block(data: impl Into<Transaction>, previous: &Block, miner: &Address) -> Self {
+        let coinbase = Coinbase::new(previous.index_in_chain + 1, 0, *miner);
+        Self::with_transactions(vec![coinbase.into(), data.into()], previous)
+    }
+
+    /// Build a new block located after the given block, holding several transactions.
+    ///
+    /// The block is timestamped with the current time, unless its parent is more recent. It keeps
+    /// the state root of its parent, which must be updated if the transactions touch a contract.
+    pub fn with_transactions(transactions: Vec<Transaction>, previous: &Block) -> Self {
         Self {
-            transactions: data,
+            transactions,
             previous_hash: Some(previous.hash()),
             nonce: 0,
-            index_in_chain: previous.index_in_chain + 1
+            index_in_chain: previous.index_in_chain + 1,
+            timestamp: unix_time().max(previous.timestamp),
+            state_root: previous.state_root.clone(),
         }
     }
 
@@ -38,6 +112,22 @@ impl Block {
         self.nonce = nonce;
     }
 
+    pub fn set_timestamp(&mut self, timestamp: u64) {
+        self.timestamp = timestamp;
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn set_state_root(&mut self, state_root: String) {
+        self.state_root = state_root;
+    }
+
+    pub fn state_root(&self) -> &str {
+        &self.state_root
+    }
+
     pub fn set_previous_hash(&mut self, previous_hash: String) {
         self.previous_hash = Some(previous_hash);
     }
@@ -47,29 +137,48 @@ impl Block {
     }
 
     pub fn hash(&self) -> String {
-        let data = self.bytes();
-        digest(data)
+        self.header().hash()
     }
     
     pub fn is_hash_valid(&self, difficulty: usize) -> bool {
-        let start_pattern = String::from_utf8(vec![b'0'; difficulty]).unwrap();
-        self.hash().starts_with(&start_pattern)
+        self.header().is_hash_valid(difficulty)
+    }
+
+    /// Returns the header of this block, from which its hash is computed.
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            previous_hash: self.previous_hash.clone(),
+            merkle_root: merkle_root(&self.transaction_bytes()),
+            nonce: self.nonce,
+            timestamp: self.timestamp,
+            state_root: self.state_root.clone(),
+        }
     }
 
     /// Returns a bytes representation of this block
     pub fn bytes(&self) -> Vec<u8> {
-        let mut bytes: Vec<u8> = self.transactions.to_bytes();
-        bytes.extend_from_slice(&self.nonce.to_le_bytes());
-        if let Some(hash) = &self.previous_hash {
-            bytes.extend_from_slice(hash.as_bytes());
-        }
-        bytes
+        self.header().bytes()
     }
 
-    pub fn transactions(&self) -> &SimpleTransaction {
+    /// Returns the bytes of each transaction, the leaves of the Merkle tree of the block.
+    pub fn transaction_bytes(&self) -> Vec<Vec<u8>> {
+        self.transactions.iter().map(Transaction::to_bytes).collect()
+    }
+
+    pub fn transactions(&self) -> &[Transaction] {
         &self.transactions
     }
 
+    /// Returns the size of the transactions of the block, in bytes.
+    pub fn size(&self) -> usize {
+        self.transactions.iter().map(|tx| tx.to_bytes().len()).sum()
+    }
+
+    /// Checks the signatures of all the transactions of the block.
+    pub fn verify_transactions(&self) -> Result<(), TransactionError> {
+        self.transactions.iter().try_for_each(|tx| tx.verify())
+    }
+
     pub fn nonce(&self) -> u64 {
         self.nonce
     }
@@ -111,4 +220,9 @@ mod tests {
         let b2_parsed: Block = serde_json::from_str(&as_json).unwrap();
         assert_eq!(b2.hash(), b2_parsed.hash());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_genesis_hash_is_valid() {
+        assert!(Block::genesis().is_hash_valid(5));
+    }
+}
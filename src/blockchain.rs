@@ -1,11 +1,50 @@
-use std::cmp::max;
-use std::collections::{HashMap, VecDeque};
-use crate::block::Block;
-use crate::simple_transaction::SimpleTransaction;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io;
+use std::path::Path;
+use crate::address::Address;
+use crate::blob::BlobError;
+use crate::block::{Block, BlockHeader, MAX_BLOCK_SIZE};
+use crate::coinbase::{Coinbase, RewardSchedule};
+use crate::merkle::merkle_path;
+use crate::names::NameEntry;
+use crate::notary::{notarized_digest, ExistenceProof};
+use crate::signed_transaction::Payload;
+use crate::state::Ledger;
+use crate::transaction::{Transaction, TransactionError};
+use crate::utxo::UtxoSet;
+use crate::validity::{unix_time, MAX_FUTURE_DRIFT};
 
 /// Depth below the head of a chain after which we consider that all workers must have agreed on.
 const SAFE_HORIZON: i64 = 10;
 
+/// A switch of the main chain onto one of the forks.
+#[derive(Debug)]
+pub struct Reorg {
+    /// Blocks removed from the main chain, from the oldest to the newest
+    pub disconnected: Vec<Block>,
+    /// Blocks added to the main chain, from the oldest to the newest
+    pub connected: Vec<Block>,
+}
+
+impl Reorg {
+    /// Number of blocks of the previous main chain that were abandoned.
+    pub fn depth(&self) -> usize {
+        self.disconnected.len()
+    }
+}
+
+/// Where a block ends up when it is added to the blockchain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Placement {
+    /// At the head of the main chain
+    MainChain,
+    /// At the head of a forked chain, or at the root of a new fork
+    Fork,
+    /// The block can't be attached to any chain yet
+    Orphan,
+}
+
 /// Keeps track of the main chain and of possible divergence on the last `SAFE_HORIZON` layers.
 pub struct Blockchain {
     chain: Vec<Block>,
@@ -14,115 +53,326 @@ pub struct Blockchain {
     /// TODO (optimization) store the index of the root instead of storing the hash of the root
     pending_forks: HashMap<String, Vec<Block>>,
     /// A pool of blocks that worker received but that can't be attached to no other.
-    orphan: VecDeque<Block>
+    orphan: VecDeque<Block>,
+    /// Balances of the accounts, after the last block of the main chain
+    ledger: Ledger,
+    /// Unspent outputs, after the last block of the main chain
+    utxos: UtxoSet,
+    /// Reward that the miner of each block can claim
+    rewards: RewardSchedule,
+    /// Height of the block of the main chain containing each transaction, keyed by its id
+    tx_index: HashMap<String, u64>,
+    /// Heights of the blocks of the main chain notarizing each document, keyed by its digest
+    documents: HashMap<String, Vec<u64>>,
 }
 
 impl Blockchain {
     /// Creates a new blockchain, containing a single block (the genesis)
     pub fn new() -> Self {
+        Self::with_ledger(Ledger::new())
+    }
+
+    /// Creates a new blockchain whose accounts start with the balances of the given ledger.
+    pub fn with_ledger(ledger: Ledger) -> Self {
+        Self::with_genesis(ledger, UtxoSet::new())
+    }
+
+    /// Creates a new blockchain starting with the given balances and unspent outputs.
+    pub fn with_genesis(ledger: Ledger, utxos: UtxoSet) -> Self {
         let genesis = Block::genesis();
-        Self {
-            chain: vec![genesis],
+        let mut blockchain = Self {
+            chain: Vec::new(),
             pending_forks: HashMap::new(),
-            orphan: VecDeque::new()
-        }
+            orphan: VecDeque::new(),
+            ledger,
+            utxos,
+            rewards: RewardSchedule::default(),
+            tx_index: HashMap::new(),
+            documents: HashMap::new(),
+        };
+        blockchain.index_block(&genesis);
+        blockchain.chain.push(genesis);
+        blockchain
+    }
+
+    /// Uses the given reward schedule instead of the default one.
+    pub fn with_rewards(mut self, rewards: RewardSchedule) -> Self {
+        self.rewards = rewards;
+        self
     }
     
-    pub fn add_block_unsafe(&mut self, block: Block) {
+    /// Loads a main chain written with `save`, on top of the given initial balances and reward
+    /// schedule, which must be the ones the chain was built with.
+    ///
+    /// Blocks are added as if they were received from the network, so a corrupted file can't
+    /// produce an inconsistent chain: a block which doesn't extend the main chain is an error.
+    pub fn load(path: &Path, ledger: Ledger, rewards: RewardSchedule) -> io::Result<Self> {
+        let blocks: Vec<Block> = serde_json::from_slice(&fs::read(path)?)?;
+        let mut blockchain = Self::with_ledger(ledger).with_rewards(rewards);
+        let mut blocks = blocks.into_iter();
+        if blocks.next().map(|genesis| genesis.hash()) != Some(blockchain.last_block().hash()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the chain doesn't start with the genesis"));
+        }
+        for block in blocks {
+            let index = block.index_in_chain();
+            match blockchain.add_block(block) {
+                Ok(Placement::MainChain) => {}
+                Ok(placement) => {
+                    let message = format!("block {index} doesn't extend the main chain ({placement:?})");
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+                }
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("block {index}: {e}"))),
+            }
+        }
+        Ok(blockchain)
+    }
+
+    /// Writes the main chain to a file, as JSON.
+    ///
+    /// The chain is first written to a temporary file, so that an interrupted write never
+    /// corrupts a previous save.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&self.chain)?)?;
+        fs::rename(tmp, path)
+    }
+
+    /// Adds a block on top of the main chain without checking its proof of work, typically a
+    /// block mined by this node. A block refused by the ledger is not added.
+    pub fn add_block_unsafe(&mut self, block: Block) -> Result<(), TransactionError> {
+        self.connect_block(&block)?;
+        self.index_block(&block);
         self.chain.push(block);
+        Ok(())
+    }
+
+    /// Adds the transactions of a block of the main chain to the index.
+    fn index_block(&mut self, block: &Block) {
+        for tx in block.transactions() {
+            self.tx_index.insert(tx.id(), block.index_in_chain());
+            if let Some(digest) = notarized_digest(tx) {
+                self.documents.entry(digest.to_string()).or_default().push(block.index_in_chain());
+            }
+        }
+    }
+
+    /// Removes the transactions of a block leaving the main chain from the index.
+    fn unindex_block(&mut self, block: &Block) {
+        for tx in block.transactions() {
+            let id = tx.id();
+            if self.tx_index.get(&id) == Some(&block.index_in_chain()) {
+                self.tx_index.remove(&id);
+            }
+            if let Some(digest) = notarized_digest(tx) {
+                if let Some(heights) = self.documents.get_mut(digest) {
+                    heights.retain(|height| *height != block.index_in_chain());
+                    if heights.is_empty() {
+                        self.documents.remove(digest);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Checks that each transaction of the blocks appears only once in them, and is not written
+    /// in the main chain up to the given height.
+    fn check_unique_transactions(&self, blocks: &[Block], height: u64) -> Result<(), TransactionError> {
+        let mut ids = HashSet::new();
+        for tx in blocks.iter().flat_map(Block::transactions) {
+            let id = tx.id();
+            let is_written = self.tx_index.get(&id).is_some_and(|written| *written <= height);
+            if is_written || !ids.insert(id) {
+                return Err(TransactionError::DuplicateTransaction);
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies a block at the head of the main chain to the ledger and to the UTXO set.
+    /// Nothing changes if the block is refused by one of them, or if one of its transactions is
+    /// already written.
+    fn connect_block(&mut self, block: &Block) -> Result<(), TransactionError> {
+        self.check_unique_transactions(std::slice::from_ref(block), u64::MAX)?;
+        self.utxos.connect_block(block, &self.rewards)?;
+        if let Err(e) = self.ledger.apply_block(block) {
+            self.utxos.disconnect_block(block);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Checks that the block is not older than its parent, and that all its transactions can be
+    /// written at its height and timestamp.
+    fn check_block_locks(block: &Block, previous: &Block) -> Result<(), TransactionError> {
+        if block.timestamp() < previous.timestamp() {
+            return Err(TransactionError::InvalidTimestamp);
+        }
+        block.transactions()
+            .iter()
+            .try_for_each(|tx| tx.validity().check(block.index_in_chain(), block.timestamp()))
+    }
+
+    /// Computes the unspent outputs after switching from the blocks `disconnected` to the blocks
+    /// `connected`, without touching the current set.
+    fn switch_utxos(&self, disconnected: &[Block], connected: &[Block]) -> Result<UtxoSet, TransactionError> {
+        let mut utxos = self.utxos.clone();
+        for block in disconnected.iter().rev() {
+            utxos.disconnect_block(block);
+        }
+        for block in connected {
+            utxos.connect_block(block, &self.rewards)?;
+        }
+        Ok(utxos)
     }
 
     /// Internal function to assign blocks to one of the chain.
     ///
     /// Tries to add the block first to the main chain, then to any of the forked chain.
+    /// Blocks added to the main chain must be valid for the ledger; blocks added to a fork are
+    /// only checked if the fork becomes the main chain.
     ///
-    /// Returns  (added_to_main_chain, added_to_forked_chain), that is:
-    ///
-    /// * (true,  _)      if block was added on the main chain
-    /// * (false, true)   if the block was added on one of the forked chain
-    /// * (false, false)  if the block was not added to ANY chain, meaning that this block is an
-    ///                   orphan.
-    fn assign_block(&mut self, block: Block) -> (bool, bool) {
+    /// Returns `Placement::Orphan` if the block was not added to ANY chain, and an error if it
+    /// would extend the main chain but is refused.
+    fn assign_block(&mut self, block: Block) -> Result<Placement, TransactionError> {
         // The previous hash is the key that indicates where this block is linked.
         let previous_hash = block.previous_hash().unwrap();
 
         // Try to add this block to the main chain
         if previous_hash == self.chain.last().unwrap().hash() {
+            let checked = Self::check_block_locks(&block, self.last_block())
+                .and_then(|_| self.connect_block(&block));
+            if let Err(e) = checked {
+                log::warn!("Block {} refused: {e}", block.hash());
+                return Err(e)
+            }
+            self.index_block(&block);
             self.chain.push(block);
-            (true, true)
+            Ok(Placement::MainChain)
         } else {
             // Try to place this block at the head of one of the forked chain
             for (_, chain) in &mut self.pending_forks {
                 // Try to place this block on the given chain
                 if previous_hash == chain.last().unwrap().hash() {
                     chain.push(block);
-                    return (false, true)
+                    return Ok(Placement::Fork)
                 }
             }
-            (false, false)
+            Ok(Placement::Orphan)
+        }
+    }
+
+    /// Places a block on the main chain, on one of the forked chains, or at the root of a new fork.
+    fn place_block(&mut self, block: Block) -> Result<Placement, TransactionError> {
+        let previous_hash = block.previous_hash().unwrap();
+        let placement = self.assign_block(block.clone())?;
+        if placement != Placement::Orphan {
+            return Ok(placement)
         }
+
+        // Not a single hypothesis could accept the new block at his head, and the main chain
+        // could not either. This block is the start of a new fork if its parent is in the main
+        // chain.
+        let is_new_fork = self.chain.iter().any(|block| block.hash() == previous_hash);
+        if is_new_fork {
+            self.pending_forks.insert(previous_hash, vec![block]);
+            return Ok(Placement::Fork)
+        }
+        Ok(Placement::Orphan)
+    }
+
+    /// Returns true if the block is already part of the main chain or of one of the forks.
+    fn contains_block(&self, hash: &str) -> bool {
+        self.chain.iter()
+            .chain(self.pending_forks.values().flatten())
+            .any(|block| block.hash() == hash)
     }
 
     /// Returns true if the main chain was updated, false otherwise.
     /// If the block is not set inserted in the main chain, it is kept as a hypothesis or as an orphan
     pub fn add_block_safe(&mut self, block: Block) -> bool {
-        let (added_to_main, added_to_forked) = self.assign_block(block.clone());
-        if !added_to_main && !added_to_forked {
-            // If we arrived here, it means that
-            // - not a single hypothesis could accept the new block at his head)
-            // - the main chain could not
-            // There are two possibilities now
-            // 1. This block is the start of a new fork
-            //    In this case, we can check whether the previous hash is in the main chain
-            // 2. This block was received 'too' early and is not attached to any of the previous
-            //    block. This happens when the communication fails. In this case, we store it
-            //    and will try later on to fit it somewhere
-
-            let previous_hash = block.previous_hash().unwrap();
-            let is_new_fork = self.chain.iter().any(|block| block.hash() == previous_hash);
-            if is_new_fork {
-                self.pending_forks.insert(previous_hash, vec![block]);
-            } else {
-                self.orphan.push_back(block);
-            }
+        matches!(self.add_block(block), Ok(Placement::MainChain))
+    }
 
-            return false
-        } else {
-            // Since we managed to assign the block, we can try to assign our orphan blocks
+    /// Adds a block received from the network, and returns where it was placed.
+    ///
+    /// The block is refused if it is already known, if it is invalid on its own, or if it would
+    /// extend the main chain but is refused by the ledger or the UTXO set.
+    pub fn add_block(&mut self, block: Block) -> Result<Placement, TransactionError> {
+        // A block can be received several times, for instance when a peer announces its chain.
+        if self.contains_block(&block.hash()) {
+            return Err(TransactionError::KnownBlock)
+        }
+
+        // Only the genesis has no parent
+        if block.previous_hash().is_none() {
+            return Err(TransactionError::Malformed)
+        }
+
+        if let Err(e) = block.verify_transactions() {
+            log::warn!("Block {} refused: {e}", block.hash());
+            return Err(e)
+        }
+
+        if block.timestamp() > unix_time().saturating_add(MAX_FUTURE_DRIFT) {
+            log::warn!("Block {} refused: {}", block.hash(), TransactionError::InvalidTimestamp);
+            return Err(TransactionError::InvalidTimestamp)
+        }
+
+        if block.size() > MAX_BLOCK_SIZE {
+            log::warn!("Block {} refused: {}", block.hash(), TransactionError::BlockTooLarge);
+            return Err(TransactionError::BlockTooLarge)
+        }
+
+        let placement = self.place_block(block.clone())?;
+        if placement == Placement::Orphan {
+            // This block was received 'too' early and is not attached to any of the previous
+            // block. This happens when the communication fails. In this case, we store it
+            // and will try later on to fit it somewhere
+            self.orphan.push_back(block);
+            return Ok(placement)
+        }
+
+        // Since we managed to place the block, we can try to place our orphan blocks.
+        // Orphans may depend on each other, so we loop until no more orphan can be placed.
+        let mut placed_orphan = true;
+        while placed_orphan {
+            placed_orphan = false;
             let mut new_orphan = VecDeque::new();
             // TODO I see that this is terrible in term of memory... 
             //      But it shouldn't be too hard to improve.
             while let Some(lonely_block) = self.orphan.pop_front() {
-                let (main, forked) = self.assign_block(lonely_block.clone());
-                if !main && !forked {
-                    new_orphan.push_back(lonely_block);
+                if self.contains_block(&lonely_block.hash()) {
+                    continue;
+                }
+                match self.place_block(lonely_block.clone()) {
+                    Ok(Placement::Orphan) => new_orphan.push_back(lonely_block),
+                    _ => placed_orphan = true,
                 }
             }
             self.orphan = new_orphan;
         }
 
-        // After adding a block, we can now try to place our oprhan...
-        // TODO
-
-
-        added_to_main
+        Ok(placement)
     }
 
     /// We check all the hypothesis over our main chain.
     /// If one of them is longer, then we switch to this one.
-    pub fn resolve_pending_forks(&mut self) {
+    ///
+    /// Returns the reorganization of the main chain, if one happened.
+    pub fn resolve_pending_forks(&mut self) -> Option<Reorg> {
         let len = (self.chain.len() - 1) as u64;
 
         // Find the longest chain among the forks
         let best_fork = self.pending_forks
             .iter()
-            .filter(|(start, chain)|
+            .filter(|(_, chain)|
                 chain.last().map(|block| block.index_in_chain()).unwrap_or(0) > len
             ).max_by_key(|(_, chain)|
                 chain.last().map(|block| block.index_in_chain()).unwrap_or(0)
             );
 
         // If we have found a better fork, then perform the swapping
+        let mut reorg = None;
         if best_fork.is_some() {
             // Remove the best fork from the pending ones.
             // This allows us to get ownership.
@@ -131,12 +381,32 @@ impl Blockchain {
             log::error!("WE ARE SWAPPING THE MAIN BRANCH");
 
             if let Some(root) = self.chain.iter().position(|b| b.hash() == start) {
-                // Remove everything after the root
-                self.chain.truncate(root + 1);
+                // The balances after the new chain are computed from scratch, and the unspent
+                // outputs by undoing the abandoned blocks: if one of the new blocks is invalid,
+                // the fork is abandoned.
+                let candidate: Vec<Block> = self.chain[..=root].iter().chain(&new_chain).cloned().collect();
+                let locks = candidate[root..]
+                    .windows(2)
+                    .try_for_each(|pair| Self::check_block_locks(&pair[1], &pair[0]))
+                    .and_then(|_| self.check_unique_transactions(&new_chain, root as u64));
+                let state = locks.and_then(|_| self.ledger.replay(&candidate)).and_then(|ledger| {
+                    let utxos = self.switch_utxos(&self.chain[root + 1..], &new_chain)?;
+                    Ok((ledger, utxos))
+                });
+                match state {
+                    Ok((ledger, utxos)) => {
+                        self.ledger = ledger;
+                        self.utxos = utxos;
+
+                        // Remove everything after the root, and add the entire new chain
+                        let disconnected = self.chain.split_off(root + 1);
+                        disconnected.iter().for_each(|block| self.unindex_block(block));
+                        new_chain.iter().for_each(|block| self.index_block(block));
+                        self.chain.extend(new_chain.iter().cloned());
 
-                // Add the entire new chain
-                for block in new_chain {
-                    self.chain.push(block)
+                        reorg = Some(Reorg { disconnected, connected: new_chain });
+                    }
+                    Err(e) => log::warn!("Fork refused: {e}"),
                 }
             }
         }
@@ -148,32 +418,195 @@ impl Blockchain {
             chain_len as i64 > len as i64 - SAFE_HORIZON
         });
 
+        reorg
     }
-    
-    pub fn last_transaction(&self) -> &SimpleTransaction {
+
+    /// Returns the balances of the accounts after the last block of the main chain.
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    /// Returns the unspent outputs after the last block of the main chain.
+    pub fn utxos(&self) -> &UtxoSet {
+        &self.utxos
+    }
+
+    pub fn rewards(&self) -> &RewardSchedule {
+        &self.rewards
+    }
+
+    /// Checks that a transaction can be added on top of the main chain, for both the accounts and
+    /// the UTXO models, in a block created now.
+    pub fn check_transaction(&self, tx: &Transaction) -> Result<(), TransactionError> {
+        let height = self.last_block().index_in_chain() + 1;
+        let timestamp = self.next_timestamp();
+        tx.validity().check(height, timestamp)?;
+        self.ledger.check_transaction(tx)?;
+        self.utxos.check_transaction(tx, height, timestamp, &self.rewards)
+    }
+
+    /// Returns true if the transaction can't be written in the next block, nor in any later one.
+    pub fn is_transaction_expired(&self, tx: &Transaction) -> bool {
+        tx.validity().is_expired(self.last_block().index_in_chain() + 1, self.next_timestamp())
+    }
+
+    /// Returns the timestamp of a block created now on top of the main chain.
+    fn next_timestamp(&self) -> u64 {
+        unix_time().max(self.last_block().timestamp())
+    }
+
+    /// Returns the blocks of the main chain, starting with the genesis.
+    pub fn blocks(&self) -> &[Block] {
+        &self.chain
+    }
+
+    /// Returns the block of the main chain with the given hash.
+    pub fn block_by_hash(&self, hash: &str) -> Option<&Block> {
+        self.chain.iter().rev().find(|block| block.hash() == hash)
+    }
+
+    pub fn last_block(&self) -> &Block {
+        self.chain.last().unwrap()
+    }
+
+    pub fn last_transaction(&self) -> &[Transaction] {
         self.chain.last().unwrap().transactions()
     }
 
-    /// Returns a block at the last stage of the chain ready to be mined
-    pub fn get_candidate_block(&self, transaction: SimpleTransaction) -> Block {
-        Block::new_after_block(transaction, self.chain.last().unwrap())
+    /// Returns a block ready to be mined, starting with a coinbase that pays the subsidy and the
+    /// fees of the block to the miner.
+    ///
+    /// Transactions are added in the given order, so they should come highest fee first. Those
+    /// that are already written, that are invalid on top of the previous ones, or that can't be
+    /// written at the height and timestamp of the block, are skipped.
+    pub fn get_rewarded_candidate_block(&self, transactions: Vec<Transaction>, miner: &Address) -> Block {
+        let height = self.last_block().index_in_chain() + 1;
+        let timestamp = self.next_timestamp();
+        let mut ledger = self.ledger.clone();
+        let mut utxos = self.utxos.clone();
+        let mut spent = Vec::new();
+        let mut selected: Vec<Transaction> = Vec::new();
+        let mut fees: u64 = 0;
+        // Room is kept for the coinbase
+        let mut size = Transaction::from(Coinbase::new(height, u64::MAX, *miner)).to_bytes().len();
+        for tx in transactions {
+            if tx.verify().is_err() || self.has_transaction(&tx) || selected.contains(&tx) {
+                continue;
+            }
+            let tx_size = tx.to_bytes().len();
+            if size + tx_size > MAX_BLOCK_SIZE {
+                log::info!("Skipping {tx:?}: the block is full");
+                continue;
+            }
+            if let Err(e) = tx.validity().check(height, timestamp) {
+                log::info!("Skipping {tx:?}: {e}");
+                continue;
+            }
+            // Each transaction only touches the ledger or the UTXO set, so at most one of them
+            // changes before a transaction is refused.
+            let fee = utxos.fee(&tx);
+            let applied = ledger.apply_transaction(&tx)
+                .and_then(|_| utxos.apply_transaction(&tx, &mut spent, height, timestamp, &self.rewards));
+            if let Err(e) = applied {
+                log::warn!("Skipping {tx:?}: it is not valid on top of the chain: {e}");
+                continue;
+            }
+            fees = fees.saturating_add(fee);
+            size += tx_size;
+            selected.push(tx);
+        }
+
+        let amount = self.rewards.subsidy(height).saturating_add(fees);
+        let coinbase = Coinbase::new(height, amount, *miner);
+        selected.insert(0, coinbase.into());
+        let mut block = Block::with_transactions(selected, self.last_block());
+        block.set_timestamp(timestamp);
+        block.set_state_root(ledger.contracts().root());
+        block
     }
 
     pub fn len(&self) -> usize {
         self.chain.len()
     }
 
-    pub fn has_transaction(&self, tx: &SimpleTransaction) -> bool {
-        self.chain.iter().any(|block| block.transactions() == tx)
+    pub fn has_transaction(&self, tx: &Transaction) -> bool {
+        self.tx_index.contains_key(&tx.id())
+    }
+
+    /// Returns the height of the block of the main chain containing the transaction with the
+    /// given id.
+    pub fn transaction_height(&self, id: &str) -> Option<u64> {
+        self.tx_index.get(id).copied()
+    }
+
+    /// Returns the transactions of the main chain involving the account, with the height of their
+    /// block, from the oldest to the newest.
+    pub fn history(&self, address: &Address) -> Vec<(u64, &Transaction)> {
+        self.chain
+            .iter()
+            .flat_map(|block| block.transactions().iter().map(move |tx| (block.index_in_chain(), tx)))
+            .filter(|(_, tx)| tx.involves(address))
+            .collect()
+    }
+
+    /// Returns a proof that the document of the given digest was notarized in the main chain, in
+    /// the first block anchoring it.
+    pub fn prove_document(&self, digest: &str) -> Option<ExistenceProof> {
+        let height = *self.documents.get(digest)?.iter().min()?;
+        let block = &self.chain[height as usize];
+        let index = block.transactions().iter().position(|tx| notarized_digest(tx) == Some(digest))?;
+        Some(ExistenceProof {
+            transaction: block.transactions()[index].clone(),
+            block_hash: block.hash(),
+            height,
+            confirmations: self.chain.len() as u64 - height,
+            path: merkle_path(&block.transaction_bytes(), index)?,
+        })
+    }
+
+    /// Reassembles the blob of the given digest from the chunks listed by its manifest, and
+    /// checks its integrity.
+    pub fn get_blob(&self, digest: &str) -> Result<Vec<u8>, BlobError> {
+        let blobs = self.ledger.blobs();
+        let manifest = blobs.manifest(digest).ok_or(BlobError::UnknownBlob)?;
+        let mut blob = Vec::with_capacity(manifest.size as usize);
+        for chunk in &manifest.chunks {
+            let location = blobs.chunk(chunk).ok_or(BlobError::Corrupted)?;
+            let data = self.chain
+                .get(location.height as usize)
+                .into_iter()
+                .flat_map(|block| block.transactions())
+                .find_map(|tx| match tx {
+                    Transaction::Signed(signed) => match signed.payload() {
+                        Payload::Chunk(data) if sha256::digest(data.as_slice()) == *chunk => Some(data),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .ok_or(BlobError::Corrupted)?;
+            blob.extend_from_slice(data);
+        }
+        if blob.len() as u64 != manifest.size || sha256::digest(blob.as_slice()) != manifest.digest {
+            return Err(BlobError::Corrupted);
+        }
+        Ok(blob)
+    }
+
+    /// Returns the entry of a name registered on the main chain, if it did not expire.
+    pub fn resolve_name(&self, name: &str) -> Option<&NameEntry> {
+        self.ledger.names().resolve(name, self.ledger.height())
+    }
+
+    /// Returns the headers of the main chain, starting with the genesis.
+    pub fn headers(&self) -> Vec<BlockHeader> {
+        self.chain.iter().map(Block::header).collect()
     }
 
     /// Returns true if the transaction is written before the 'safe' horizon,
     /// which mean that we consider that all workers have agreed upon this position.
-    pub fn is_transaction_safely_written(&self, tx: &SimpleTransaction) -> bool {
-        if self.chain.len() < SAFE_HORIZON as usize {
-            return false;
-        }
-        self.chain[..self.chain.len() - SAFE_HORIZON as usize].iter().any(|block| block.transactions() == tx)
+    pub fn is_transaction_safely_written(&self, tx: &Transaction) -> bool {
+        self.transaction_height(&tx.id())
+            .is_some_and(|height| height + (SAFE_HORIZON as u64) < self.chain.len() as u64)
     }
 
     pub fn print_chain(&self) {
@@ -196,27 +629,52 @@ impl Blockchain {
 
 }
 
+#[cfg(test)]
+impl Blockchain {
+    /// Creates a new blockchain whose accounts start with the given balances.
+    pub(crate) fn with_allocations(allocations: impl IntoIterator<Item = (Address, u64)>) -> Self {
+        Self::with_ledger(Ledger::with_allocations(allocations))
+    }
+
+    /// Adds a block holding the given transactions on top of the main chain, rewarding a new
+    /// miner, and returns it. Panics if a transaction is left out, or if the block is refused.
+    pub(crate) fn add_test_block(&mut self, transactions: Vec<Transaction>) -> Block {
+        let count = transactions.len();
+        let block = self.get_rewarded_candidate_block(transactions, &crate::client::Client::new().address());
+        assert_eq!(count + 1, block.transactions().len(), "a transaction was left out of the block");
+        assert!(self.add_block_safe(block.clone()), "the block was refused");
+        block
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
+    use std::io;
     use crate::block::Block;
-    use crate::blockchain::Blockchain;
+    use crate::blockchain::{Blockchain, Placement};
+    use crate::client::Client;
+    use crate::coinbase::{Coinbase, RewardSchedule};
+    use crate::signed_transaction::{Payload, SignedTransaction};
     use crate::simple_transaction::SimpleTransaction;
+    use crate::state::Ledger;
+    use crate::transaction::{Transaction, TransactionError};
+    use crate::validity::{unix_time, Lock, Validity, MAX_FUTURE_DRIFT};
 
     #[test]
     fn test_blockchain_divergence_when_divergent_chain_is_longer_at_resolution() {
+        let miner = Client::new().address();
         let mut chain = Blockchain::new();
 
         // Create a first block and add it to the chain
-        let b1 = chain.get_candidate_block(SimpleTransaction::new());
-        chain.add_block_safe(b1);
+        chain.add_test_block(vec![SimpleTransaction::from_str("0").into()]);
 
         // Create two blocks on top of B1
-        let b2 = chain.get_candidate_block(SimpleTransaction::from_str("left"));
-        let b3 = chain.get_candidate_block(SimpleTransaction::from_str("right"));
+        let b2 = chain.get_rewarded_candidate_block(vec![SimpleTransaction::from_str("left").into()], &miner);
+        let b3 = chain.get_rewarded_candidate_block(vec![SimpleTransaction::from_str("right").into()], &miner);
 
         // Add one of them first
-        chain.add_block_safe(b2);
+        assert_eq!(true, chain.add_block_safe(b2));
 
         // You can't add the next one
         assert_eq!(false, chain.add_block_safe(b3.clone()));
@@ -225,7 +683,7 @@ mod tests {
         assert_eq!(1, chain.pending_forks.len());
 
         // Create a new block on top of b3
-        let b4 = Block::new_after_block(SimpleTransaction::from_str("I was easy to mine..."), &b3);
+        let b4 = Block::new_rewarded_after_block(SimpleTransaction::from_str("I was easy to mine..."), &b3, &miner);
 
         // This one too should not be merged.
         assert_eq!(false, chain.add_block_safe(b4.clone()));
@@ -244,18 +702,18 @@ mod tests {
 
     #[test]
     fn test_blockchain_divergence_when_main_chain_is_longer_at_resolution() {
+        let miner = Client::new().address();
         let mut chain = Blockchain::new();
 
         // Create a first block and add it to the chain
-        let b1 = chain.get_candidate_block(SimpleTransaction::new());
-        chain.add_block_safe(b1);
+        chain.add_test_block(vec![SimpleTransaction::from_str("0").into()]);
 
         // Create two blocks on top of B1
-        let b2 = chain.get_candidate_block(SimpleTransaction::from_str("left"));
-        let b3 = chain.get_candidate_block(SimpleTransaction::from_str("right"));
+        let b2 = chain.get_rewarded_candidate_block(vec![SimpleTransaction::from_str("left").into()], &miner);
+        let b3 = chain.get_rewarded_candidate_block(vec![SimpleTransaction::from_str("right").into()], &miner);
 
         // Add one of them first
-        chain.add_block_safe(b2.clone());
+        assert_eq!(true, chain.add_block_safe(b2.clone()));
 
         // You can't add the next one
         assert_eq!(false, chain.add_block_safe(b3));
@@ -264,7 +722,7 @@ mod tests {
         assert_eq!(1, chain.pending_forks.len());
 
         // Create a new block on top of b2
-        let b4 = Block::new_after_block(SimpleTransaction::from_str("I was easy to mine..."), &b2);
+        let b4 = Block::new_rewarded_after_block(SimpleTransaction::from_str("I was easy to mine..."), &b2, &miner);
 
         // This one can be merged
         assert_eq!(true, chain.add_block_safe(b4));
@@ -285,17 +743,17 @@ mod tests {
     fn test_divergence_with_unordered_buffer() {
 
         // Create a chain
+        let miner = Client::new().address();
         let mut chain = Blockchain::new();
-        let b1 = chain.get_candidate_block(SimpleTransaction::new());
-        chain.add_block_safe(b1.clone());
+        let b1 = chain.add_test_block(vec![SimpleTransaction::from_str("0").into()]);
 
         // Create three block on top of each others
-        let b2 = Block::new_after_block(SimpleTransaction::from_str("1"), &b1);
-        let b3 = Block::new_after_block(SimpleTransaction::from_str("1"), &b2);
+        let b2 = Block::new_rewarded_after_block(SimpleTransaction::from_str("1"), &b1, &miner);
+        let b3 = Block::new_rewarded_after_block(SimpleTransaction::from_str("2"), &b2, &miner);
 
         // If you send `b3` before `b2`, the main chain must not be updated of course
         assert_eq!(2, chain.len());
-        chain.add_block_safe(b3);
+        assert_eq!(false, chain.add_block_safe(b3));
         assert_eq!(2, chain.len());
 
         // We can now check that we have 1 orphan block
@@ -303,8 +761,277 @@ mod tests {
 
         // But after you send `b2`, the chain must not be of size '3' but indeed of size '4'
         // It should detect that it can create a new chain longer
-        chain.add_block_safe(b2);
+        assert_eq!(true, chain.add_block_safe(b2));
         assert_eq!(4, chain.len());
         assert_eq!(0, chain.orphan.len());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_orphan_is_placed_when_its_parent_starts_a_fork() {
+        let miner = Client::new().address();
+        let mut chain = Blockchain::new();
+        let b1 = chain.add_test_block(vec![SimpleTransaction::from_str("1").into()]);
+        chain.add_test_block(vec![SimpleTransaction::from_str("2").into()]);
+
+        // A competing branch on top of b1, received in the wrong order
+        let c2 = Block::new_rewarded_after_block(SimpleTransaction::from_str("2 bis"), &b1, &miner);
+        let c3 = Block::new_rewarded_after_block(SimpleTransaction::from_str("3 bis"), &c2, &miner);
+        assert_eq!(false, chain.add_block_safe(c3.clone()));
+        assert_eq!(1, chain.orphan.len());
+
+        // Receiving c2 starts a new fork, on which the orphan c3 can now be placed
+        assert_eq!(false, chain.add_block_safe(c2));
+        assert_eq!(0, chain.orphan.len());
+
+        let reorg = chain.resolve_pending_forks().unwrap();
+        assert_eq!(1, reorg.depth());
+        assert_eq!(2, reorg.connected.len());
+        assert_eq!(c3.hash(), chain.last_block().hash());
+    }
+
+    #[test]
+    fn test_block_received_twice_is_ignored() {
+        let mut chain = Blockchain::new();
+        let b1 = chain.add_test_block(vec![SimpleTransaction::from_str("1").into()]);
+        assert_eq!(false, chain.add_block_safe(b1.clone()));
+        assert_eq!(Err(TransactionError::KnownBlock), chain.add_block(b1));
+        assert_eq!(2, chain.len());
+        assert_eq!(0, chain.pending_forks.len());
+    }
+
+    #[test]
+    fn test_placement_of_added_blocks() {
+        let miner = Client::new().address();
+        let mut alice = Client::new();
+        let bob = Client::new();
+        let mut chain = Blockchain::with_allocations([(alice.address(), 10)]);
+        let genesis = chain.last_block().clone();
+
+        let b1 = chain.get_rewarded_candidate_block(vec![alice.sign("1").into()], &miner);
+        assert_eq!(Ok(Placement::MainChain), chain.add_block(b1.clone()));
+        let c1 = Block::new_rewarded_after_block(alice.sign("1 bis"), &genesis, &miner);
+        let c2 = Block::new_rewarded_after_block(alice.sign("2 bis"), &c1, &miner);
+        assert_eq!(Ok(Placement::Orphan), chain.add_block(c2));
+        assert_eq!(Ok(Placement::Fork), chain.add_block(c1));
+
+        // A block extending the main chain is refused with the reason
+        let overdraw = Block::new_rewarded_after_block(alice.transfer(bob.address(), 11), chain.last_block(), &miner);
+        assert_eq!(Err(TransactionError::NotEnoughSold), chain.add_block(overdraw));
+    }
+
+    #[test]
+    fn test_unsafe_block_refused_by_the_ledger_is_not_added() {
+        let miner = Client::new().address();
+        let mut alice = Client::new();
+        let bob = Client::new();
+        let mut chain = Blockchain::with_allocations([(alice.address(), 10)]);
+
+        let overdraw = Block::new_rewarded_after_block(alice.transfer(bob.address(), 11), chain.last_block(), &miner);
+        assert_eq!(Err(TransactionError::NotEnoughSold), chain.add_block_unsafe(overdraw));
+        assert_eq!(1, chain.len());
+        assert_eq!(10, chain.ledger().balance(&alice.address()));
+        assert_eq!(0, chain.ledger().balance(&bob.address()));
+    }
+
+    #[test]
+    fn test_block_with_invalid_signature_is_refused() {
+        let miner = Client::new().address();
+        let mut chain = Blockchain::new();
+        let mut client = Client::new();
+
+        chain.add_test_block(vec![client.sign("signed").into()]);
+
+        // Reuse the signature of another message
+        let signed = client.sign("original");
+        let forged = SignedTransaction::new(client.public_key(), Payload::Message("forged".to_string()), 0, signed.nonce(), signed.signature().to_vec());
+        let invalid = Block::new_rewarded_after_block(forged, chain.last_block(), &miner);
+        assert_eq!(false, chain.add_block_safe(invalid));
+        assert_eq!(2, chain.len());
+        assert_eq!(0, chain.orphan.len());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut chain = Blockchain::new();
+        chain.add_test_block(vec![SimpleTransaction::from_str("1").into()]);
+        chain.add_test_block(vec![SimpleTransaction::from_str("2").into()]);
+
+        let path = std::env::temp_dir().join("repyh_test_save_and_load.json");
+        chain.save(&path).unwrap();
+        let loaded = Blockchain::load(&path, Ledger::new(), RewardSchedule::default()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(3, loaded.len());
+        assert_eq!(chain.last_block().hash(), loaded.last_block().hash());
+        let b1 = chain.blocks()[1].hash();
+        assert_eq!(Some(1), loaded.block_by_hash(&b1).map(Block::index_in_chain));
+        assert!(loaded.block_by_hash("unknown").is_none());
+    }
+
+    #[test]
+    fn test_load_keeps_the_allocations() {
+        let miner = Client::new().address();
+        let mut alice = Client::new();
+        let bob = Client::new();
+        let ledger = Ledger::with_allocations([(alice.address(), 10)]);
+        let mut chain = Blockchain::with_ledger(ledger.clone());
+        let tx = alice.sign_payload(Payload::Transfer { receiver: bob.address(), amount: 6 }, 0);
+        let b1 = chain.get_rewarded_candidate_block(vec![tx.into()], &miner);
+        assert_eq!(Ok(Placement::MainChain), chain.add_block(b1));
+
+        let path = std::env::temp_dir().join("repyh_test_load_keeps_the_allocations.json");
+        chain.save(&path).unwrap();
+        let loaded = Blockchain::load(&path, ledger, RewardSchedule::default());
+        // Without the allocations, the transfer overdraws the account of Alice
+        let unfunded = Blockchain::load(&path, Ledger::new(), RewardSchedule::default());
+        std::fs::remove_file(&path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(4, loaded.ledger().balance(&alice.address()));
+        assert_eq!(6, loaded.ledger().balance(&bob.address()));
+        assert_eq!(io::ErrorKind::InvalidData, unfunded.err().unwrap().kind());
+    }
+
+    #[test]
+    fn test_load_refuses_blocks_outside_the_main_chain() {
+        let mut chain = Blockchain::new();
+        chain.add_test_block(vec![SimpleTransaction::from_str("1").into()]);
+        chain.add_test_block(vec![SimpleTransaction::from_str("2").into()]);
+
+        // Without its parent, the last block is an orphan
+        let mut blocks = chain.blocks().to_vec();
+        blocks.remove(1);
+        let path = std::env::temp_dir().join("repyh_test_load_refuses_blocks_outside_the_main_chain.json");
+        std::fs::write(&path, serde_json::to_vec(&blocks).unwrap()).unwrap();
+        let loaded = Blockchain::load(&path, Ledger::new(), RewardSchedule::default());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(io::ErrorKind::InvalidData, loaded.err().unwrap().kind());
+    }
+
+    #[test]
+    fn test_candidate_block_skips_invalid_transactions() {
+        let mut alice = Client::new();
+        let bob = Client::new();
+        let miner = Client::new();
+        let chain = Blockchain::with_allocations([(alice.address(), 10)]);
+
+        // Both transfers are valid alone, but alice can't afford the second one after the first
+        let first: Transaction = alice.sign_payload(Payload::Transfer { receiver: bob.address(), amount: 6 }, 2).into();
+        let second: Transaction = alice.transfer(bob.address(), 5).into();
+        let block = chain.get_rewarded_candidate_block(vec![first.clone(), second, first.clone()], &miner.address());
+
+        assert_eq!(2, block.transactions().len());
+        assert_eq!(&first, &block.transactions()[1]);
+        let Transaction::Coinbase(coinbase) = &block.transactions()[0] else { panic!("no coinbase") };
+        assert_eq!(chain.rewards().subsidy(1) + 2, coinbase.output().amount);
+    }
+
+    #[test]
+    fn test_same_message_can_be_written_twice() {
+        let mut chain = Blockchain::new();
+        let first: Transaction = SimpleTransaction::with_nonce("hello", 1).into();
+        let second: Transaction = SimpleTransaction::with_nonce("hello", 2).into();
+        assert_ne!(first.id(), second.id());
+
+        chain.add_test_block(vec![first.clone()]);
+        assert_eq!(true, chain.has_transaction(&first));
+        assert_eq!(false, chain.has_transaction(&second));
+        chain.add_test_block(vec![second.clone()]);
+        assert_eq!(Some(2), chain.transaction_height(&second.id()));
+    }
+
+    #[test]
+    fn test_transaction_written_twice_is_refused() {
+        let miner = Client::new().address();
+        let mut chain = Blockchain::new();
+        let b1 = chain.add_test_block(vec![SimpleTransaction::from_str("1").into()]);
+
+        // Already in the main chain
+        let replay = Block::new_rewarded_after_block(SimpleTransaction::from_str("1"), &b1, &miner);
+        assert_eq!(Err(TransactionError::DuplicateTransaction), chain.add_block(replay));
+
+        // Twice in the same block
+        let tx: Transaction = SimpleTransaction::from_str("2").into();
+        let twice = Block::with_transactions(vec![Coinbase::new(2, 0, miner).into(), tx.clone(), tx], &b1);
+        assert_eq!(Err(TransactionError::DuplicateTransaction), chain.add_block(twice));
+        assert_eq!(2, chain.len());
+    }
+
+    #[test]
+    fn test_fork_writing_a_transaction_twice_is_refused() {
+        let miner = Client::new().address();
+        let mut chain = Blockchain::new();
+        let b1 = chain.add_test_block(vec![SimpleTransaction::from_str("1").into()]);
+        let b2 = chain.add_test_block(vec![SimpleTransaction::from_str("2").into()]);
+
+        // The fork writes again a transaction of the common part of the chains
+        let c2 = Block::new_rewarded_after_block(SimpleTransaction::from_str("3"), &b1, &miner);
+        let c3 = Block::new_rewarded_after_block(SimpleTransaction::from_str("1"), &c2, &miner);
+        assert_eq!(Ok(Placement::Fork), chain.add_block(c2));
+        assert_eq!(Ok(Placement::Fork), chain.add_block(c3));
+        assert_eq!(true, chain.resolve_pending_forks().is_none());
+        assert_eq!(b2.hash(), chain.last_block().hash());
+
+        // The fork writes the same transaction in two of its blocks
+        let d2 = Block::new_rewarded_after_block(SimpleTransaction::from_str("4"), &b1, &miner);
+        let d3 = Block::new_rewarded_after_block(SimpleTransaction::from_str("4"), &d2, &miner);
+        assert_eq!(Ok(Placement::Fork), chain.add_block(d2));
+        assert_eq!(Ok(Placement::Fork), chain.add_block(d3));
+        assert_eq!(true, chain.resolve_pending_forks().is_none());
+        assert_eq!(b2.hash(), chain.last_block().hash());
+    }
+
+    #[test]
+    fn test_history_of_an_account() {
+        let mut alice = Client::new();
+        let bob = Client::new();
+        let carol = Client::new();
+        let mut chain = Blockchain::with_allocations([(alice.address(), 10)]);
+
+        let to_bob: Transaction = alice.transfer(bob.address(), 4).into();
+        chain.add_test_block(vec![to_bob.clone()]);
+        chain.add_test_block(vec![alice.transfer(carol.address(), 4).into()]);
+
+        assert_eq!(vec![(1, &to_bob)], chain.history(&bob.address()));
+        assert_eq!(2, chain.history(&alice.address()).len());
+    }
+
+    #[test]
+    fn test_time_locks_are_enforced() {
+        let miner = Client::new().address();
+        let mut chain = Blockchain::new();
+        let locked: Transaction = SimpleTransaction::with_nonce("later", 1)
+            .with_validity(Validity::new(Some(Lock::Height(2)), None))
+            .into();
+        let expiring: Transaction = SimpleTransaction::with_nonce("sooner", 2)
+            .with_validity(Validity::new(None, Some(Lock::Height(2))))
+            .into();
+
+        // The next block has height 1
+        assert_eq!(Err(TransactionError::NotYetValid), chain.check_transaction(&locked));
+        assert_eq!(false, chain.add_block_safe(Block::new_rewarded_after_block(locked.clone(), chain.last_block(), &miner)));
+        chain.add_test_block(vec![expiring.clone()]);
+
+        assert_eq!(Ok(()), chain.check_transaction(&locked));
+        assert_eq!(true, chain.is_transaction_expired(&expiring));
+        let block = chain.get_rewarded_candidate_block(vec![expiring, locked.clone()], &Client::new().address());
+        assert_eq!(&locked, &block.transactions()[1]);
+        assert_eq!(2, block.transactions().len());
+    }
+
+    #[test]
+    fn test_block_older_than_its_parent_is_refused() {
+        let miner = Client::new().address();
+        let mut chain = Blockchain::new();
+        let mut b1 = chain.get_rewarded_candidate_block(vec![SimpleTransaction::from_str("1").into()], &miner);
+        b1.set_timestamp(1000);
+        assert_eq!(true, chain.add_block_safe(b1));
+
+        let mut b2 = chain.get_rewarded_candidate_block(vec![SimpleTransaction::from_str("2").into()], &miner);
+        b2.set_timestamp(999);
+        assert_eq!(false, chain.add_block_safe(b2.clone()));
+        b2.set_timestamp(unix_time() + MAX_FUTURE_DRIFT + 60);
+        assert_eq!(false, chain.add_block_safe(b2));
+    }
+}
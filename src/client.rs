@@ -0,0 +1,100 @@
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use rand::rngs::OsRng;
+use crate::address::Address;
+use crate::signed_transaction::{Payload, SignedTransaction};
+use crate::utxo::{OutPoint, TxInput, TxOutput, UtxoTransaction, Witness};
+use crate::validity::Validity;
+
+/// The client to the blockchain network
+/// Holds the keys of the user, and signs the transactions it sends.
+pub struct Client {
+    signing_key: SigningKey,
+    /// Nonce of the last transaction signed by this client
+    nonce: u64,
+}
+
+impl Client {
+    /// Creates a new client, with a new ed25519 key
+    pub fn new() -> Self {
+        Self::from_signing_key(SigningKey::generate(&mut OsRng))
+    }
+
+    /// Creates a client from an existing key
+    pub fn from_signing_key(signing_key: SigningKey) -> Self {
+        Self {
+            signing_key,
+            nonce: 0,
+        }
+    }
+
+    /// Sets the nonce of the last transaction of this client, for instance to the one recorded by
+    /// the ledger of the chain. The next transaction uses the following nonce.
+    pub fn set_nonce(&mut self, nonce: u64) {
+        self.nonce = nonce;
+    }
+
+    /// Creates a transaction carrying a message signed by this client.
+    pub fn sign(&mut self, message: &str) -> SignedTransaction {
+        self.sign_payload(Payload::Message(message.to_string()), 0)
+    }
+
+    /// Creates a transaction to another client of the network
+    ///
+    /// Note that the balance is not checked here: the transaction is refused by the ledger when it
+    /// is mined if the client does not have enough money.
+    pub fn transfer(&mut self, receiver: Address, amount: u64) -> SignedTransaction {
+        self.sign_payload(Payload::Transfer { receiver, amount }, 0)
+    }
+
+    /// Signs the given payload, offering `fee` to the miner who writes it.
+    pub fn sign_payload(&mut self, payload: Payload, fee: u64) -> SignedTransaction {
+        self.sign_with_validity(payload, fee, Validity::default())
+    }
+
+    /// Signs the given payload, which can only be written in the blocks allowed by `validity`.
+    pub fn sign_with_validity(&mut self, payload: Payload, fee: u64, validity: Validity) -> SignedTransaction {
+        self.nonce += 1;
+        let sender = self.public_key();
+        let data = SignedTransaction::signed_bytes(&sender, &payload, fee, self.nonce, &validity);
+        let signature = self.signing_key.sign(&data);
+        SignedTransaction::new(sender, payload, fee, self.nonce, signature.to_vec()).with_validity(validity)
+    }
+
+    /// Creates a UTXO transaction spending outputs owned by this client.
+    pub fn spend(&self, inputs: Vec<OutPoint>, outputs: Vec<TxOutput>) -> UtxoTransaction {
+        self.spend_with_validity(inputs, outputs, Validity::default())
+    }
+
+    /// Creates a UTXO transaction which can only be written in the blocks allowed by `validity`.
+    pub fn spend_with_validity(&self, inputs: Vec<OutPoint>, outputs: Vec<TxOutput>, validity: Validity) -> UtxoTransaction {
+        let data = UtxoTransaction::signed_bytes(&inputs, &outputs, &validity);
+        let signature = self.signing_key.sign(&data).to_vec();
+        let inputs = inputs
+            .into_iter()
+            .map(|previous_output| {
+                let witness = Witness::Key { public_key: self.public_key(), signature: signature.clone() };
+                TxInput { previous_output, witness }
+            })
+            .collect();
+        UtxoTransaction::new(inputs, outputs).with_validity(validity)
+    }
+
+    pub fn signing_key(&self) -> &SigningKey {
+        &self.signing_key
+    }
+
+    pub fn public_key(&self) -> VerifyingKey {
+        self.signing_key.verifying_key()
+    }
+
+    /// Returns the address of the account of this client.
+    pub fn address(&self) -> Address {
+        Address::from_public_key(&self.public_key())
+    }
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,169 @@
+use std::fmt::{Debug, Formatter};
+use serde::{Deserialize, Serialize};
+use sha256::digest;
+use crate::address::Address;
+use crate::utxo::{OutPoint, TxOutput};
+
+/// Parameters of the reward paid to the miner of each block.
+#[derive(Clone, Debug)]
+pub struct RewardSchedule {
+    /// Amount created by the first blocks of the chain
+    pub initial_subsidy: u64,
+    /// Number of blocks after which the subsidy is divided by two
+    pub halving_interval: u64,
+    /// Number of blocks that must be mined on top of a coinbase before its output can be spent
+    pub maturity: u64,
+}
+
+impl Default for RewardSchedule {
+    fn default() -> Self {
+        Self {
+            initial_subsidy: 50,
+            halving_interval: 1000,
+            maturity: 10,
+        }
+    }
+}
+
+impl RewardSchedule {
+    /// Returns the amount that the miner of the block at the given height can create.
+    pub fn subsidy(&self, height: u64) -> u64 {
+        let halvings = height / self.halving_interval.max(1);
+        if halvings >= u64::BITS as u64 {
+            return 0;
+        }
+        self.initial_subsidy >> halvings
+    }
+}
+
+/// The first transaction of a block, which pays the subsidy and the fees of the block to its miner.
+#[derive(Clone, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct Coinbase {
+    /// Height of the block. It makes the id of each coinbase unique.
+    height: u64,
+    output: TxOutput,
+}
+
+impl Coinbase {
+    pub fn new(height: u64, amount: u64, miner: Address) -> Self {
+        Self { height, output: TxOutput { amount, owner: miner } }
+    }
+
+    /// Returns the bytes representation of this transaction.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.height.to_be_bytes());
+        bytes.extend_from_slice(&self.output.amount.to_be_bytes());
+        bytes.extend_from_slice(self.output.owner.as_bytes());
+        bytes
+    }
+
+    /// Returns the id of the transaction, used to reference its output.
+    pub fn id(&self) -> String {
+        digest(self.to_bytes())
+    }
+
+    /// Returns the reference to the output paid to the miner.
+    pub fn outpoint(&self) -> OutPoint {
+        OutPoint { tx_id: self.id(), index: 0 }
+    }
+
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    pub fn output(&self) -> &TxOutput {
+        &self.output
+    }
+}
+
+impl Debug for Coinbase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "coinbase at {} ({})", self.height, self.output.amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::Block;
+    use crate::blockchain::Blockchain;
+    use crate::client::Client;
+    use crate::coinbase::{Coinbase, RewardSchedule};
+    use crate::state::Ledger;
+    use crate::transaction::{Transaction, TransactionError};
+    use crate::utxo::{OutPoint, TxOutput, UtxoSet, GENESIS_TX_ID};
+
+    #[test]
+    fn test_subsidy_halves() {
+        let schedule = RewardSchedule { initial_subsidy: 50, halving_interval: 10, maturity: 1 };
+        assert_eq!(50, schedule.subsidy(1));
+        assert_eq!(50, schedule.subsidy(9));
+        assert_eq!(25, schedule.subsidy(10));
+        assert_eq!(12, schedule.subsidy(25));
+        assert_eq!(0, schedule.subsidy(10 * 64));
+    }
+
+    #[test]
+    fn test_miner_collects_subsidy_and_fees() {
+        let alice = Client::new();
+        let bob = Client::new();
+        let miner = Client::new();
+        let utxos = UtxoSet::with_allocations([TxOutput { amount: 50, owner: alice.address() }]);
+        let mut chain = Blockchain::with_genesis(Ledger::new(), utxos);
+
+        // Alice leaves 10 to the miner
+        let genesis_output = OutPoint { tx_id: GENESIS_TX_ID.to_string(), index: 0 };
+        let tx = alice.spend(vec![genesis_output], vec![TxOutput { amount: 40, owner: bob.address() }]);
+        let block = chain.get_rewarded_candidate_block(vec![tx.into()], &miner.address());
+        assert_eq!(true, chain.add_block_safe(block));
+        assert_eq!(60, chain.utxos().balance(&miner.address()));
+    }
+
+    #[test]
+    fn test_coinbase_claiming_too_much_is_refused() {
+        let miner = Client::new();
+        let mut chain = Blockchain::new();
+        let coinbase = Coinbase::new(1, 51, miner.address());
+        let block = Block::with_transactions(vec![coinbase.into()], chain.last_block());
+        assert_eq!(false, chain.add_block_safe(block));
+        assert_eq!(0, chain.utxos().balance(&miner.address()));
+    }
+
+    #[test]
+    fn test_block_without_coinbase_is_refused() {
+        let mut alice = Client::new();
+        let mut chain = Blockchain::new();
+        let block = Block::new_after_block(alice.sign("hello"), chain.last_block());
+        assert_eq!(Err(TransactionError::InvalidCoinbase), chain.add_block(block));
+        assert_eq!(1, chain.len());
+    }
+
+    #[test]
+    fn test_coinbase_must_be_the_first_transaction() {
+        let mut alice = Client::new();
+        let mut chain = Blockchain::new();
+        let coinbase = Coinbase::new(1, 50, alice.address());
+        let block = Block::with_transactions(vec![alice.sign("hello").into(), coinbase.clone().into()], chain.last_block());
+        assert_eq!(false, chain.add_block_safe(block));
+
+        // Nor can it be submitted on its own
+        assert_eq!(Err(TransactionError::InvalidCoinbase), chain.check_transaction(&coinbase.into()));
+    }
+
+    #[test]
+    fn test_coinbase_needs_confirmations_to_be_spent() {
+        let mut miner = Client::new();
+        let rewards = RewardSchedule { maturity: 2, ..Default::default() };
+        let mut chain = Blockchain::new().with_rewards(rewards);
+
+        let block = chain.get_rewarded_candidate_block(vec![miner.sign("first").into()], &miner.address());
+        let Transaction::Coinbase(coinbase) = &block.transactions()[0] else { panic!("no coinbase") };
+        let spend: Transaction = miner.spend(vec![coinbase.outpoint()], vec![TxOutput { amount: 50, owner: miner.address() }]).into();
+        assert_eq!(true, chain.add_block_safe(block));
+
+        assert_eq!(Err(TransactionError::ImmatureCoinbase), chain.check_transaction(&spend));
+        chain.add_test_block(vec![miner.sign("second").into()]);
+        assert_eq!(Ok(()), chain.check_transaction(&spend));
+    }
+}
@@ -0,0 +1,485 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use sha256::digest;
+use wasmi::{Caller, Config, Engine, Extern, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+use wasmi::core::TrapCode;
+use crate::address::Address;
+use crate::transaction::push_field;
+
+/// Maximum length, in bytes, of the WebAssembly module of a contract.
+pub const MAX_CODE_LEN: usize = 64 * 1024;
+
+/// Maximum gas that a transaction can give to a contract.
+pub const MAX_GAS: u64 = 10_000_000;
+
+/// Maximum size, in bytes, of the memory of a contract during an execution.
+pub const MAX_MEMORY: usize = 1024 * 1024;
+
+/// Maximum length of a key, and of a value, of the storage of a contract.
+pub const MAX_KEY_LEN: usize = 64;
+pub const MAX_VALUE_LEN: usize = 1024;
+
+/// Price, in coins, of each unit of gas used by a contract, paid by the sender of the transaction.
+pub const GAS_PRICE: u64 = 1;
+
+/// Gas charged for each call of a host function, on top of the bytes it copies.
+const HOST_CALL_GAS: u64 = 100;
+
+/// Gas charged for each byte copied between the contract and the host.
+const BYTE_GAS: u64 = 1;
+
+/// Gas charged for each byte written to the storage, which every node keeps forever.
+const STORAGE_BYTE_GAS: u64 = 50;
+
+/// Domain of the hash giving the address of a contract.
+const CONTRACT_HASH_TAG: &[u8] = b"contract";
+
+/// Module of the host functions imported by contracts.
+const HOST_MODULE: &str = "env";
+
+/// Reasons for a contract to be refused, or for its execution to fail.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ContractError {
+    /// The code is not a valid module, imports unknown functions, or does not export `call`
+    InvalidCode,
+    /// No contract is deployed at the called address
+    UnknownContract,
+    /// The transaction gives more gas than `MAX_GAS`
+    GasLimitTooHigh,
+    /// The execution used all the gas given by the transaction
+    OutOfGas,
+    /// The execution stopped on an error
+    Trapped(String),
+}
+
+impl std::error::Error for ContractError {}
+
+impl Display for ContractError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContractError::InvalidCode => write!(f, "invalid contract code"),
+            ContractError::UnknownContract => write!(f, "unknown contract"),
+            ContractError::GasLimitTooHigh => write!(f, "gas limit too high"),
+            ContractError::OutOfGas => write!(f, "out of gas"),
+            ContractError::Trapped(reason) => write!(f, "execution failed: {reason}"),
+        }
+    }
+}
+
+/// A deployed contract: its WebAssembly module, and its key-value store.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Contract {
+    code: Vec<u8>,
+    storage: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// Hash of the code and of the storage, updated when the contract is committed
+    digest: String,
+}
+
+impl Contract {
+    fn new(code: &[u8]) -> Self {
+        Self { code: code.to_vec(), storage: BTreeMap::new(), digest: String::new() }
+    }
+
+    /// Returns the hash committing to the code and the storage of the contract.
+    fn hash(&self) -> String {
+        let mut bytes = Vec::new();
+        push_field(&mut bytes, &self.code);
+        bytes.extend_from_slice(&(self.storage.len() as u32).to_be_bytes());
+        for (key, value) in &self.storage {
+            push_field(&mut bytes, key);
+            push_field(&mut bytes, value);
+        }
+        digest(bytes)
+    }
+
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.storage.get(key).map(Vec::as_slice)
+    }
+}
+
+/// The result of a deploy or call that ran, to be committed with `ContractState::commit`.
+#[derive(Debug)]
+pub struct Execution {
+    /// Address of the deployed or called contract
+    pub contract: Address,
+    /// Gas used by the execution, which is paid even if it failed
+    pub gas_used: u64,
+    /// Contract after the execution, or the reason why the execution failed
+    state: Result<Contract, ContractError>,
+}
+
+impl Execution {
+    /// Returns the reason why the execution failed, if it did.
+    pub fn error(&self) -> Option<&ContractError> {
+        self.state.as_ref().err()
+    }
+}
+
+/// What a contract can reach during an execution.
+struct HostState {
+    caller: Address,
+    input: Vec<u8>,
+    storage: BTreeMap<Vec<u8>, Vec<u8>>,
+    limits: StoreLimits,
+    out_of_gas: bool,
+}
+
+/// The contracts deployed on the main chain, and their storage.
+///
+/// Contracts are WebAssembly modules run by an interpreter, with floats disabled and with every
+/// instruction and host call metered in gas, so that every node reaches the same state when it
+/// replays the chain. A contract exports a `call` function, and optionally an `init` function run
+/// at its deployment. Both take no argument, and reach the transaction and the storage through
+/// the functions imported from the `env` module:
+///
+/// - `input_len() -> i32` and `read_input(ptr)` give the input of the call
+/// - `caller(ptr)` writes the 20 bytes of the address of the sender
+/// - `storage_read(key_ptr, key_len, value_ptr, value_cap) -> i32` copies at most `value_cap`
+///   bytes of the value of a key, and returns its full length, or -1 if the key is missing
+/// - `storage_write(key_ptr, key_len, value_ptr, value_len)` sets the value of a key
+///
+/// An execution that fails or runs out of gas changes nothing, but its gas is paid.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ContractState {
+    contracts: BTreeMap<Address, Contract>,
+}
+
+impl ContractState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the address of the contract deployed by the transaction of the given sender and
+    /// nonce.
+    pub fn contract_address(sender: &Address, nonce: u64) -> Address {
+        Address::from_hash_of(&[CONTRACT_HASH_TAG, sender.as_bytes(), &nonce.to_be_bytes()].concat())
+    }
+
+    pub fn get(&self, contract: &Address) -> Option<&Contract> {
+        self.contracts.get(contract)
+    }
+
+    /// Returns the hash committing to the code and the storage of every contract.
+    ///
+    /// Only the contracts changed by a commit are hashed again: the root hashes the digest kept
+    /// for each contract.
+    pub fn root(&self) -> String {
+        let mut bytes = Vec::new();
+        for (address, contract) in &self.contracts {
+            bytes.extend_from_slice(address.as_bytes());
+            bytes.extend_from_slice(contract.digest.as_bytes());
+        }
+        digest(bytes)
+    }
+
+    /// Deploys a contract at the address given by the sender and the nonce of the transaction,
+    /// and runs its `init` function if it exports one.
+    ///
+    /// Refusing the code is an error, while an `init` that fails gives a failed execution.
+    pub fn deploy(&self, sender: &Address, nonce: u64, code: &[u8], gas_limit: u64) -> Result<Execution, ContractError> {
+        if code.len() > MAX_CODE_LEN {
+            return Err(ContractError::InvalidCode);
+        }
+        let contract = Self::contract_address(sender, nonce);
+        if self.contracts.contains_key(&contract) {
+            return Err(ContractError::InvalidCode);
+        }
+        let deployed = Contract::new(code);
+        Self::execute(contract, deployed, "init", sender, &[], gas_limit)
+    }
+
+    /// Runs the `call` function of a contract with the given input.
+    ///
+    /// Calling an unknown contract is an error, while a call that fails gives a failed execution.
+    pub fn call(&self, sender: &Address, contract: &Address, input: &[u8], gas_limit: u64) -> Result<Execution, ContractError> {
+        let called = self.contracts.get(contract).ok_or(ContractError::UnknownContract)?;
+        Self::execute(*contract, called.clone(), "call", sender, input, gas_limit)
+    }
+
+    /// Saves the contract resulting from an execution, unless the execution failed.
+    pub fn commit(&mut self, execution: Execution) {
+        if let Ok(mut contract) = execution.state {
+            contract.digest = contract.hash();
+            self.contracts.insert(execution.contract, contract);
+        }
+    }
+
+    fn execute(
+        address: Address,
+        mut contract: Contract,
+        function: &str,
+        sender: &Address,
+        input: &[u8],
+        gas_limit: u64,
+    ) -> Result<Execution, ContractError> {
+        if gas_limit > MAX_GAS {
+            return Err(ContractError::GasLimitTooHigh);
+        }
+        let mut config = Config::default();
+        config.consume_fuel(true).floats(false);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, &contract.code[..]).map_err(|_| ContractError::InvalidCode)?;
+        if module.get_export("call").and_then(|export| export.func().cloned()).is_none() {
+            return Err(ContractError::InvalidCode);
+        }
+
+        let state = HostState {
+            caller: *sender,
+            input: input.to_vec(),
+            storage: std::mem::take(&mut contract.storage),
+            limits: StoreLimitsBuilder::new().memory_size(MAX_MEMORY).instances(1).build(),
+            out_of_gas: false,
+        };
+        let mut store = Store::new(&engine, state);
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(gas_limit).expect("fuel metering is enabled");
+
+        let linker = Self::linker(&engine).map_err(|_| ContractError::InvalidCode)?;
+        let instance = linker.instantiate(&mut store, &module).map_err(|_| ContractError::InvalidCode)?;
+        let result = instance.start(&mut store).map_err(Self::trap).and_then(|instance| {
+            match instance.get_export(&store, function) {
+                Some(_) => instance
+                    .get_typed_func::<(), ()>(&store, function)
+                    .map_err(|_| ContractError::InvalidCode)?
+                    .call(&mut store, ())
+                    .map_err(Self::trap),
+                // Only `init` is optional, and `call` was checked above
+                None => Ok(()),
+            }
+        });
+
+        let remaining = store.get_fuel().expect("fuel metering is enabled");
+        let state = match result {
+            _ if store.data().out_of_gas => Err(ContractError::OutOfGas),
+            Err(e) => Err(e),
+            Ok(()) => {
+                contract.storage = store.into_data().storage;
+                Ok(contract)
+            }
+        };
+        // Running out of gas uses all of it, even if the last instruction cost more than what remained
+        let gas_used = match state {
+            Err(ContractError::OutOfGas) => gas_limit,
+            _ => gas_limit - remaining,
+        };
+        Ok(Execution { contract: address, gas_used, state })
+    }
+
+    fn trap(error: wasmi::Error) -> ContractError {
+        match error.as_trap_code() {
+            Some(TrapCode::OutOfFuel) => ContractError::OutOfGas,
+            _ => ContractError::Trapped(error.to_string()),
+        }
+    }
+
+    /// Returns the host functions that contracts can import.
+    fn linker(engine: &Engine) -> Result<Linker<HostState>, wasmi::Error> {
+        let mut linker = Linker::new(engine);
+        linker.func_wrap(HOST_MODULE, "input_len", |mut caller: Caller<'_, HostState>| {
+            charge(&mut caller, 0)?;
+            Ok(caller.data().input.len() as i32)
+        })?;
+        linker.func_wrap(HOST_MODULE, "read_input", |mut caller: Caller<'_, HostState>, ptr: i32| {
+            let input = caller.data().input.clone();
+            charge(&mut caller, input.len())?;
+            write_memory(&mut caller, ptr, &input)
+        })?;
+        linker.func_wrap(HOST_MODULE, "caller", |mut caller: Caller<'_, HostState>, ptr: i32| {
+            let address = caller.data().caller;
+            charge(&mut caller, address.as_bytes().len())?;
+            write_memory(&mut caller, ptr, address.as_bytes())
+        })?;
+        linker.func_wrap(
+            HOST_MODULE,
+            "storage_read",
+            |mut caller: Caller<'_, HostState>, key_ptr: i32, key_len: i32, value_ptr: i32, value_cap: i32| {
+                let key = read_memory(&mut caller, key_ptr, key_len, MAX_KEY_LEN)?;
+                let Some(value) = caller.data().storage.get(&key).cloned() else {
+                    charge(&mut caller, key.len())?;
+                    return Ok(-1);
+                };
+                let copied = value.len().min(value_cap.max(0) as usize);
+                charge(&mut caller, key.len() + copied)?;
+                write_memory(&mut caller, value_ptr, &value[..copied])?;
+                Ok(value.len() as i32)
+            },
+        )?;
+        linker.func_wrap(
+            HOST_MODULE,
+            "storage_write",
+            |mut caller: Caller<'_, HostState>, key_ptr: i32, key_len: i32, value_ptr: i32, value_len: i32| {
+                let key = read_memory(&mut caller, key_ptr, key_len, MAX_KEY_LEN)?;
+                let value = read_memory(&mut caller, value_ptr, value_len, MAX_VALUE_LEN)?;
+                charge_gas(&mut caller, (key.len() + value.len()) as u64 * STORAGE_BYTE_GAS)?;
+                caller.data_mut().storage.insert(key, value);
+                Ok(())
+            },
+        )?;
+        Ok(linker)
+    }
+}
+
+/// Charges a host call copying `bytes` bytes.
+fn charge(caller: &mut Caller<'_, HostState>, bytes: usize) -> Result<(), wasmi::Error> {
+    charge_gas(caller, HOST_CALL_GAS + bytes as u64 * BYTE_GAS)
+}
+
+fn charge_gas(caller: &mut Caller<'_, HostState>, gas: u64) -> Result<(), wasmi::Error> {
+    let fuel = caller.get_fuel().expect("fuel metering is enabled");
+    if fuel < gas {
+        caller.set_fuel(0).expect("fuel metering is enabled");
+        caller.data_mut().out_of_gas = true;
+        return Err(wasmi::Error::new(ContractError::OutOfGas.to_string()));
+    }
+    caller.set_fuel(fuel - gas).expect("fuel metering is enabled");
+    Ok(())
+}
+
+/// Copies `len` bytes of the memory of the contract, refusing more than `max` bytes.
+fn read_memory(caller: &mut Caller<'_, HostState>, ptr: i32, len: i32, max: usize) -> Result<Vec<u8>, wasmi::Error> {
+    if len < 0 || len as usize > max {
+        return Err(wasmi::Error::new("value too long"));
+    }
+    charge(caller, len as usize)?;
+    let memory = caller.get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| wasmi::Error::new("no exported memory"))?;
+    let mut buffer = vec![0; len as usize];
+    memory.read(&*caller, ptr as u32 as usize, &mut buffer)
+        .map_err(|_| wasmi::Error::new("read out of bounds"))?;
+    Ok(buffer)
+}
+
+fn write_memory(caller: &mut Caller<'_, HostState>, ptr: i32, data: &[u8]) -> Result<(), wasmi::Error> {
+    let memory = caller.get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| wasmi::Error::new("no exported memory"))?;
+    memory.write(&mut *caller, ptr as u32 as usize, data)
+        .map_err(|_| wasmi::Error::new("write out of bounds"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::Block;
+    use crate::blockchain::Blockchain;
+    use crate::client::Client;
+    use crate::contract::{ContractError, ContractState, GAS_PRICE, MAX_GAS};
+    use crate::signed_transaction::Payload;
+    use crate::transaction::TransactionError;
+
+    /// A contract counting its calls in the key "count".
+    const COUNTER: &str = r#"
+        (module
+          (import "env" "storage_read" (func $read (param i32 i32 i32 i32) (result i32)))
+          (import "env" "storage_write" (func $write (param i32 i32 i32 i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "count")
+          (func (export "init")
+            (call $write (i32.const 0) (i32.const 5) (i32.const 16) (i32.const 8)))
+          (func (export "call")
+            (drop (call $read (i32.const 0) (i32.const 5) (i32.const 16) (i32.const 8)))
+            (i64.store (i32.const 16) (i64.add (i64.load (i32.const 16)) (i64.const 1)))
+            (call $write (i32.const 0) (i32.const 5) (i32.const 16) (i32.const 8))))
+    "#;
+
+    /// A contract that never returns.
+    const LOOP: &str = r#"(module (func (export "call") (loop $forever (br $forever))))"#;
+
+    fn count(chain: &Blockchain, contract: &crate::address::Address) -> u64 {
+        let value = chain.ledger().contracts().get(contract).unwrap().get(b"count").unwrap();
+        u64::from_le_bytes(value.try_into().unwrap())
+    }
+
+    #[test]
+    fn test_counter_contract() {
+        let mut alice = Client::new();
+        let mut chain = Blockchain::with_allocations([(alice.address(), 1_000_000)]);
+        let empty_root = chain.last_block().state_root().to_string();
+
+        let deploy = alice.sign_payload(Payload::Deploy { code: wat::parse_str(COUNTER).unwrap(), gas_limit: 100_000 }, 0);
+        let contract = ContractState::contract_address(&alice.address(), deploy.nonce());
+        chain.add_test_block(vec![deploy.into()]);
+        assert_eq!(0, count(&chain, &contract));
+        assert_ne!(empty_root, chain.last_block().state_root());
+
+        for _ in 0..2 {
+            let call = alice.sign_payload(Payload::Call { contract, input: Vec::new(), gas_limit: 100_000 }, 0);
+            chain.add_test_block(vec![call.into()]);
+        }
+        assert_eq!(2, count(&chain, &contract));
+        assert_eq!(chain.ledger().contracts().root(), chain.last_block().state_root());
+    }
+
+    #[test]
+    fn test_state_root_is_checked_on_replay() {
+        let miner = Client::new().address();
+        let mut alice = Client::new();
+        let mut chain = Blockchain::with_allocations([(alice.address(), 1_000_000)]);
+        let deploy = alice.sign_payload(Payload::Deploy { code: wat::parse_str(COUNTER).unwrap(), gas_limit: 100_000 }, 0);
+        let contract = ContractState::contract_address(&alice.address(), deploy.nonce());
+        chain.add_test_block(vec![deploy.into()]);
+
+        // Every node replaying the chain reaches the same state
+        let replayed = chain.ledger().replay(chain.blocks()).unwrap();
+        assert_eq!(chain.ledger().contracts(), replayed.contracts());
+
+        // A block that does not commit to the state after its call is refused
+        let call = alice.sign_payload(Payload::Call { contract, input: Vec::new(), gas_limit: 100_000 }, 0);
+        let forged = Block::new_rewarded_after_block(call, chain.last_block(), &miner);
+        assert_eq!(false, chain.add_block_safe(forged));
+        assert_eq!(0, count(&chain, &contract));
+    }
+
+    #[test]
+    fn test_failed_executions_change_nothing() {
+        let alice = Client::new().address();
+        let mut state = ContractState::new();
+        let execution = state.deploy(&alice, 1, &wat::parse_str(LOOP).unwrap(), 1000).unwrap();
+        let contract = execution.contract;
+        state.commit(execution);
+        let root = state.root();
+
+        let execution = state.call(&alice, &contract, &[], 1000).unwrap();
+        assert_eq!(Some(&ContractError::OutOfGas), execution.error());
+        assert_eq!(1000, execution.gas_used);
+        state.commit(execution);
+        assert_eq!(ContractError::GasLimitTooHigh, state.call(&alice, &contract, &[], MAX_GAS + 1).unwrap_err());
+        assert_eq!(ContractError::UnknownContract, state.call(&alice, &alice, &[], 1000).unwrap_err());
+        assert_eq!(root, state.root());
+
+        // Floats could round differently on some machines, so they are refused
+        let floats = wat::parse_str(r#"(module (func (export "call") (drop (f32.const 1))))"#).unwrap();
+        assert_eq!(ContractError::InvalidCode, state.deploy(&alice, 2, &floats, 1000).unwrap_err());
+    }
+
+    #[test]
+    fn test_failed_call_pays_for_its_gas() {
+        let mut alice = Client::new();
+        let mut chain = Blockchain::with_allocations([(alice.address(), 5000)]);
+        let deploy = alice.sign_payload(Payload::Deploy { code: wat::parse_str(LOOP).unwrap(), gas_limit: 1000 }, 0);
+        let contract = ContractState::contract_address(&alice.address(), deploy.nonce());
+        chain.add_test_block(vec![deploy.into()]);
+        let balance = chain.ledger().balance(&alice.address());
+        let root = chain.ledger().contracts().root();
+
+        // The call runs out of gas: it is written, and pays for all its gas, but changes nothing
+        let call = alice.sign_payload(Payload::Call { contract, input: Vec::new(), gas_limit: 1000 }, 0);
+        assert_eq!(Ok(()), chain.check_transaction(&call.clone().into()));
+        chain.add_test_block(vec![call.into()]);
+        assert_eq!(balance - 1000 * GAS_PRICE, chain.ledger().balance(&alice.address()));
+        assert_eq!(2, chain.ledger().nonce(&alice.address()));
+        assert_eq!(root, chain.ledger().contracts().root());
+    }
+
+    #[test]
+    fn test_gas_must_be_affordable() {
+        let mut alice = Client::new();
+        let chain = Blockchain::with_allocations([(alice.address(), 999)]);
+        let deploy = alice.sign_payload(Payload::Deploy { code: wat::parse_str(LOOP).unwrap(), gas_limit: 1000 }, 0);
+        assert_eq!(Err(TransactionError::NotEnoughSold), chain.check_transaction(&deploy.into()));
+    }
+}
@@ -0,0 +1,152 @@
+use bip39::Mnemonic;
+use ed25519_dalek::SigningKey;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sha2::Sha512;
+use crate::address::Address;
+
+/// Bytes of entropy of a new mnemonic, which then has 24 words.
+const MNEMONIC_ENTROPY_LEN: usize = 32;
+
+/// Key of the HMAC deriving the master key from the seed, as defined by SLIP-0010 for ed25519.
+const MASTER_HMAC_KEY: &[u8] = b"ed25519 seed";
+
+/// Offset of the hardened indexes. ed25519 only supports hardened derivation.
+const HARDENED: u32 = 0x8000_0000;
+
+/// Purpose of the derivation path, following BIP44.
+const PURPOSE: u32 = 44;
+
+/// Coin type of the derivation path. 1 is the coin type shared by test networks.
+const COIN_TYPE: u32 = 1;
+
+/// Number of consecutive unused accounts after which the recovery stops looking for more.
+pub const GAP_LIMIT: u32 = 20;
+
+/// A private key with the chain code from which its children are derived.
+struct ExtendedKey {
+    key: [u8; 32],
+    chain_code: [u8; 32],
+}
+
+impl ExtendedKey {
+    fn master(seed: &[u8]) -> Self {
+        Self::from_hmac(MASTER_HMAC_KEY, &[seed])
+    }
+
+    /// Derives the hardened child of the given index.
+    fn child(&self, index: u32) -> Self {
+        let index = (index | HARDENED).to_be_bytes();
+        Self::from_hmac(&self.chain_code, &[&[0], &self.key, &index])
+    }
+
+    fn from_hmac(key: &[u8], data: &[&[u8]]) -> Self {
+        let mut mac = Hmac::<Sha512>::new_from_slice(key).expect("HMAC accepts keys of any length");
+        for part in data {
+            mac.update(part);
+        }
+        let output = mac.finalize().into_bytes();
+        let mut extended = Self { key: [0; 32], chain_code: [0; 32] };
+        extended.key.copy_from_slice(&output[..32]);
+        extended.chain_code.copy_from_slice(&output[32..]);
+        extended
+    }
+}
+
+/// A hierarchical deterministic wallet: all its keys are derived from a single seed, so that
+/// they can be recovered from the mnemonic phrase of the seed.
+///
+/// Keys are derived with SLIP-0010, the ed25519 variant of BIP32, and the key of the account
+/// `n` is at the path `m/44'/1'/n'`.
+pub struct HdWallet {
+    /// Key at `m/44'/1'`, parent of the keys of all the accounts
+    root: ExtendedKey,
+}
+
+impl HdWallet {
+    /// Generates a new random mnemonic phrase.
+    pub fn generate_mnemonic() -> Mnemonic {
+        let mut entropy = [0; MNEMONIC_ENTROPY_LEN];
+        OsRng.fill_bytes(&mut entropy);
+        Mnemonic::from_entropy(&entropy).expect("32 bytes is a valid entropy length")
+    }
+
+    /// Returns the wallet of a mnemonic phrase, used without BIP39 passphrase.
+    pub fn from_mnemonic(mnemonic: &Mnemonic) -> Self {
+        Self::from_seed(&mnemonic.to_seed(""))
+    }
+
+    pub fn from_seed(seed: &[u8]) -> Self {
+        let root = ExtendedKey::master(seed).child(PURPOSE).child(COIN_TYPE);
+        Self { root }
+    }
+
+    /// Derives the key of the account of the given index.
+    pub fn signing_key(&self, index: u32) -> SigningKey {
+        SigningKey::from_bytes(&self.root.child(index).key)
+    }
+
+    pub fn address(&self, index: u32) -> Address {
+        Address::from_public_key(&self.signing_key(index).verifying_key())
+    }
+
+    /// Finds the accounts of this wallet for which `is_used` returns true, typically the ones
+    /// that appear on the chain.
+    ///
+    /// Accounts are scanned in order, until `GAP_LIMIT` consecutive ones are unused. Returns
+    /// the index and the key of each used account.
+    pub fn recover(&self, mut is_used: impl FnMut(&Address) -> bool) -> Vec<(u32, SigningKey)> {
+        let mut used = Vec::new();
+        let mut gap = 0;
+        let mut index = 0;
+        while gap < GAP_LIMIT && index < HARDENED {
+            let key = self.signing_key(index);
+            if is_used(&Address::from_public_key(&key.verifying_key())) {
+                used.push((index, key));
+                gap = 0;
+            } else {
+                gap += 1;
+            }
+            index += 1;
+        }
+        used
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bip39::Mnemonic;
+    use crate::hd_wallet::{ExtendedKey, HdWallet, GAP_LIMIT};
+
+    #[test]
+    fn test_slip10_vector() {
+        // Test vector 1 of SLIP-0010, for ed25519
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let master = ExtendedKey::master(&seed);
+        assert_eq!("2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7", hex::encode(master.key));
+        assert_eq!("90046a93de5380a72b5e45010748567d5ea02bbf6522f979e05c0d8d8ca9fffb", hex::encode(master.chain_code));
+
+        let child = master.child(0);
+        assert_eq!("68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3", hex::encode(child.key));
+        assert_eq!("8b59aa11380b624e81507a27fedda59fea6d0b779a778918a2fd3590e16e9c69", hex::encode(child.chain_code));
+    }
+
+    #[test]
+    fn test_keys_are_recovered_from_the_phrase() {
+        let mnemonic = HdWallet::generate_mnemonic();
+        assert_eq!(24, mnemonic.word_count());
+        let wallet = HdWallet::from_mnemonic(&mnemonic);
+        let used = [wallet.address(0), wallet.address(3), wallet.address(4 + GAP_LIMIT)];
+
+        let restored = HdWallet::from_mnemonic(&Mnemonic::parse(mnemonic.to_string()).unwrap());
+        let recovered: Vec<u32> = restored
+            .recover(|address| used.contains(address))
+            .into_iter()
+            .map(|(index, _)| index)
+            .collect();
+
+        // The last account is too far after the others to be found
+        assert_eq!(vec![0, 3], recovered);
+    }
+}
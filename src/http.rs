@@ -0,0 +1,84 @@
+use std::io::Read;
+use rouille::{Request, Response};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+
+/// Maximum length, in bytes, of the JSON body of a submitted transaction.
+pub const MAX_TRANSACTION_BODY_LEN: u64 = 1024 * 1024;
+
+/// Parses the JSON body of a request, of at most `max_len` bytes, or returns the response
+/// refusing it.
+pub fn read_json<T: DeserializeOwned>(request: &Request, max_len: u64) -> Result<T, Response> {
+    let is_json = request
+        .header("Content-Type")
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+    if !is_json {
+        return Err(error(415, "unsupported_media_type", "expected an application/json body"));
+    }
+    let Some(data) = request.data() else {
+        return Err(error(400, "missing_body", "the body was already read"));
+    };
+    let mut body = Vec::new();
+    if data.take(max_len + 1).read_to_end(&mut body).is_err() {
+        return Err(error(400, "unreadable_body", "the body could not be read"));
+    }
+    if body.len() as u64 > max_len {
+        return Err(error(413, "body_too_large", &format!("the body is larger than {max_len} bytes")));
+    }
+    serde_json::from_slice(&body).map_err(|e| error(400, "invalid_json", &e.to_string()))
+}
+
+/// Returns an error response, whose JSON body gives a stable code and a readable message.
+pub fn error(status: u16, code: &str, message: &str) -> Response {
+    Response::json(&json!({ "error": { "code": code, "message": message } })).with_status_code(status)
+}
+
+/// Returns the status of a response, and its JSON body.
+#[cfg(test)]
+pub(crate) fn status_and_json(response: Response) -> (u16, serde_json::Value) {
+    let mut body = String::new();
+    response.data.into_reader_and_size().0.read_to_string(&mut body).unwrap();
+    (response.status_code, serde_json::from_str(&body).unwrap())
+}
+
+/// Returns the status of an error response, and the code given in its body.
+#[cfg(test)]
+pub(crate) fn status_and_code(response: Response) -> (u16, String) {
+    let (status, body) = status_and_json(response);
+    (status, body["error"]["code"].as_str().unwrap().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use rouille::Request;
+    use crate::http::{read_json, status_and_code};
+
+    fn request(content_type: &str, body: &str) -> Request {
+        let headers = vec![("Content-Type".to_string(), content_type.to_string())];
+        Request::fake_http("POST", "/transactions", headers, body.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_json_body_is_parsed() {
+        let parsed: Result<Vec<u32>, _> = read_json(&request("application/json; charset=utf-8", "[1, 2]"), 16);
+        assert_eq!(Some(vec![1, 2]), parsed.ok());
+    }
+
+    #[test]
+    fn test_other_media_types_are_answered_with_415() {
+        let response = read_json::<Vec<u32>>(&request("text/plain", "[1, 2]"), 16).unwrap_err();
+        assert_eq!((415, "unsupported_media_type".to_string()), status_and_code(response));
+    }
+
+    #[test]
+    fn test_large_body_is_answered_with_413() {
+        let response = read_json::<Vec<u32>>(&request("application/json", "[1, 2, 3, 4, 5, 6]"), 16).unwrap_err();
+        assert_eq!((413, "body_too_large".to_string()), status_and_code(response));
+    }
+
+    #[test]
+    fn test_malformed_json_is_answered_with_400() {
+        let response = read_json::<Vec<u32>>(&request("application/json", "[1, 2"), 16).unwrap_err();
+        assert_eq!((400, "invalid_json".to_string()), status_and_code(response));
+    }
+}
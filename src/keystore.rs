@@ -0,0 +1,324 @@
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use argon2::Argon2;
+use bip39::Mnemonic;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::{SigningKey, SECRET_KEY_LENGTH};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use crate::address::Address;
+use crate::hd_wallet::HdWallet;
+
+/// Length of the salt given to the key derivation function.
+const SALT_LEN: usize = 16;
+
+/// Length of the nonce of ChaCha20-Poly1305.
+const NONCE_LEN: usize = 12;
+
+/// A secret encrypted with the key of the keystore.
+#[derive(Serialize, Deserialize)]
+struct Encrypted {
+    /// Hex encoded nonce
+    nonce: String,
+    /// Hex encoded ciphertext, including the authentication tag
+    ciphertext: String,
+}
+
+/// A signing key stored in the keystore.
+#[derive(Serialize, Deserialize)]
+struct StoredKey {
+    address: Address,
+    secret: Encrypted,
+    /// Index of the account in the HD wallet, if the key was derived from the mnemonic
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    index: Option<u32>,
+}
+
+/// Content of a keystore file.
+#[derive(Serialize, Deserialize)]
+struct KeystoreFile {
+    /// Hex encoded salt of the key derivation
+    salt: String,
+    /// An empty secret, used to check the password even when the keystore holds no key
+    check: Encrypted,
+    /// The mnemonic phrase from which keys are derived. Keystores created before HD wallets
+    /// only hold imported keys.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mnemonic: Option<Encrypted>,
+    /// Index of the next account to derive
+    #[serde(default)]
+    next_index: u32,
+    keys: Vec<StoredKey>,
+}
+
+/// Signing keys of a wallet, stored encrypted in a file.
+///
+/// Keys are either imported, or derived from the mnemonic of an HD wallet, which is stored
+/// encrypted as well.
+///
+/// The encryption key is derived from a password with Argon2id, and every secret is encrypted
+/// with ChaCha20-Poly1305, so that a wrong password or a corrupted file is detected.
+pub struct Keystore {
+    file: KeystoreFile,
+    cipher: ChaCha20Poly1305,
+}
+
+impl Keystore {
+    /// Creates an empty keystore, protected by the given password.
+    pub fn create(password: &str) -> Result<Self, KeystoreError> {
+        let mut salt = [0; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let cipher = derive_cipher(password, &salt)?;
+        let check = encrypt(&cipher, &[])?;
+        Ok(Self {
+            file: KeystoreFile { salt: hex::encode(salt), check, mnemonic: None, next_index: 0, keys: Vec::new() },
+            cipher,
+        })
+    }
+
+    /// Creates an empty HD wallet, whose keys are derived from the given mnemonic.
+    pub fn create_with_mnemonic(password: &str, mnemonic: &Mnemonic) -> Result<Self, KeystoreError> {
+        let mut keystore = Self::create(password)?;
+        keystore.file.mnemonic = Some(encrypt(&keystore.cipher, mnemonic.to_string().as_bytes())?);
+        Ok(keystore)
+    }
+
+    /// Restores an HD wallet from its mnemonic: the keys of the accounts for which `is_used`
+    /// returns true are added to the keystore.
+    pub fn restore(password: &str, mnemonic: &Mnemonic, is_used: impl FnMut(&Address) -> bool) -> Result<Self, KeystoreError> {
+        let mut keystore = Self::create_with_mnemonic(password, mnemonic)?;
+        for (index, key) in HdWallet::from_mnemonic(mnemonic).recover(is_used) {
+            keystore.insert(&key, Some(index))?;
+            keystore.file.next_index = index + 1;
+        }
+        Ok(keystore)
+    }
+
+    /// Opens a keystore written with `save`.
+    pub fn open(path: &Path, password: &str) -> Result<Self, KeystoreError> {
+        let file: KeystoreFile = serde_json::from_slice(&fs::read(path)?)?;
+        let salt = hex::decode(&file.salt).map_err(|_| KeystoreError::Corrupted)?;
+        let cipher = derive_cipher(password, &salt)?;
+        decrypt(&cipher, &file.check).map_err(|_| KeystoreError::WrongPassword)?;
+        Ok(Self { file, cipher })
+    }
+
+    /// Writes the keystore to a file that only its owner can read.
+    ///
+    /// The keystore is first written to a temporary file, so that an interrupted write never
+    /// loses the keys of a previous save.
+    pub fn save(&self, path: &Path) -> Result<(), KeystoreError> {
+        let tmp = path.with_extension("tmp");
+        // A temporary file left by an interrupted save may have other permissions
+        let _ = fs::remove_file(&tmp);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(&tmp)?.write_all(&serde_json::to_vec_pretty(&self.file)?)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// Adds a key to the keystore, and returns its address. Adding a key twice has no effect.
+    pub fn add(&mut self, key: &SigningKey) -> Result<Address, KeystoreError> {
+        self.insert(key, None)
+    }
+
+    /// Derives the key of the next account of the HD wallet, and returns its address.
+    pub fn derive_next(&mut self) -> Result<Address, KeystoreError> {
+        let index = self.file.next_index;
+        let key = self.hd_wallet()?.signing_key(index);
+        let address = self.insert(&key, Some(index))?;
+        self.file.next_index = index + 1;
+        Ok(address)
+    }
+
+    fn insert(&mut self, key: &SigningKey, index: Option<u32>) -> Result<Address, KeystoreError> {
+        let address = Address::from_public_key(&key.verifying_key());
+        if !self.contains(&address) {
+            let secret = encrypt(&self.cipher, key.as_bytes())?;
+            self.file.keys.push(StoredKey { address, secret, index });
+        }
+        Ok(address)
+    }
+
+    /// Returns true if keys can be derived from a mnemonic.
+    pub fn has_mnemonic(&self) -> bool {
+        self.file.mnemonic.is_some()
+    }
+
+    /// Decrypts the mnemonic phrase of the HD wallet.
+    pub fn mnemonic(&self) -> Result<Mnemonic, KeystoreError> {
+        let encrypted = self.file.mnemonic.as_ref().ok_or(KeystoreError::NoMnemonic)?;
+        let phrase = String::from_utf8(decrypt(&self.cipher, encrypted)?).map_err(|_| KeystoreError::Corrupted)?;
+        Mnemonic::parse(phrase).map_err(|_| KeystoreError::Corrupted)
+    }
+
+    fn hd_wallet(&self) -> Result<HdWallet, KeystoreError> {
+        Ok(HdWallet::from_mnemonic(&self.mnemonic()?))
+    }
+
+    pub fn contains(&self, address: &Address) -> bool {
+        self.file.keys.iter().any(|key| &key.address == address)
+    }
+
+    /// Returns the addresses of all the keys, in the order in which they were added.
+    pub fn addresses(&self) -> Vec<Address> {
+        self.file.keys.iter().map(|key| key.address).collect()
+    }
+
+    /// Decrypts the key of the given address.
+    pub fn signing_key(&self, address: &Address) -> Result<SigningKey, KeystoreError> {
+        let stored = self.file.keys
+            .iter()
+            .find(|key| &key.address == address)
+            .ok_or(KeystoreError::UnknownAddress(*address))?;
+        let secret: [u8; SECRET_KEY_LENGTH] = decrypt(&self.cipher, &stored.secret)?
+            .try_into()
+            .map_err(|_| KeystoreError::Corrupted)?;
+        let key = SigningKey::from_bytes(&secret);
+        if Address::from_public_key(&key.verifying_key()) != *address {
+            return Err(KeystoreError::Corrupted);
+        }
+        Ok(key)
+    }
+}
+
+fn derive_cipher(password: &str, salt: &[u8]) -> Result<ChaCha20Poly1305, KeystoreError> {
+    let mut key = [0; 32];
+    Argon2::default()
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|_| KeystoreError::Corrupted)?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+fn encrypt(cipher: &ChaCha20Poly1305, plaintext: &[u8]) -> Result<Encrypted, KeystoreError> {
+    let mut nonce = [0; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| KeystoreError::Corrupted)?;
+    Ok(Encrypted { nonce: hex::encode(nonce), ciphertext: hex::encode(ciphertext) })
+}
+
+fn decrypt(cipher: &ChaCha20Poly1305, encrypted: &Encrypted) -> Result<Vec<u8>, KeystoreError> {
+    let nonce = hex::decode(&encrypted.nonce).map_err(|_| KeystoreError::Corrupted)?;
+    let ciphertext = hex::decode(&encrypted.ciphertext).map_err(|_| KeystoreError::Corrupted)?;
+    if nonce.len() != NONCE_LEN {
+        return Err(KeystoreError::Corrupted);
+    }
+    cipher
+        .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+        .map_err(|_| KeystoreError::Corrupted)
+}
+
+/// Reasons why the keystore can't be used.
+#[derive(Debug)]
+pub enum KeystoreError {
+    Io(io::Error),
+    /// The file is not a keystore
+    Format(serde_json::Error),
+    WrongPassword,
+    /// A secret can't be decrypted, although the password is right
+    Corrupted,
+    /// The keystore holds no key for this address
+    UnknownAddress(Address),
+    /// The keystore was not created from a mnemonic, so it can't derive keys
+    NoMnemonic,
+}
+
+impl Display for KeystoreError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeystoreError::Io(e) => write!(f, "{e}"),
+            KeystoreError::Format(e) => write!(f, "Not a keystore: {e}"),
+            KeystoreError::WrongPassword => write!(f, "Wrong password"),
+            KeystoreError::Corrupted => write!(f, "The keystore is corrupted"),
+            KeystoreError::UnknownAddress(address) => write!(f, "No key for {address}"),
+            KeystoreError::NoMnemonic => write!(f, "The keystore has no mnemonic"),
+        }
+    }
+}
+
+impl std::error::Error for KeystoreError {}
+
+impl From<io::Error> for KeystoreError {
+    fn from(e: io::Error) -> Self {
+        KeystoreError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for KeystoreError {
+    fn from(e: serde_json::Error) -> Self {
+        KeystoreError::Format(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::Client;
+    use crate::hd_wallet::HdWallet;
+    use crate::keystore::{Keystore, KeystoreError};
+
+    #[test]
+    fn test_keys_survive_a_save() {
+        let client = Client::new();
+        let mut keystore = Keystore::create("correct horse").unwrap();
+        let address = keystore.add(client.signing_key()).unwrap();
+        assert_eq!(client.address(), address);
+
+        let path = std::env::temp_dir().join("repyh_test_keystore.json");
+        keystore.save(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(0o600, std::fs::metadata(&path).unwrap().permissions().mode() & 0o777);
+        }
+        let opened = Keystore::open(&path, "correct horse");
+        let wrong = Keystore::open(&path, "battery staple");
+        std::fs::remove_file(&path).unwrap();
+
+        let opened = opened.unwrap();
+        assert_eq!(vec![address], opened.addresses());
+        assert_eq!(client.signing_key().to_bytes(), opened.signing_key(&address).unwrap().to_bytes());
+        assert!(matches!(wrong, Err(KeystoreError::WrongPassword)));
+    }
+
+    #[test]
+    fn test_secrets_are_not_stored_in_clear() {
+        let client = Client::new();
+        let mut keystore = Keystore::create("correct horse").unwrap();
+        keystore.add(client.signing_key()).unwrap();
+        let as_json = serde_json::to_string(&keystore.file).unwrap();
+        assert_eq!(false, as_json.contains(&hex::encode(client.signing_key().to_bytes())));
+    }
+
+    #[test]
+    fn test_derived_keys_are_restored() {
+        let mnemonic = HdWallet::generate_mnemonic();
+        let mut keystore = Keystore::create_with_mnemonic("correct horse", &mnemonic).unwrap();
+        let first = keystore.derive_next().unwrap();
+        let second = keystore.derive_next().unwrap();
+        assert_ne!(first, second);
+
+        // Only the second account was used on the chain
+        let restored = Keystore::restore("battery staple", &mnemonic, |address| *address == second).unwrap();
+        assert_eq!(vec![second], restored.addresses());
+        assert_eq!(keystore.signing_key(&second).unwrap().to_bytes(), restored.signing_key(&second).unwrap().to_bytes());
+
+        // The restored keystore does not derive the used account again
+        let mut restored = restored;
+        assert_eq!(false, [first, second].contains(&restored.derive_next().unwrap()));
+
+        let mut plain = Keystore::create("correct horse").unwrap();
+        assert!(matches!(plain.derive_next(), Err(KeystoreError::NoMnemonic)));
+    }
+}
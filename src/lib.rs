@@ -1,4 +1,26 @@
+pub mod address;
+pub mod blob;
 pub mod block;
 pub mod blockchain;
+pub mod client;
+pub mod coinbase;
+pub mod contract;
+pub mod hd_wallet;
+pub mod keystore;
+pub mod mempool;
+pub mod merkle;
+pub mod message;
 pub mod mining;
+pub mod multisig;
+pub mod names;
+pub mod notary;
+pub mod scenario;
+pub mod script;
+pub mod signed_transaction;
 pub mod simple_transaction;
+pub mod simulation;
+pub mod state;
+pub mod token;
+pub mod transaction;
+pub mod utxo;
+pub mod validity;
@@ -1,38 +1,98 @@
+use repyh::blockchain::Blockchain;
+use repyh::coinbase::RewardSchedule;
 use repyh::simple_transaction::SimpleTransaction;
-use std::sync::{Arc, Mutex};
+use repyh::state::Ledger;
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::{mpsc, Arc, Mutex};
 use env_logger::Env;
 use crate::server::{run_web_server, Server};
 
+mod http;
 mod server;
 
+/// File in which the server saves its representation of the chain when it stops.
+const CHAIN_FILE: &str = "server_chain.json";
+
+/// Flag enabling the routes that take their data in the path of `GET` requests.
+const LEGACY_ROUTES_FLAG: &str = "--legacy-routes";
+
 /// The server is in charge of
 /// - keeping track of pending transactions.
 /// - responding to clients who want to submit new transactions.
-fn main() {
+fn main() -> ExitCode {
     let env = Env::default()
         .filter_or("MY_LOG_LEVEL", "info")
         .write_style_or("MY_LOG_STYLE", "always");
     env_logger::init_from_env(env);
     
-    let mut server = Server::new();
-
-    // Submit a bunch of transaction
-    server.submit_transaction(SimpleTransaction::from_str("Hello World"));
-    server.submit_transaction(SimpleTransaction::from_str("Starship landed"));
-    server.submit_transaction(SimpleTransaction::from_str("He dies on mars"));
-    server.submit_transaction(SimpleTransaction::from_str("Again something else"));
-    server.submit_transaction(SimpleTransaction::from_str("I got a book on that day"));
-    server.submit_transaction(SimpleTransaction::from_str("Please, never forget that i said that one day"));
-    server.submit_transaction(SimpleTransaction::from_str("The answer is 42"));
-    server.submit_transaction(SimpleTransaction::from_str("In the beginning the Universe was created"));
-    server.submit_transaction(SimpleTransaction::from_str("This was a bad move"));
-    server.submit_transaction(SimpleTransaction::from_str("He was his father"));
-    server.submit_transaction(SimpleTransaction::from_str("One chain to rule them all"));
+    let chain_file = Path::new(CHAIN_FILE);
+    let server = if chain_file.exists() {
+        match Blockchain::load(chain_file, Ledger::new(), RewardSchedule::default()) {
+            Ok(blockchain) => {
+                log::info!("Loaded the chain from {CHAIN_FILE}");
+                Server::with_blockchain(blockchain)
+            }
+            Err(e) => {
+                log::error!("Could not load the chain from {CHAIN_FILE}: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        // Submit a bunch of transaction, the first time the server starts
+        let mut server = Server::new();
+        let messages = [
+            "Hello World",
+            "Starship landed",
+            "He dies on mars",
+            "Again something else",
+            "I got a book on that day",
+            "Please, never forget that i said that one day",
+            "The answer is 42",
+            "In the beginning the Universe was created",
+            "This was a bad move",
+            "He was his father",
+            "One chain to rule them all",
+        ];
+        for message in messages {
+            // Plain text transactions are never refused
+            let _ = server.submit_transaction(SimpleTransaction::from_str(message));
+        }
+        server
+    };
 
     // Send a thread-safe pointer to the server to the webserver
     let server = Arc::new(Mutex::new(server));
 
+    // Stop the server on SIGINT (Ctrl-C) or SIGTERM
+    let (tx_shutdown, rx_shutdown) = mpsc::channel();
+    if let Err(e) = ctrlc::set_handler(move || {
+        let _ = tx_shutdown.send(());
+    }) {
+        log::error!("Could not install the signal handler: {e}");
+        return ExitCode::FAILURE;
+    }
+
+    let legacy_routes = std::env::args().skip(1).any(|arg| arg == LEGACY_ROUTES_FLAG);
+    if legacy_routes {
+        log::info!("The legacy GET routes are enabled");
+    }
+
     // Rouille example taken from https://github.com/tomaka/rouille/blob/master/examples/hello-world.rs
     let server_ref = server.clone();
-    run_web_server(server_ref);
+    match run_web_server(server_ref, legacy_routes, rx_shutdown) {
+        Ok(()) => {
+            let server = server.lock().unwrap();
+            log::info!("Server stopped with {} pending transactions.", server.mempool_len());
+            if let Err(e) = server.save_blockchain(chain_file) {
+                log::error!("Could not save the chain to {CHAIN_FILE}: {e}");
+                return ExitCode::FAILURE;
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            log::error!("Server error: {e}");
+            ExitCode::FAILURE
+        }
+    }
 }
\ No newline at end of file
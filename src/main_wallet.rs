@@ -0,0 +1,388 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use bip39::Mnemonic;
+use ed25519_dalek::{SigningKey, VerifyingKey, PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH};
+use rand::rngs::OsRng;
+use reqwest::Url;
+use serde_json::Value;
+use repyh::address::Address;
+use repyh::blob::split;
+use repyh::block::BlockHeader;
+use repyh::client::Client;
+use repyh::hd_wallet::HdWallet;
+use repyh::keystore::Keystore;
+use repyh::message::EncryptedMessage;
+use repyh::mining::DIFFICULTY;
+use repyh::notary::ExistenceProof;
+use repyh::signed_transaction::{Payload, SignedTransaction};
+use repyh::transaction::Transaction;
+
+/// Keystore used if `WALLET_FILE` is not set.
+const DEFAULT_WALLET_FILE: &str = "wallet.json";
+
+/// Node queried if `WALLET_NODE` is not set.
+const DEFAULT_NODE_URL: &str = "http://localhost:8000";
+
+const USAGE: &str = "Usage: wallet <command>
+
+Commands:
+  generate                               derives the key of a new account
+  import <secret key>                    imports an ed25519 secret key, given in hex
+  restore                                restores the accounts of a mnemonic phrase found on the chain
+  mnemonic                               shows the mnemonic phrase of the wallet
+  list                                   lists the addresses of the keystore
+  balance [address]                      shows the balance of an address, or of all of them
+  history <address>                      shows the transactions of an address
+  transfer <from> <to> <amount> [fee]    signs a transfer and submits it
+  notarize <from> <file> [metadata]      anchors the SHA-256 digest of a file on the chain
+  prove <file>                           checks that a file was notarized, against the headers
+  store <from> <file>                    stores a file on the chain, in chunks
+  fetch <digest> <file>                  downloads a stored file, and checks its digest
+  public-key <address>                   shows the public key of an address, to receive messages
+  message <from> <public key> <text>     sends a message that only the owner of the key can read
+  inbox                                  decrypts the messages sent to the addresses of the keystore
+
+Environment:
+  WALLET_FILE      keystore file (default: wallet.json)
+  WALLET_PASSWORD  password of the keystore, asked if not set
+  WALLET_NODE      URL of the node to query (default: http://localhost:8000)";
+
+/// A wallet: keeps the keys of the user in an encrypted keystore, and talks to a node.
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args.as_slice() {
+        ["generate"] => {
+            let mut keystore = open_or_create_keystore()?;
+            let address = if keystore.has_mnemonic() {
+                keystore.derive_next()?
+            } else {
+                // Keystores created before HD wallets hold random keys
+                keystore.add(&SigningKey::generate(&mut OsRng))?
+            };
+            keystore.save(&wallet_file())?;
+            println!("{address}");
+        }
+        ["import", secret] => {
+            let secret: [u8; SECRET_KEY_LENGTH] = hex::decode(secret)?
+                .try_into()
+                .map_err(|_| format!("A secret key has {SECRET_KEY_LENGTH} bytes"))?;
+            let mut keystore = open_or_create_keystore()?;
+            let address = keystore.add(&SigningKey::from_bytes(&secret))?;
+            keystore.save(&wallet_file())?;
+            println!("{address}");
+        }
+        ["restore"] => restore()?,
+        ["mnemonic"] => println!("{}", open_keystore()?.mnemonic()?),
+        ["list"] => {
+            for address in open_keystore()?.addresses() {
+                println!("{address}");
+            }
+        }
+        ["balance"] => {
+            for address in open_keystore()?.addresses() {
+                print_account(&address)?;
+            }
+        }
+        ["balance", address] => print_account(&address.parse()?)?,
+        ["history", address] => print_history(&address.parse()?)?,
+        ["transfer", from, to, amount] => transfer(from, to, amount, "0")?,
+        ["transfer", from, to, amount, fee] => transfer(from, to, amount, fee)?,
+        ["notarize", from, file] => notarize(from, file, "")?,
+        ["notarize", from, file, metadata] => notarize(from, file, metadata)?,
+        ["prove", file] => prove(file)?,
+        ["store", from, file] => store(from, file)?,
+        ["fetch", digest, file] => fetch(digest, file)?,
+        ["public-key", address] => {
+            let key = open_keystore()?.signing_key(&address.parse()?)?;
+            println!("{}", hex::encode(key.verifying_key().as_bytes()));
+        }
+        ["message", from, to, text] => message(from, to, text)?,
+        ["inbox"] => inbox()?,
+        _ => {
+            eprintln!("{USAGE}");
+            return Err("Invalid command".into());
+        }
+    }
+    Ok(())
+}
+
+fn wallet_file() -> PathBuf {
+    std::env::var("WALLET_FILE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from(DEFAULT_WALLET_FILE))
+}
+
+/// Reads the password from `WALLET_PASSWORD`, or asks for it.
+fn password() -> Result<String, Box<dyn Error>> {
+    if let Ok(password) = std::env::var("WALLET_PASSWORD") {
+        return Ok(password);
+    }
+    ask("Password")
+}
+
+fn ask(prompt: &str) -> Result<String, Box<dyn Error>> {
+    print!("{prompt}: ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn open_keystore() -> Result<Keystore, Box<dyn Error>> {
+    Ok(Keystore::open(&wallet_file(), &password()?)?)
+}
+
+/// Opens the keystore, or creates it with a new mnemonic the first time.
+fn open_or_create_keystore() -> Result<Keystore, Box<dyn Error>> {
+    let path = wallet_file();
+    if path.exists() {
+        return open_keystore();
+    }
+    println!("Creating the keystore {}", path.display());
+    let mnemonic = HdWallet::generate_mnemonic();
+    let keystore = Keystore::create_with_mnemonic(&password()?, &mnemonic)?;
+    println!("Write down this mnemonic phrase, it is the only way to recover your accounts:\n\n{mnemonic}\n");
+    Ok(keystore)
+}
+
+/// Creates the keystore from an existing mnemonic, with the accounts that appear on the chain.
+fn restore() -> Result<(), Box<dyn Error>> {
+    let path = wallet_file();
+    if path.exists() {
+        return Err(format!("{} already exists", path.display()).into());
+    }
+    let mnemonic = Mnemonic::parse(ask("Mnemonic phrase")?)?;
+
+    let mut error = None;
+    let keystore = Keystore::restore(&password()?, &mnemonic, |address| match get_history(address) {
+        Ok(history) => !history.is_empty(),
+        Err(e) => {
+            error.get_or_insert(e);
+            false
+        }
+    })?;
+    if let Some(e) = error {
+        return Err(e);
+    }
+
+    keystore.save(&path)?;
+    for address in keystore.addresses() {
+        println!("{address}");
+    }
+    Ok(())
+}
+
+/// Returns the URL of an end-point of the node. Segments are escaped.
+fn endpoint(segments: &[&str]) -> Result<Url, Box<dyn Error>> {
+    let node = std::env::var("WALLET_NODE").unwrap_or_else(|_| DEFAULT_NODE_URL.to_string());
+    let mut url = Url::parse(&node)?;
+    url.path_segments_mut()
+        .map_err(|_| format!("Invalid node URL: {node}"))?
+        .pop_if_empty()
+        .extend(segments);
+    Ok(url)
+}
+
+fn get_account(address: &Address) -> Result<Value, Box<dyn Error>> {
+    let response = reqwest::blocking::get(endpoint(&["account", &address.to_string()])?)?;
+    Ok(serde_json::from_str(&response.error_for_status()?.text()?)?)
+}
+
+fn print_account(address: &Address) -> Result<(), Box<dyn Error>> {
+    let account = get_account(address)?;
+    println!("{address}: balance {}, unspent outputs {}, nonce {}", account["balance"], account["unspent"], account["nonce"]);
+    if let Some(tokens) = account["tokens"].as_object() {
+        for (symbol, amount) in tokens {
+            println!("  {amount} {symbol}");
+        }
+    }
+    Ok(())
+}
+
+fn get_history(address: &Address) -> Result<Vec<Value>, Box<dyn Error>> {
+    let response = reqwest::blocking::get(endpoint(&["history", &address.to_string()])?)?;
+    Ok(serde_json::from_str(&response.error_for_status()?.text()?)?)
+}
+
+fn print_history(address: &Address) -> Result<(), Box<dyn Error>> {
+    for entry in get_history(address)? {
+        let tx: Transaction = serde_json::from_value(entry["transaction"].clone())?;
+        println!("block {}: {} {tx:?}", entry["height"], entry["id"].as_str().unwrap_or_default());
+    }
+    Ok(())
+}
+
+/// Submits a signed transaction to the node, and returns its id.
+///
+/// The node answers with the reason when it refuses the transaction.
+fn submit(tx: SignedTransaction) -> Result<String, Box<dyn Error>> {
+    let tx = Transaction::from(tx);
+    let response = reqwest::blocking::Client::new()
+        .post(endpoint(&["transactions"])?)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_string(&tx)?)
+        .send()?;
+    if response.status().is_success() {
+        return Ok(tx.id());
+    }
+    let status = response.status();
+    let body: Value = serde_json::from_str(&response.text()?).unwrap_or_default();
+    let reason = body["error"]["message"].as_str().map(str::to_string).unwrap_or_else(|| status.to_string());
+    Err(format!("{}: {reason}", tx.id()).into())
+}
+
+/// Signs a transfer with the key of `from`, and submits it to the node.
+fn transfer(from: &str, to: &str, amount: &str, fee: &str) -> Result<(), Box<dyn Error>> {
+    let from: Address = from.parse()?;
+    let to: Address = to.parse()?;
+    let amount: u64 = amount.parse()?;
+    let fee: u64 = fee.parse()?;
+
+    let keystore = open_keystore()?;
+    let mut client = Client::from_signing_key(keystore.signing_key(&from)?);
+
+    // The nonce must be higher than the one of the last transaction of the account
+    let account = get_account(&from)?;
+    if account["balance"].as_u64().unwrap_or(0) < amount.saturating_add(fee) {
+        return Err(format!("{from} can't afford {amount} plus a fee of {fee}").into());
+    }
+    client.set_nonce(account["nonce"].as_u64().unwrap_or(0));
+
+    let id = submit(client.sign_payload(Payload::Transfer { receiver: to, amount }, fee))?;
+    println!("{id}: accepted");
+    Ok(())
+}
+
+/// Signs the notarization of the digest of a file with the key of `from`, and submits it.
+fn notarize(from: &str, file: &str, metadata: &str) -> Result<(), Box<dyn Error>> {
+    let from: Address = from.parse()?;
+    let digest = sha256::digest(std::fs::read(file)?);
+
+    let keystore = open_keystore()?;
+    let mut client = Client::from_signing_key(keystore.signing_key(&from)?);
+    client.set_nonce(get_account(&from)?["nonce"].as_u64().unwrap_or(0));
+
+    submit(client.sign_payload(Payload::Notarize { digest: digest.clone(), metadata: metadata.to_string() }, 0))?;
+    println!("{digest}: accepted");
+    Ok(())
+}
+
+/// Fetches the proof of existence of a file and the headers of the chain, and checks the proof
+/// locally: the node is not trusted.
+fn prove(file: &str) -> Result<(), Box<dyn Error>> {
+    let digest = sha256::digest(std::fs::read(file)?);
+    let response = reqwest::blocking::get(endpoint(&["proof", &digest])?)?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(format!("{digest} is not notarized").into());
+    }
+    let proof: ExistenceProof = serde_json::from_str(&response.error_for_status()?.text()?)?;
+    let response = reqwest::blocking::get(endpoint(&["headers"])?)?;
+    let headers: Vec<BlockHeader> = serde_json::from_str(&response.error_for_status()?.text()?)?;
+
+    let confirmations = proof.verify(&digest, &headers, DIFFICULTY)?;
+    println!("{digest}: notarized in block {} ({}), {confirmations} confirmations", proof.height, proof.block_hash);
+    Ok(())
+}
+
+/// Splits a file in chunks, and submits them followed by their manifest, signed with the key of
+/// `from`. Chunks already stored on the chain are skipped, and the manifest is written once all
+/// the chunks are.
+fn store(from: &str, file: &str) -> Result<(), Box<dyn Error>> {
+    let from: Address = from.parse()?;
+    let (chunks, manifest) = split(&std::fs::read(file)?);
+
+    let keystore = open_keystore()?;
+    let mut client = Client::from_signing_key(keystore.signing_key(&from)?);
+    client.set_nonce(get_account(&from)?["nonce"].as_u64().unwrap_or(0));
+
+    let mut submitted = HashSet::new();
+    let mut payloads = Vec::new();
+    for chunk in chunks {
+        let digest = sha256::digest(chunk.as_slice());
+        if submitted.insert(digest.clone()) && !is_chunk_stored(&digest)? {
+            payloads.push(Payload::Chunk(chunk));
+        }
+    }
+    let stored = submitted.len() - payloads.len();
+    payloads.push(Payload::Manifest(manifest.clone()));
+    for payload in payloads {
+        submit(client.sign_payload(payload, 0)).map_err(|e| format!("A transaction of {file} was refused: {e}"))?;
+    }
+    println!(
+        "{}: {} bytes in {} chunks, {stored} of them already stored",
+        manifest.digest, manifest.size, manifest.chunks.len()
+    );
+    Ok(())
+}
+
+/// Returns true if the chunk of the given digest is stored on the chain.
+fn is_chunk_stored(digest: &str) -> Result<bool, Box<dyn Error>> {
+    let response = reqwest::blocking::get(endpoint(&["chunk", digest])?)?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+    response.error_for_status()?;
+    Ok(true)
+}
+
+/// Downloads a blob stored on the chain, and checks that it has the expected digest.
+fn fetch(digest: &str, file: &str) -> Result<(), Box<dyn Error>> {
+    let response = reqwest::blocking::get(endpoint(&["blob", digest])?)?;
+    if !response.status().is_success() {
+        return Err(format!("{digest}: {}", response.text()?).into());
+    }
+    let blob = response.bytes()?;
+    if sha256::digest(blob.as_ref()) != digest {
+        return Err(format!("{digest}: the node returned a corrupted blob").into());
+    }
+    std::fs::write(file, &blob)?;
+    println!("{digest}: {} bytes written to {file}", blob.len());
+    Ok(())
+}
+
+/// Encrypts a message for the owner of a public key, signs it with the key of `from`, and
+/// submits it.
+fn message(from: &str, to: &str, text: &str) -> Result<(), Box<dyn Error>> {
+    let from: Address = from.parse()?;
+    let to: [u8; PUBLIC_KEY_LENGTH] = hex::decode(to)?
+        .try_into()
+        .map_err(|_| format!("A public key is {PUBLIC_KEY_LENGTH} bytes long"))?;
+    let message = EncryptedMessage::encrypt(&VerifyingKey::from_bytes(&to)?, text.as_bytes());
+    let recipient = message.recipient;
+
+    let keystore = open_keystore()?;
+    let mut client = Client::from_signing_key(keystore.signing_key(&from)?);
+    client.set_nonce(get_account(&from)?["nonce"].as_u64().unwrap_or(0));
+
+    submit(client.sign_payload(Payload::Encrypted(message), 0))?;
+    println!("{recipient}: accepted");
+    Ok(())
+}
+
+/// Finds the messages sent to the addresses of the keystore in their history, and decrypts them.
+fn inbox() -> Result<(), Box<dyn Error>> {
+    let keystore = open_keystore()?;
+    for address in keystore.addresses() {
+        let key = keystore.signing_key(&address)?;
+        for entry in get_history(&address)? {
+            let Transaction::Signed(tx) = serde_json::from_value(entry["transaction"].clone())? else {
+                continue;
+            };
+            let Payload::Encrypted(message) = tx.payload() else { continue };
+            // The history also holds the messages sent by the address
+            if message.recipient != address {
+                continue;
+            }
+            let text = match message.decrypt(&key) {
+                Ok(text) => String::from_utf8_lossy(&text).into_owned(),
+                Err(e) => e.to_string(),
+            };
+            println!("block {}: from {}: {text}", entry["height"], tx.sender_address());
+        }
+    }
+    Ok(())
+}
@@ -1,23 +1,91 @@
 extern crate log;
 
 use repyh::block::Block;
-use repyh::simple_transaction::SimpleTransaction;
+use repyh::transaction::Transaction;
 use reqwest::Client;
 use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use env_logger::Env;
 use tokio::sync::{mpsc, oneshot};
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::Sender;
 use tokio_util::sync::CancellationToken;
 use repyh::blockchain::Blockchain;
-use repyh::mining::mine;
+use repyh::coinbase::RewardSchedule;
+use repyh::mining::{mine, DIFFICULTY};
+use ed25519_dalek::{SigningKey, SECRET_KEY_LENGTH};
+use rand::rngs::OsRng;
+use repyh::address::Address;
+use repyh::mempool::Mempool;
+use repyh::state::Ledger;
+use crate::node_api::NodeState;
 
+mod http;
+mod node_api;
 mod p2p_network;
 
-const DIFFICULTY: usize = 5;
+/// File of the data directory in which the main chain is saved when the node stops.
+const CHAIN_FILE: &str = "chain.json";
+
+/// Maximum time given to each component of the node to stop.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// File of the data directory holding the key to which the rewards of this node are paid.
+const MINER_KEY_FILE: &str = "miner.key";
+
+/// Maximum number of transactions waiting in the mempool of the node.
+const MEMPOOL_CAPACITY: usize = 1000;
+
+/// Number of pending transactions put in each block the node mines.
+const TRANSACTIONS_PER_BLOCK: usize = 20;
+
+/// Time waited before looking for transactions again, when there is nothing to mine.
+const IDLE_DELAY: Duration = Duration::from_secs(1);
+
+/// Directory in which a node stores its persistent data, if `NODE_DATA_DIR` is not set.
+const DEFAULT_DATA_DIR: &str = "node_data";
+
+/// Returns the directory in which this node stores its persistent data.
+///
+/// Several nodes running on the same machine must each use a different `NODE_DATA_DIR`.
+fn data_dir() -> PathBuf {
+    std::env::var("NODE_DATA_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from(DEFAULT_DATA_DIR))
+}
+
+/// Loads the key of the miner from the data directory, or generates it the first time.
+///
+/// The key is kept across restarts: it is the only way to spend the rewards of this node.
+fn load_or_create_miner_key(data_dir: &Path) -> Result<SigningKey, Box<dyn Error>> {
+    let path = data_dir.join(MINER_KEY_FILE);
+    if path.exists() {
+        let bytes: [u8; SECRET_KEY_LENGTH] = fs::read(&path)?
+            .try_into()
+            .map_err(|_| format!("{} is not an ed25519 secret key", path.display()))?;
+        return Ok(SigningKey::from_bytes(&bytes));
+    }
+
+    let key = SigningKey::generate(&mut OsRng);
+    fs::create_dir_all(data_dir)?;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&path)?.write_all(&key.to_bytes())?;
+    log::info!("Generated a new miner key in {}", path.display());
+    Ok(key)
+}
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn main() -> Result<ExitCode, Box<dyn Error>> {
     let env = Env::default()
         .filter_or("MY_LOG_LEVEL", "info")
         .write_style_or("MY_LOG_STYLE", "always");
@@ -28,39 +96,92 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .parse_env(env)
         .init();
 
-    let (tx_local_block, rx_local_block) = mpsc::unbounded_channel();
-    let (tx_network_blocks, mut rx_network_blocks) = mpsc::unbounded_channel::<String>();
+    let data_dir = data_dir();
+    let keypair = p2p_network::load_or_create_identity(&data_dir)?;
+
+    // `node peer-id` only prints the identity of this node.
+    if std::env::args().nth(1).as_deref() == Some("peer-id") {
+        println!("{}", keypair.public().to_peer_id());
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let miner = Address::from_public_key(&load_or_create_miner_key(&data_dir)?.verifying_key());
+    log::info!("Rewards are paid to {miner}");
+    let api_port = node_api::api_port()?;
+
+    // Cancelled when the node is asked to stop. Every mining task uses a child of this token.
+    let shutdown = CancellationToken::new();
+    tokio::spawn(wait_for_shutdown_signal(shutdown.clone()));
+
+    let (tx_local_block, rx_local_block) = mpsc::channel(p2p_network::CHANNEL_CAPACITY);
+    let (tx_network_blocks, mut rx_network_blocks) = mpsc::channel::<String>(p2p_network::CHANNEL_CAPACITY);
+    let (tx_local_transactions, rx_local_transactions) = mpsc::channel(p2p_network::CHANNEL_CAPACITY);
+    let (tx_network_transactions, rx_network_transactions) = mpsc::channel(p2p_network::CHANNEL_CAPACITY);
+    let channels = p2p_network::SwarmChannels {
+        rx_local_blocks: rx_local_block,
+        tx_network_blocks,
+        rx_local_transactions,
+        tx_network_transactions,
+    };
 
     // Create a thread that listens to the P2P network
     // This allows us to know if another node found a node, and if so, to check it...
-    p2p_network::join_p2p_network(rx_local_block, tx_network_blocks).expect("TODO: panic message");
+    let peers = p2p_network::Peers::default();
+    let p2p = p2p_network::join_p2p_network(keypair, channels, peers.clone(), shutdown.clone())?;
     
     // Leave some initial time so that the P2P network setup correctly
     log::info!("P2P initialized. Waiting for a small delay for initialization to finish...");
-    tokio::time::sleep(tokio::time::Duration::from_secs(20)).await;
-    
-    let chain = Arc::new(Mutex::new(Blockchain::new()));
+    tokio::select! {
+        _ = tokio::time::sleep(tokio::time::Duration::from_secs(20)) => {}
+        _ = shutdown.cancelled() => {}
+    }
+
+    let chain_file = data_dir.join(CHAIN_FILE);
+    let chain = if chain_file.exists() {
+        log::info!("Loading the chain from {}", chain_file.display());
+        Blockchain::load(&chain_file, Ledger::new(), RewardSchedule::default())?
+    } else {
+        Blockchain::new()
+    };
+    let chain = Arc::new(Mutex::new(chain));
+    let mempool = Arc::new(Mutex::new(Mempool::new(MEMPOOL_CAPACITY)));
     let client = reqwest::Client::new();
 
-    loop {
-        let token = CancellationToken::new();
+    // Every node receives transactions on its own API, and from the other nodes
+    tokio::spawn(receive_network_transactions(rx_network_transactions, chain.clone(), mempool.clone()));
+    let node = NodeState { chain: chain.clone(), mempool: mempool.clone(), peers, tx_local_transactions };
+    let (api, stop_api) = node_api::spawn_api(node, api_port)?;
+
+    let mut mining_task = None;
+    while !shutdown.is_cancelled() {
+        let token = shutdown.child_token();
         let cloned_token = token.clone();
         let cloned_client = client.clone();
         let cloned_tx = tx_local_block.clone();
         let cloned_chain = chain.clone();
+        let cloned_mempool = mempool.clone();
+        let cloned_miner = miner;
 
         let (mining_finished_signal, mining_finished_received) = oneshot::channel();
 
         // Create a new task, but don't await on the task
-        tokio::spawn(async move {
-            request_transaction_and_mine(cloned_tx, cloned_client, cloned_token, cloned_chain, mining_finished_signal).await;
-        });
+        mining_task = Some(tokio::spawn(async move {
+            if let Err(e) = request_transaction_and_mine(cloned_tx, cloned_client, cloned_token, cloned_chain, cloned_mempool, cloned_miner, mining_finished_signal).await {
+                log::error!("Mining failed: {e}");
+            }
+        }));
 
         tokio::select! {
             Some(msg) = rx_network_blocks.recv() => {
                 // New block received from the network
                 // Extract the new block
-                let block: Block = serde_json::from_str(&msg).unwrap();
+                let block: Block = match serde_json::from_str(&msg) {
+                    Ok(block) => block,
+                    Err(e) => {
+                        log::warn!("Malformed block from the network: {e}");
+                        continue;
+                    }
+                };
                 if block.is_hash_valid(DIFFICULTY) {
                     log::info!("Block from network arrived: {:?}", block.transactions());
                     if chain.lock().unwrap().add_block_safe(block) {
@@ -74,73 +195,178 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 }
             }
             // This branch is necessary to 'listen' for mining finished
-            val = mining_finished_received => {}
+            _ = mining_finished_received => {}
+            _ = shutdown.cancelled() => {}
         }
     }
 
+    // Graceful shutdown: mining tasks were cancelled with the shutdown token, let the last one
+    // finish before writing the chain.
+    log::info!("Shutting down...");
+    if let Some(task) = mining_task {
+        let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, task).await;
+    }
+
+    // No transaction is accepted anymore
+    let _ = stop_api.send(());
+    let mut exit_code = ExitCode::SUCCESS;
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, tokio::task::spawn_blocking(move || api.join())).await.is_err() {
+        log::error!("The API did not stop in time");
+        exit_code = ExitCode::FAILURE;
+    }
+
+    if let Err(e) = chain.lock().unwrap().save(&chain_file) {
+        log::error!("Could not save the chain to {}: {e}", chain_file.display());
+        exit_code = ExitCode::FAILURE;
+    }
+
+    // The P2P task announces our departure and closes the swarm. Blocks it receives meanwhile are
+    // dropped, so that it never waits for room in the channel.
+    drop(rx_network_blocks);
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, p2p).await.is_err() {
+        log::error!("The P2P network did not stop in time");
+        exit_code = ExitCode::FAILURE;
+    }
+
+    log::info!("Bye.");
+    Ok(exit_code)
+}
+
+/// Cancels the token as soon as the process receives SIGINT (Ctrl-C) or SIGTERM.
+async fn wait_for_shutdown_signal(shutdown: CancellationToken) {
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => { signal.recv().await; }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+    log::info!("Shutdown requested");
+    shutdown.cancel();
+}
+
+/// Adds the transactions published by the other nodes to the mempool, until the swarm stops.
+async fn receive_network_transactions(
+    mut rx_network_transactions: mpsc::Receiver<String>,
+    chain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+) {
+    while let Some(msg) = rx_network_transactions.recv().await {
+        let tx: Transaction = match serde_json::from_str(&msg) {
+            Ok(tx) => tx,
+            Err(e) => {
+                log::warn!("Malformed transaction from the network: {e}");
+                continue;
+            }
+        };
+        let chain = chain.lock().unwrap();
+        let mut mempool = mempool.lock().unwrap();
+        if mempool.contains(&tx) || chain.has_transaction(&tx) {
+            continue;
+        }
+        if let Err(e) = mempool.submit(tx, &chain) {
+            log::info!("Refused a transaction from the network: {e}");
+        }
+    }
 }
 
-/// * Ask the transaction server for a new transaction to mine
+/// * Gather the transactions submitted to this node, and those of the transaction server if it runs
 /// * Start to mine while listening for cancellation
 /// * If mining finished, forward your block to the network
 async fn request_transaction_and_mine(
-    tx_local_block: UnboundedSender<String>,
+    tx_local_block: Sender<String>,
     client: Client,
     cancellation_token: CancellationToken,
     chain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    miner: Address,
     mining_finished_signal: oneshot::Sender<()>,
 ) -> Result<(), Box<dyn Error>>
 {
 
-    // Ask the server for pending transaction
-    let response = if let Ok(response) = async_req("http://localhost:8000/get_transaction", &client).await {
-        Some(response)
-    } else {
-        None
-    };
+    // Ask the server for pending transactions, and add them to our own pool
+    let response = async_req("http://localhost:8000/get_transactions", &client).await.ok();
+    if let Some(response) = response {
+        let as_text = response.text().await?;
+        if let Ok(parsed) = serde_json::from_str::<Vec<Transaction>>(&as_text) {
+            let chain = chain.lock().unwrap();
+            let mut mempool = mempool.lock().unwrap();
+            for tx in parsed {
+                let _ = mempool.submit(tx, &chain);
+            }
+        }
+    }
 
-    match response {
-        Some(res) => {
-            // decrypt the transaction
-            let as_text = res.text().await?;
-            if let Ok(parsed) = serde_json::from_str::<SimpleTransaction>(&as_text) {
-                // We only mine if the transaction is not already written here
-                if chain.lock().unwrap().has_transaction(&parsed) {
-                    return Ok(());
-                }
+    // Transactions that are already written here, or that the other nodes would refuse, are left
+    // out of the block. The highest fees come first.
+    let mut new_block = {
+        let chain = chain.lock().unwrap();
+        let mut mempool = mempool.lock().unwrap();
+        mempool.prune(&chain);
+        chain.get_rewarded_candidate_block(mempool.best(TRANSACTIONS_PER_BLOCK), &miner)
+    };
 
-                log::info!("Mining new block: {parsed:?}");
+    // We only mine if there is something to write besides our reward
+    if new_block.transactions().len() < 2 {
+        tokio::select! {
+            _ = tokio::time::sleep(IDLE_DELAY) => {}
+            _ = cancellation_token.cancelled() => {}
+        }
+        return Ok(());
+    }
 
-                // Start to mine the block
-                // We use a cancellation token to abort the task
-                let mut new_block = chain.lock().unwrap().get_candidate_block(parsed);
-                if let Some(_) = mine(&mut new_block, DIFFICULTY, cancellation_token.clone()).await {
-                    log::info!("  Finished to mine !");
+    log::info!("Mining new block: {:?}", new_block.transactions());
 
-                    // Broadcast the mined bitcoin to the swarm.
-                    let as_json = serde_json::to_string(&new_block).unwrap();
-                    tx_local_block
-                        .send(as_json.clone())
-                        .expect("Broadcasting mined block did not work.");
+    // Start to mine the block, rewarding ourselves with its coinbase
+    // We use a cancellation token to abort the task
+    if let Some(_) = mine(&mut new_block, DIFFICULTY, cancellation_token.clone()).await {
+        log::info!("  Finished to mine !");
 
-                    // Set it in the chain.
-                    chain.lock().unwrap().add_block_unsafe(new_block);
-                    chain.lock().unwrap().resolve_pending_forks();
-                    chain.lock().unwrap().print_chain();
+        // Set it in the chain, unless the ledger refuses it.
+        {
+            let mut chain = chain.lock().unwrap();
+            if let Err(e) = chain.add_block_unsafe(new_block.clone()) {
+                log::error!("Dropping the mined block, refused by the ledger: {e}");
+                return Ok(());
+            }
+            chain.resolve_pending_forks();
+            chain.print_chain();
+        }
 
-                    // Send it to the server
-                    async_req(&format!("http://localhost:8000/submit_block/{}", as_json), &client).await;
+        // Broadcast the mined bitcoin to the swarm.
+        let as_json = serde_json::to_string(&new_block).unwrap();
+        // If the swarm is busy, wait until it can take the block. The swarm only stops when the node
+        // shuts down, and the block is already in our chain, which is saved.
+        if tx_local_block.send(as_json.clone()).await.is_err() {
+            log::warn!("The mined block was not broadcast: the node is shutting down");
+            return Ok(());
+        }
 
-                    // Send an interruption for the asynchronous system to retriever a loop.
-                    mining_finished_signal.send(()).unwrap_or(());
-                    cancellation_token.cancel();
-                }
+        // Send it to the server
+        // The error is not `Send`, so it must not be kept while the body is read.
+        match async_post("http://localhost:8000/blocks", as_json, &client).await.map_err(|e| e.to_string()) {
+            Ok(response) if response.status().is_success() => log::info!("The server accepted the block"),
+            Ok(response) => {
+                let status = response.status();
+                let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap_or_default()).unwrap_or_default();
+                let message = body["error"]["message"].as_str().unwrap_or("no reason given");
+                log::warn!("The server refused the block ({status}): {message}");
             }
+            Err(e) => log::warn!("Could not send the block to the server: {e}"),
         }
-        _ => {}
+
+        // Send an interruption for the asynchronous system to retriever a loop.
+        mining_finished_signal.send(()).unwrap_or(());
+        cancellation_token.cancel();
     }
-    
+
     Ok(())
 }
 
@@ -153,3 +379,15 @@ async fn async_req(url: &str, client: &Client) -> Result<reqwest::Response, Box<
         .await?;
     Ok(response)
 }
+
+/// Sends a JSON body in a POST request in async rust
+async fn async_post(url: &str, body: String, client: &Client) -> Result<reqwest::Response, Box<dyn Error>> {
+    let response = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .timeout(std::time::Duration::from_secs(180))
+        .send()
+        .await?;
+    Ok(response)
+}
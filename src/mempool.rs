@@ -0,0 +1,276 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, HashMap};
+use sha256::digest;
+use crate::blob::BlobError;
+use crate::blockchain::Blockchain;
+use crate::signed_transaction::Payload;
+use crate::transaction::{Transaction, TransactionError};
+
+/// Fee paid by a transaction for each byte it takes in a block.
+#[derive(Clone, Copy, Debug)]
+pub struct FeeRate {
+    fee: u64,
+    size: u64,
+}
+
+impl FeeRate {
+    pub fn new(fee: u64, tx: &Transaction) -> Self {
+        Self { fee, size: tx.to_bytes().len().max(1) as u64 }
+    }
+}
+
+impl PartialEq for FeeRate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for FeeRate {}
+
+impl PartialOrd for FeeRate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FeeRate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Compares fee / size without rounding
+        (self.fee as u128 * other.size as u128).cmp(&(other.fee as u128 * self.size as u128))
+    }
+}
+
+/// Pool of the transactions waiting to be mined, ordered by fee rate.
+///
+/// When the pool is full, the transactions paying the lowest fee rate are evicted first.
+pub struct Mempool {
+    /// Keyed by fee rate, and then by arrival: among transactions paying the same rate, the
+    /// oldest one comes first.
+    entries: BTreeMap<(FeeRate, Reverse<u64>), Transaction>,
+    /// Number of transactions received so far
+    sequence: u64,
+    capacity: usize,
+}
+
+impl Mempool {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            sequence: 0,
+            capacity,
+        }
+    }
+
+    /// Adds a transaction paying the given fee to the pool.
+    ///
+    /// If the pool is full, the transaction with the lowest fee rate is evicted, unless it pays at
+    /// least as much as the new one: in this case, the new transaction is refused.
+    pub fn insert(&mut self, tx: Transaction, fee: u64) -> Result<(), TransactionError> {
+        if self.contains(&tx) {
+            return Ok(());
+        }
+
+        let rate = FeeRate::new(fee, &tx);
+        if self.entries.len() >= self.capacity {
+            match self.entries.first_key_value() {
+                Some(((lowest, _), _)) if *lowest < rate => {
+                    let evicted = self.entries.pop_first();
+                    log::info!("Mempool is full, evicting {:?}", evicted.map(|(_, tx)| tx));
+                }
+                _ => return Err(TransactionError::FeeTooLow),
+            }
+        }
+
+        self.sequence += 1;
+        self.entries.insert((rate, Reverse(self.sequence)), tx);
+        Ok(())
+    }
+
+    /// Adds a transaction to the pool, if its signature is valid, if it can be written in the next
+    /// block of the chain, if its sender can afford it, and if its fee is high enough to enter the
+    /// pool.
+    ///
+    /// A manifest is accepted while some of its chunks are still waiting in the pool.
+    pub fn submit(&mut self, tx: Transaction, chain: &Blockchain) -> Result<(), TransactionError> {
+        tx.verify()?;
+        self.check(&tx, chain)?;
+        let fee = chain.utxos().fee(&tx);
+        self.insert(tx, fee)
+    }
+
+    /// Checks that the transaction can be written in the next block of the chain, counting the
+    /// chunks of the pool as stored.
+    fn check(&self, tx: &Transaction, chain: &Blockchain) -> Result<(), TransactionError> {
+        match chain.check_transaction(tx) {
+            Err(TransactionError::Blob(BlobError::MissingChunk)) => {
+                let Transaction::Signed(signed) = tx else { return Err(BlobError::MissingChunk.into()) };
+                let pending: HashMap<String, usize> = self.entries
+                    .values()
+                    .filter_map(|entry| match entry {
+                        Transaction::Signed(entry) => match entry.payload() {
+                            Payload::Chunk(data) => Some((digest(data.as_slice()), data.len())),
+                            _ => None,
+                        },
+                        _ => None,
+                    })
+                    .collect();
+                // The chain checks nothing else after the blobs of a manifest
+                let blobs = chain.ledger().blobs();
+                Ok(blobs.check_with_pending(signed.payload(), |chunk| pending.get(chunk).copied())?)
+            }
+            result => result,
+        }
+    }
+
+    /// Removes the transactions that are safely written in the chain, those that expired, and
+    /// those that became invalid on top of the chain. Transactions written in the last blocks are
+    /// kept, in case a reorganization drops them.
+    pub fn prune(&mut self, chain: &Blockchain) {
+        let evicted: Vec<(FeeRate, Reverse<u64>)> = self.entries
+            .iter()
+            .filter(|(_, tx)| {
+                if chain.is_transaction_expired(tx) {
+                    log::info!("Transaction {tx:?} expired");
+                    return true;
+                }
+                if chain.has_transaction(tx) {
+                    return chain.is_transaction_safely_written(tx);
+                }
+                if let Err(e) = self.check(tx, chain) {
+                    log::info!("Transaction {tx:?} is no longer valid: {e}");
+                    return true;
+                }
+                false
+            })
+            .map(|(key, _)| *key)
+            .collect();
+        for key in evicted {
+            self.entries.remove(&key);
+        }
+    }
+
+    pub fn contains(&self, tx: &Transaction) -> bool {
+        self.entries.values().any(|entry| entry == tx)
+    }
+
+    /// Returns up to `n` transactions, the highest fee rate first.
+    pub fn best(&self, n: usize) -> Vec<Transaction> {
+        self.entries.values().rev().take(n).cloned().collect()
+    }
+
+    /// Only keeps the transactions for which `keep` returns true.
+    pub fn retain(&mut self, mut keep: impl FnMut(&Transaction) -> bool) {
+        self.entries.retain(|_, tx| keep(tx));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blob::{split, BlobError, MAX_CHUNK_LEN};
+    use crate::blockchain::Blockchain;
+    use crate::client::Client;
+    use crate::mempool::Mempool;
+    use crate::signed_transaction::Payload;
+    use crate::transaction::{Transaction, TransactionError};
+
+    fn message(client: &mut Client, text: &str, fee: u64) -> Transaction {
+        client.sign_payload(Payload::Message(text.to_string()), fee).into()
+    }
+
+    #[test]
+    fn test_highest_fee_rate_first() {
+        let mut client = Client::new();
+        let mut mempool = Mempool::new(10);
+        let low = message(&mut client, "low", 1);
+        let high = message(&mut client, "high", 10);
+        let medium = message(&mut client, "medium", 5);
+        for (tx, fee) in [(&low, 1), (&high, 10), (&medium, 5)] {
+            mempool.insert(tx.clone(), fee).unwrap();
+        }
+        assert_eq!(vec![high, medium], mempool.best(2));
+    }
+
+    #[test]
+    fn test_lowest_fee_rate_is_evicted_when_full() {
+        let mut client = Client::new();
+        let mut mempool = Mempool::new(2);
+        let low = message(&mut client, "low", 1);
+        let medium = message(&mut client, "medium", 5);
+        let high = message(&mut client, "high", 10);
+        mempool.insert(low.clone(), 1).unwrap();
+        mempool.insert(medium.clone(), 5).unwrap();
+
+        assert_eq!(Ok(()), mempool.insert(high.clone(), 10));
+        assert_eq!(2, mempool.len());
+        assert_eq!(false, mempool.contains(&low));
+
+        // A transaction paying less than all the others is refused
+        assert_eq!(Err(TransactionError::FeeTooLow), mempool.insert(low, 1));
+        assert_eq!(vec![high, medium], mempool.best(2));
+    }
+
+    #[test]
+    fn test_submitted_transactions_are_checked_against_the_chain() {
+        let mut alice = Client::new();
+        let bob = Client::new();
+        let chain = Blockchain::with_allocations([(alice.address(), 10)]);
+        let mut mempool = Mempool::new(10);
+
+        let overdraw = alice.transfer(bob.address(), 11).into();
+        assert_eq!(Err(TransactionError::NotEnoughSold), mempool.submit(overdraw, &chain));
+        assert_eq!(Ok(()), mempool.submit(alice.transfer(bob.address(), 5).into(), &chain));
+        assert_eq!(1, mempool.len());
+    }
+
+    #[test]
+    fn test_prune_evicts_transactions_invalidated_by_the_chain() {
+        let mut alice = Client::new();
+        let bob = Client::new();
+        let mut chain = Blockchain::with_allocations([(alice.address(), 10)]);
+        let mut mempool = Mempool::new(10);
+        let written: Transaction = alice.transfer(bob.address(), 8).into();
+        let overdraw: Transaction = alice.transfer(bob.address(), 5).into();
+        mempool.submit(written.clone(), &chain).unwrap();
+        mempool.submit(overdraw.clone(), &chain).unwrap();
+
+        let block = chain.get_rewarded_candidate_block(vec![written.clone()], &Client::new().address());
+        assert_eq!(true, chain.add_block_safe(block));
+        mempool.prune(&chain);
+
+        // The written transaction is kept until it is safe from reorganizations
+        assert_eq!(true, mempool.contains(&written));
+        assert_eq!(false, mempool.contains(&overdraw));
+    }
+
+    #[test]
+    fn test_blob_is_stored_through_the_pool() {
+        let mut alice = Client::new();
+        let mut chain = Blockchain::new();
+        let mut mempool = Mempool::new(10);
+        let blob: Vec<u8> = (0..2 * MAX_CHUNK_LEN + 10).map(|i| (i % 251) as u8).collect();
+        let (chunks, manifest) = split(&blob);
+
+        // The manifest is refused until its chunks wait in the pool
+        let early = alice.sign_payload(Payload::Manifest(manifest.clone()), 0).into();
+        assert_eq!(Err(TransactionError::Blob(BlobError::MissingChunk)), mempool.submit(early, &chain));
+        for chunk in chunks {
+            mempool.submit(alice.sign_payload(Payload::Chunk(chunk), 0).into(), &chain).unwrap();
+        }
+        let manifest_tx = alice.sign_payload(Payload::Manifest(manifest.clone()), 0).into();
+        assert_eq!(Ok(()), mempool.submit(manifest_tx, &chain));
+
+        // The chunks are written before the manifest, in the same block
+        let block = chain.get_rewarded_candidate_block(mempool.best(mempool.len()), &Client::new().address());
+        assert_eq!(5, block.transactions().len());
+        assert_eq!(true, chain.add_block_safe(block));
+        assert_eq!(Ok(blob), chain.get_blob(&manifest.digest));
+    }
+}
@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+type Hash = [u8; 32];
+
+/// First byte hashed with a leaf, so that a leaf can't be taken for an inner node.
+const LEAF_TAG: u8 = 0;
+
+/// First byte hashed with the two children of an inner node.
+const NODE_TAG: u8 = 1;
+
+/// A step of a Merkle path: the hash of the sibling of the current node.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct MerkleStep {
+    /// Hash of the sibling, in hex
+    pub sibling: String,
+    /// True if the sibling is the left child of their parent
+    pub left: bool,
+}
+
+fn leaf_hash(item: &[u8]) -> Hash {
+    Sha256::new().chain_update([LEAF_TAG]).chain_update(item).finalize().into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    Sha256::new().chain_update([NODE_TAG]).chain_update(left).chain_update(right).finalize().into()
+}
+
+/// Returns the levels of the tree of the items, from the leaves to the root. The last node of a
+/// level with an odd number of nodes is moved up unchanged.
+fn levels(items: &[Vec<u8>]) -> Vec<Vec<Hash>> {
+    let mut levels = vec![items.iter().map(|item| leaf_hash(item)).collect::<Vec<Hash>>()];
+    while levels.last().unwrap().len() > 1 {
+        let next = levels.last().unwrap()
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => node_hash(left, right),
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+        levels.push(next);
+    }
+    levels
+}
+
+/// Returns the root of the Merkle tree of the items, in hex.
+pub fn merkle_root(items: &[Vec<u8>]) -> String {
+    match levels(items).last().unwrap().first() {
+        Some(root) => hex::encode(root),
+        None => hex::encode(Sha256::digest([])),
+    }
+}
+
+/// Returns the path from the item at `index` to the root, or `None` if there is no such item.
+pub fn merkle_path(items: &[Vec<u8>], index: usize) -> Option<Vec<MerkleStep>> {
+    if index >= items.len() {
+        return None;
+    }
+    let mut path = Vec::new();
+    let mut index = index;
+    for level in levels(items).iter().filter(|level| level.len() > 1) {
+        let sibling = index ^ 1;
+        if let Some(hash) = level.get(sibling) {
+            path.push(MerkleStep { sibling: hex::encode(hash), left: sibling < index });
+        }
+        index /= 2;
+    }
+    Some(path)
+}
+
+/// Checks that `path` leads from the item to the root.
+pub fn verify_merkle_path(item: &[u8], path: &[MerkleStep], root: &str) -> bool {
+    let mut hash = leaf_hash(item);
+    for step in path {
+        let Some(sibling) = hex::decode(&step.sibling).ok().and_then(|bytes| Hash::try_from(bytes).ok()) else {
+            return false;
+        };
+        hash = if step.left { node_hash(&sibling, &hash) } else { node_hash(&hash, &sibling) };
+    }
+    hex::encode(hash) == root
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::merkle::{merkle_path, merkle_root, verify_merkle_path};
+
+    #[test]
+    fn test_every_item_has_a_valid_path() {
+        for count in 1..=7u8 {
+            let items: Vec<Vec<u8>> = (0..count).map(|i| vec![i]).collect();
+            let root = merkle_root(&items);
+            for (index, item) in items.iter().enumerate() {
+                let path = merkle_path(&items, index).unwrap();
+                assert_eq!(true, verify_merkle_path(item, &path, &root));
+                assert_eq!(false, verify_merkle_path(b"other", &path, &root));
+            }
+            assert_eq!(None, merkle_path(&items, items.len()));
+        }
+    }
+
+    #[test]
+    fn test_root_depends_on_the_order() {
+        let items = vec![b"a".to_vec(), b"b".to_vec()];
+        let swapped = vec![b"b".to_vec(), b"a".to_vec()];
+        assert_ne!(merkle_root(&items), merkle_root(&swapped));
+        // A single leaf is hashed, so it can't be confused with the root of two leaves
+        assert_ne!(merkle_root(&items), merkle_root(&[b"ab".to_vec()]));
+    }
+}
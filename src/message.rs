@@ -0,0 +1,163 @@
+use std::fmt::{Display, Formatter};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload as AeadPayload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use crate::address::Address;
+use crate::transaction::push_field;
+
+/// Maximum length, in bytes, of the ciphertext of a message.
+pub const MAX_MESSAGE_LEN: usize = 4096;
+
+/// Bytes of the nonce of ChaCha20-Poly1305.
+const NONCE_LEN: usize = 12;
+
+/// Domain of the hash deriving the encryption key from the shared secret.
+const KEY_DERIVATION_TAG: &[u8] = b"repyh message";
+
+/// Reasons for a message to be impossible to decrypt.
+#[derive(Debug, PartialEq)]
+pub enum MessageError {
+    /// The message is addressed to another key
+    NotRecipient,
+    /// The ciphertext was altered, or encrypted with another key
+    Corrupted,
+}
+
+impl std::error::Error for MessageError {}
+
+impl Display for MessageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MessageError::NotRecipient => write!(f, "The message is addressed to another key"),
+            MessageError::Corrupted => write!(f, "The message can't be decrypted"),
+        }
+    }
+}
+
+/// A message that only its recipient can read.
+///
+/// The message is encrypted with ChaCha20-Poly1305, under a key derived from an X25519 exchange
+/// between a random key of the sender and the key of the recipient: the ed25519 key of an account
+/// is converted to its X25519 form. The address of the recipient stays public, so that wallets
+/// can find the messages addressed to them.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct EncryptedMessage {
+    pub recipient: Address,
+    /// Public X25519 key, used for this message only
+    ephemeral_key: [u8; 32],
+    nonce: [u8; NONCE_LEN],
+    ciphertext: Vec<u8>,
+}
+
+impl EncryptedMessage {
+    /// Encrypts a message for the owner of the given key.
+    pub fn encrypt(recipient: &VerifyingKey, plaintext: &[u8]) -> Self {
+        let recipient_key = PublicKey::from(recipient.to_montgomery().to_bytes());
+        let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral_key = PublicKey::from(&ephemeral).to_bytes();
+        let shared = ephemeral.diffie_hellman(&recipient_key);
+
+        let mut nonce = [0; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let recipient = Address::from_public_key(recipient);
+        let cipher = cipher(shared.as_bytes(), &ephemeral_key, recipient_key.as_bytes());
+        let aad = recipient.as_bytes();
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), AeadPayload { msg: plaintext, aad })
+            .expect("encryption with a valid key can't fail");
+        Self { recipient, ephemeral_key, nonce, ciphertext }
+    }
+
+    /// Decrypts the message with the key of its recipient.
+    pub fn decrypt(&self, key: &SigningKey) -> Result<Vec<u8>, MessageError> {
+        if Address::from_public_key(&key.verifying_key()) != self.recipient {
+            return Err(MessageError::NotRecipient);
+        }
+        let secret = StaticSecret::from(key.to_scalar_bytes());
+        let shared = secret.diffie_hellman(&PublicKey::from(self.ephemeral_key));
+        let cipher = cipher(shared.as_bytes(), &self.ephemeral_key, PublicKey::from(&secret).as_bytes());
+        let aad = self.recipient.as_bytes();
+        cipher
+            .decrypt(Nonce::from_slice(&self.nonce), AeadPayload { msg: &self.ciphertext, aad })
+            .map_err(|_| MessageError::Corrupted)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ciphertext.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ciphertext.is_empty()
+    }
+
+    pub(crate) fn push_bytes(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(self.recipient.as_bytes());
+        bytes.extend_from_slice(&self.ephemeral_key);
+        bytes.extend_from_slice(&self.nonce);
+        push_field(bytes, &self.ciphertext);
+    }
+}
+
+/// Derives the key of the message from the shared secret and both public keys.
+fn cipher(shared: &[u8; 32], ephemeral_key: &[u8; 32], recipient_key: &[u8; 32]) -> ChaCha20Poly1305 {
+    let key = Sha256::new()
+        .chain_update(KEY_DERIVATION_TAG)
+        .chain_update(shared)
+        .chain_update(ephemeral_key)
+        .chain_update(recipient_key)
+        .finalize();
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::Blockchain;
+    use crate::client::Client;
+    use crate::message::{EncryptedMessage, MessageError, MAX_MESSAGE_LEN};
+    use crate::signed_transaction::Payload;
+    use crate::transaction::{Transaction, TransactionError};
+
+    #[test]
+    fn test_only_the_recipient_decrypts() {
+        let bob = Client::new();
+        let eve = Client::new();
+        let message = EncryptedMessage::encrypt(&bob.public_key(), b"meet at noon");
+        assert_eq!(bob.address(), message.recipient);
+        assert_eq!(Ok(b"meet at noon".to_vec()), message.decrypt(bob.signing_key()));
+        assert_eq!(Err(MessageError::NotRecipient), message.decrypt(eve.signing_key()));
+
+        // Redirecting the message to another recipient breaks its authentication
+        let mut redirected = message.clone();
+        redirected.recipient = eve.address();
+        assert_eq!(Err(MessageError::Corrupted), redirected.decrypt(eve.signing_key()));
+        let mut tampered = message;
+        tampered.ciphertext[0] ^= 1;
+        assert_eq!(Err(MessageError::Corrupted), tampered.decrypt(bob.signing_key()));
+    }
+
+    #[test]
+    fn test_messages_are_written_on_the_chain() {
+        let mut alice = Client::new();
+        let bob = Client::new();
+        let mut chain = Blockchain::new();
+        let message = EncryptedMessage::encrypt(&bob.public_key(), b"hello bob");
+        chain.add_test_block(vec![alice.sign_payload(Payload::Encrypted(message), 0).into()]);
+
+        // Bob finds it in his history
+        let history = chain.history(&bob.address());
+        assert_eq!(1, history.len());
+        let Transaction::Signed(tx) = history[0].1 else { panic!() };
+        let Payload::Encrypted(message) = tx.payload() else { panic!() };
+        assert_eq!(Ok(b"hello bob".to_vec()), message.decrypt(bob.signing_key()));
+
+        let long = EncryptedMessage::encrypt(&bob.public_key(), &[0; MAX_MESSAGE_LEN]);
+        let tx = alice.sign_payload(Payload::Encrypted(long), 0).into();
+        assert_eq!(Err(TransactionError::Malformed), chain.check_transaction(&tx));
+    }
+}
@@ -2,6 +2,9 @@ use crate::block::Block;
 use sha256::digest;
 use tokio_util::sync::CancellationToken;
 
+/// Number of leading zeros of the hash of the blocks mined by the workers.
+pub const DIFFICULTY: usize = 5;
+
 /// Find the nonce for which the bytes of the given block match a pattern
 /// starting with N zeros, where `N` is the `difficulty` argument.
 pub async fn mine(
@@ -0,0 +1,280 @@
+use std::collections::HashSet;
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use crate::address::Address;
+use crate::signed_transaction::verify_signature;
+use crate::transaction::TransactionError;
+use crate::utxo::{OutPoint, TxInput, TxOutput, UtxoTransaction, Witness};
+use crate::validity::Validity;
+
+/// Maximum number of keys of a multisig policy.
+pub const MAX_MULTISIG_KEYS: usize = 15;
+
+/// Domain of the hash of a policy, so that the address of a policy can't be the address of a key.
+const POLICY_HASH_TAG: &[u8] = b"multisig";
+
+/// An m-of-n policy: outputs paid to its address can only be spent with the signatures of
+/// `threshold` distinct keys among `keys`.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct MultisigPolicy {
+    threshold: u8,
+    keys: Vec<VerifyingKey>,
+}
+
+impl MultisigPolicy {
+    pub fn new(threshold: u8, keys: Vec<VerifyingKey>) -> Result<Self, TransactionError> {
+        let policy = Self { threshold, keys };
+        policy.check()?;
+        Ok(policy)
+    }
+
+    /// Checks that the policy can be satisfied, and does not list a key twice.
+    pub fn check(&self) -> Result<(), TransactionError> {
+        let distinct: HashSet<&[u8; 32]> = self.keys.iter().map(VerifyingKey::as_bytes).collect();
+        if self.threshold == 0
+            || self.threshold as usize > self.keys.len()
+            || self.keys.len() > MAX_MULTISIG_KEYS
+            || distinct.len() != self.keys.len() {
+            return Err(TransactionError::Malformed);
+        }
+        Ok(())
+    }
+
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+
+    pub fn keys(&self) -> &[VerifyingKey] {
+        &self.keys
+    }
+
+    /// Returns the canonical encoding of the policy: the threshold, and the keys in order.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.threshold, self.keys.len() as u8];
+        for key in &self.keys {
+            bytes.extend_from_slice(key.as_bytes());
+        }
+        bytes
+    }
+
+    /// Returns the address to which outputs protected by this policy are paid.
+    pub fn address(&self) -> Address {
+        Address::from_hash_of(&[POLICY_HASH_TAG, &self.to_bytes()].concat())
+    }
+
+    /// Checks that `signatures` holds valid signatures of `data` from at least `threshold`
+    /// distinct keys of the policy. Signatures from other keys, or invalid ones, are refused.
+    pub fn verify(&self, data: &[u8], signatures: &[PartialSignature]) -> Result<(), TransactionError> {
+        self.check()?;
+        let mut signers = HashSet::new();
+        for partial in signatures {
+            if !self.keys.contains(&partial.key) {
+                return Err(TransactionError::InvalidSignature);
+            }
+            verify_signature(&partial.key, data, &partial.signature)?;
+            signers.insert(partial.key.to_bytes());
+        }
+        if signers.len() < self.threshold as usize {
+            return Err(TransactionError::NotEnoughSignatures);
+        }
+        Ok(())
+    }
+}
+
+/// The signature of one of the keys of a multisig policy.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct PartialSignature {
+    pub key: VerifyingKey,
+    pub signature: Vec<u8>,
+}
+
+/// An input of a partially signed transaction, with the signatures collected so far.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+struct PendingInput {
+    previous_output: OutPoint,
+    /// Policy of the spent output, or `None` if it was paid to the address of a single key
+    policy: Option<MultisigPolicy>,
+    /// Owner of the spent output
+    owner: Address,
+    signatures: Vec<PartialSignature>,
+}
+
+/// A UTXO transaction whose inputs are not all signed yet.
+///
+/// It is passed between the signers, as JSON for instance: each of them adds their signatures
+/// with `sign`, or copies from another signer are merged with `combine`. Once every input has
+/// enough signatures, `finalize` returns the transaction to submit.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct PartiallySignedTransaction {
+    inputs: Vec<PendingInput>,
+    outputs: Vec<TxOutput>,
+    #[serde(default)]
+    validity: Validity,
+}
+
+impl PartiallySignedTransaction {
+    pub fn new(outputs: Vec<TxOutput>) -> Self {
+        Self { inputs: Vec::new(), outputs, validity: Validity::default() }
+    }
+
+    /// Restricts the blocks in which the transaction can be written. It must be set before the
+    /// first signature.
+    pub fn with_validity(mut self, validity: Validity) -> Self {
+        self.validity = validity;
+        self
+    }
+
+    /// Spends an output paid to the address of a single key.
+    pub fn add_input(&mut self, previous_output: OutPoint, owner: Address) {
+        self.inputs.push(PendingInput { previous_output, policy: None, owner, signatures: Vec::new() });
+    }
+
+    /// Spends an output paid to the address of a multisig policy.
+    pub fn add_multisig_input(&mut self, previous_output: OutPoint, policy: MultisigPolicy) {
+        let owner = policy.address();
+        self.inputs.push(PendingInput { previous_output, policy: Some(policy), owner, signatures: Vec::new() });
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let inputs: Vec<OutPoint> = self.inputs.iter().map(|input| input.previous_output.clone()).collect();
+        UtxoTransaction::signed_bytes(&inputs, &self.outputs, &self.validity)
+    }
+
+    /// Signs every input that the key can spend, and returns how many it signed.
+    pub fn sign(&mut self, key: &SigningKey) -> usize {
+        let data = self.signed_bytes();
+        let public_key = key.verifying_key();
+        let mut signed = 0;
+        for input in &mut self.inputs {
+            let can_sign = match &input.policy {
+                Some(policy) => policy.keys().contains(&public_key),
+                None => Address::from_public_key(&public_key) == input.owner,
+            };
+            if can_sign && !input.signatures.iter().any(|partial| partial.key == public_key) {
+                let signature = key.sign(&data).to_vec();
+                input.signatures.push(PartialSignature { key: public_key, signature });
+                signed += 1;
+            }
+        }
+        signed
+    }
+
+    /// Adds the signatures collected by another signer of the same transaction.
+    pub fn combine(&mut self, other: &PartiallySignedTransaction) -> Result<(), TransactionError> {
+        if self.inputs.len() != other.inputs.len() || self.signed_bytes() != other.signed_bytes() {
+            return Err(TransactionError::Malformed);
+        }
+        for (input, theirs) in self.inputs.iter_mut().zip(&other.inputs) {
+            for partial in &theirs.signatures {
+                if !input.signatures.iter().any(|known| known.key == partial.key) {
+                    input.signatures.push(partial.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true when every input has enough valid signatures.
+    pub fn is_complete(&self) -> bool {
+        self.clone().finalize().is_ok()
+    }
+
+    /// Returns the transaction, if every input has enough valid signatures.
+    pub fn finalize(self) -> Result<UtxoTransaction, TransactionError> {
+        let data = self.signed_bytes();
+        let inputs = self.inputs
+            .into_iter()
+            .map(|input| {
+                let witness = match input.policy {
+                    Some(policy) => {
+                        policy.verify(&data, &input.signatures)?;
+                        Witness::Multisig { policy, signatures: input.signatures }
+                    }
+                    None => {
+                        let partial = input.signatures.into_iter().next().ok_or(TransactionError::NotEnoughSignatures)?;
+                        verify_signature(&partial.key, &data, &partial.signature)?;
+                        Witness::Key { public_key: partial.key, signature: partial.signature }
+                    }
+                };
+                Ok(TxInput { previous_output: input.previous_output, witness })
+            })
+            .collect::<Result<Vec<_>, TransactionError>>()?;
+        Ok(UtxoTransaction::new(inputs, self.outputs).with_validity(self.validity))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blockchain::Blockchain;
+    use crate::client::Client;
+    use crate::multisig::{MultisigPolicy, PartiallySignedTransaction};
+    use crate::state::Ledger;
+    use crate::transaction::{Transaction, TransactionError};
+    use crate::utxo::{OutPoint, TxOutput, UtxoSet, GENESIS_TX_ID};
+
+    fn team() -> (Vec<Client>, MultisigPolicy, Blockchain) {
+        let clients = vec![Client::new(), Client::new(), Client::new()];
+        let policy = MultisigPolicy::new(2, clients.iter().map(Client::public_key).collect()).unwrap();
+        let utxos = UtxoSet::with_allocations([TxOutput { amount: 100, owner: policy.address() }]);
+        (clients, policy, Blockchain::with_genesis(Ledger::new(), utxos))
+    }
+
+    fn genesis_output() -> OutPoint {
+        OutPoint { tx_id: GENESIS_TX_ID.to_string(), index: 0 }
+    }
+
+    #[test]
+    fn test_invalid_policies_are_refused() {
+        let keys = vec![Client::new().public_key(), Client::new().public_key()];
+        assert_eq!(Err(TransactionError::Malformed), MultisigPolicy::new(0, keys.clone()));
+        assert_eq!(Err(TransactionError::Malformed), MultisigPolicy::new(3, keys.clone()));
+        assert_eq!(Err(TransactionError::Malformed), MultisigPolicy::new(1, vec![keys[0], keys[0]]));
+    }
+
+    #[test]
+    fn test_spend_needs_threshold_signatures() {
+        let (clients, policy, mut chain) = team();
+        let receiver = Client::new();
+        let mut pst = PartiallySignedTransaction::new(vec![TxOutput { amount: 100, owner: receiver.address() }]);
+        pst.add_multisig_input(genesis_output(), policy);
+
+        // The first signer passes the transaction to the third one, as JSON
+        assert_eq!(1, pst.sign(clients[0].signing_key()));
+        assert_eq!(false, pst.is_complete());
+        assert_eq!(Err(TransactionError::NotEnoughSignatures), pst.clone().finalize().map(|_| ()));
+        let mut received: PartiallySignedTransaction = serde_json::from_str(&serde_json::to_string(&pst).unwrap()).unwrap();
+        received.sign(clients[2].signing_key());
+
+        let tx: Transaction = received.finalize().unwrap().into();
+        assert_eq!(Ok(()), chain.check_transaction(&tx));
+        chain.add_test_block(vec![tx]);
+        assert_eq!(100, chain.utxos().balance(&receiver.address()));
+    }
+
+    #[test]
+    fn test_signatures_are_counted_once_per_key() {
+        let (clients, policy, chain) = team();
+        let outsider = Client::new();
+        let mut pst = PartiallySignedTransaction::new(vec![TxOutput { amount: 100, owner: outsider.address() }]);
+        pst.add_multisig_input(genesis_output(), policy);
+        pst.sign(clients[1].signing_key());
+
+        // The same signer, combined twice, or a key outside the policy, does not count
+        let copy = pst.clone();
+        pst.combine(&copy).unwrap();
+        assert_eq!(0, pst.sign(clients[1].signing_key()));
+        assert_eq!(0, pst.sign(outsider.signing_key()));
+        assert_eq!(false, pst.is_complete());
+
+        // Signatures collected separately are merged
+        let mut other = copy.clone();
+        other.sign(clients[0].signing_key());
+        pst.combine(&other).unwrap();
+        let tx: Transaction = pst.finalize().unwrap().into();
+        assert_eq!(Ok(()), chain.check_transaction(&tx));
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use serde::{Deserialize, Serialize};
+use crate::address::Address;
+use crate::signed_transaction::Payload;
+use crate::transaction::push_field;
+
+/// Number of blocks after its registration, or its last update, after which a name expires.
+pub const NAME_LIFETIME: u64 = 10_000;
+
+/// Maximum length of a name.
+pub const MAX_NAME_LEN: usize = 63;
+
+/// Maximum length, in bytes, of a text record.
+pub const MAX_TEXT_LEN: usize = 256;
+
+/// Reasons for a name transaction to be refused.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NameError {
+    /// The name is empty, too long, or not made of lowercase letters, digits and dashes
+    InvalidName,
+    /// The text record is too long
+    InvalidRecord,
+    /// The name is registered, and not expired
+    NameTaken,
+    /// The name is not registered, or expired
+    UnknownName,
+    /// The sender does not own the name
+    NotOwner,
+}
+
+impl std::error::Error for NameError {}
+
+impl Display for NameError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NameError::InvalidName => write!(f, "invalid name"),
+            NameError::InvalidRecord => write!(f, "invalid record"),
+            NameError::NameTaken => write!(f, "name already registered"),
+            NameError::UnknownName => write!(f, "unknown name"),
+            NameError::NotOwner => write!(f, "not the owner of the name"),
+        }
+    }
+}
+
+/// What a name points to.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum NameRecord {
+    Address(Address),
+    Text(String),
+}
+
+impl NameRecord {
+    pub(crate) fn push_bytes(&self, bytes: &mut Vec<u8>) {
+        match self {
+            NameRecord::Address(address) => {
+                bytes.push(0);
+                bytes.extend_from_slice(address.as_bytes());
+            }
+            NameRecord::Text(text) => {
+                bytes.push(1);
+                push_field(bytes, text.as_bytes());
+            }
+        }
+    }
+}
+
+/// A registered name.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct NameEntry {
+    pub owner: Address,
+    pub record: NameRecord,
+    /// Height of the first block in which the name is free again
+    pub expires_at: u64,
+}
+
+/// Checks that a name is made of 1 to `MAX_NAME_LEN` lowercase letters, digits and dashes.
+pub fn check_name(name: &str) -> Result<(), NameError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.bytes().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'-');
+    if !valid {
+        return Err(NameError::InvalidName);
+    }
+    Ok(())
+}
+
+fn check_record(record: &NameRecord) -> Result<(), NameError> {
+    match record {
+        NameRecord::Text(text) if text.len() > MAX_TEXT_LEN => Err(NameError::InvalidRecord),
+        _ => Ok(()),
+    }
+}
+
+/// The names registered on the main chain.
+///
+/// Names are first come, first served: a name can only be registered if it is free at the height
+/// of the registration, so the first registration in the order of the chain wins. A name is
+/// registered for `NAME_LIFETIME` blocks, renewed by each update; its owner can also release it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NameRegistry {
+    names: HashMap<String, NameEntry>,
+}
+
+impl NameRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the entry of the name, if it is registered at the given height.
+    pub fn resolve(&self, name: &str, height: u64) -> Option<&NameEntry> {
+        self.names.get(name).filter(|entry| height < entry.expires_at)
+    }
+
+    /// Checks that the sender can apply the name operation of the payload in a block of the given
+    /// height. Other payloads are always accepted.
+    pub fn check(&self, sender: &Address, payload: &Payload, height: u64) -> Result<(), NameError> {
+        let owned = |name: &str| {
+            check_name(name)?;
+            match self.resolve(name, height) {
+                Some(entry) if entry.owner == *sender => Ok(()),
+                Some(_) => Err(NameError::NotOwner),
+                None => Err(NameError::UnknownName),
+            }
+        };
+        match payload {
+            Payload::RegisterName { name, record } => {
+                check_name(name)?;
+                check_record(record)?;
+                if self.resolve(name, height).is_some() {
+                    return Err(NameError::NameTaken);
+                }
+                Ok(())
+            }
+            Payload::UpdateName { name, record } => {
+                check_record(record)?;
+                owned(name)
+            }
+            Payload::TransferName { name, .. } | Payload::ReleaseName { name } => owned(name),
+            _ => Ok(()),
+        }
+    }
+
+    /// Applies the name operation of the payload. Nothing changes if it is refused.
+    pub fn apply(&mut self, sender: &Address, payload: &Payload, height: u64) -> Result<(), NameError> {
+        self.check(sender, payload, height)?;
+        match payload {
+            Payload::RegisterName { name, record } => {
+                let entry = NameEntry { owner: *sender, record: record.clone(), expires_at: height + NAME_LIFETIME };
+                self.names.insert(name.clone(), entry);
+            }
+            Payload::UpdateName { name, record } => {
+                let entry = self.names.get_mut(name).expect("checked above");
+                entry.record = record.clone();
+                entry.expires_at = height + NAME_LIFETIME;
+            }
+            Payload::TransferName { name, new_owner } => {
+                self.names.get_mut(name).expect("checked above").owner = *new_owner;
+            }
+            Payload::ReleaseName { name } => {
+                self.names.remove(name);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::address::Address;
+    use crate::block::Block;
+    use crate::blockchain::{Blockchain, Placement};
+    use crate::client::Client;
+    use crate::names::{check_name, NameError, NameRecord, NameRegistry, NAME_LIFETIME};
+    use crate::signed_transaction::Payload;
+    use crate::transaction::TransactionError;
+
+    fn register(name: &str, address: Address) -> Payload {
+        Payload::RegisterName { name: name.to_string(), record: NameRecord::Address(address) }
+    }
+
+    #[test]
+    fn test_first_registration_wins() {
+        let miner = Client::new().address();
+        let mut alice = Client::new();
+        let mut bob = Client::new();
+        let mut chain = Blockchain::new();
+
+        let first = alice.sign_payload(register("alice", alice.address()), 0);
+        let second = bob.sign_payload(register("alice", bob.address()), 0);
+        chain.add_test_block(vec![first.into()]);
+        assert_eq!(Err(TransactionError::Name(NameError::NameTaken)), chain.check_transaction(&second.clone().into()));
+        assert_eq!(false, chain.add_block_safe(Block::new_rewarded_after_block(second, chain.last_block(), &miner)));
+        assert_eq!(alice.address(), chain.resolve_name("alice").unwrap().owner);
+
+        // Only the owner updates the name, or gives it away
+        let update = Payload::UpdateName { name: "alice".to_string(), record: NameRecord::Text("hello".to_string()) };
+        let tx = bob.sign_payload(update.clone(), 0).into();
+        assert_eq!(Err(TransactionError::Name(NameError::NotOwner)), chain.check_transaction(&tx));
+        chain.add_test_block(vec![alice.sign_payload(update, 0).into()]);
+        let transfer = Payload::TransferName { name: "alice".to_string(), new_owner: bob.address() };
+        chain.add_test_block(vec![alice.sign_payload(transfer, 0).into()]);
+        let entry = chain.resolve_name("alice").unwrap();
+        assert_eq!(bob.address(), entry.owner);
+        assert_eq!(NameRecord::Text("hello".to_string()), entry.record);
+
+        // Once released, the name can be registered again
+        chain.add_test_block(vec![bob.sign_payload(Payload::ReleaseName { name: "alice".to_string() }, 0).into()]);
+        assert_eq!(None, chain.resolve_name("alice"));
+    }
+
+    #[test]
+    fn test_names_expire() {
+        let alice = Client::new().address();
+        let bob = Client::new().address();
+        let mut names = NameRegistry::new();
+        names.apply(&alice, &register("shared", alice), 1).unwrap();
+
+        let expiry = 1 + NAME_LIFETIME;
+        assert_eq!(Err(NameError::NameTaken), names.check(&bob, &register("shared", bob), expiry - 1));
+        assert_eq!(None, names.resolve("shared", expiry));
+        names.apply(&bob, &register("shared", bob), expiry).unwrap();
+        assert_eq!(bob, names.resolve("shared", expiry).unwrap().owner);
+    }
+
+    #[test]
+    fn test_names_follow_reorgs() {
+        let miner = Client::new().address();
+        let mut alice = Client::new();
+        let mut bob = Client::new();
+        let mut chain = Blockchain::new();
+        let genesis = chain.last_block().clone();
+
+        chain.add_test_block(vec![alice.sign_payload(register("name", alice.address()), 0).into()]);
+        assert_eq!(alice.address(), chain.resolve_name("name").unwrap().owner);
+
+        // On a longer fork, bob registered the name first
+        let b1 = Block::new_rewarded_after_block(bob.sign_payload(register("name", bob.address()), 0), &genesis, &miner);
+        let b2 = Block::new_rewarded_after_block(bob.sign("next"), &b1, &miner);
+        assert_eq!(Ok(Placement::Fork), chain.add_block(b1));
+        assert_eq!(Ok(Placement::Fork), chain.add_block(b2));
+        assert_eq!(1, chain.resolve_pending_forks().unwrap().depth());
+        assert_eq!(bob.address(), chain.resolve_name("name").unwrap().owner);
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(Ok(()), check_name("my-name-42"));
+        assert_eq!(Err(NameError::InvalidName), check_name(""));
+        assert_eq!(Err(NameError::InvalidName), check_name("Alice"));
+        assert_eq!(Err(NameError::InvalidName), check_name(&"a".repeat(64)));
+    }
+}
@@ -0,0 +1,255 @@
+use std::error::Error;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use rouille::{router, Request, Response};
+use serde_json::json;
+use repyh::blockchain::Blockchain;
+use repyh::mempool::Mempool;
+use repyh::mining::DIFFICULTY;
+use repyh::transaction::Transaction;
+use crate::http::{error, read_json, MAX_TRANSACTION_BODY_LEN};
+use crate::p2p_network::Peers;
+
+/// Port on which the API of a node listens, if `NODE_API_PORT` is not set.
+pub const DEFAULT_API_PORT: u16 = 8001;
+
+/// What the API of a node reads and updates, shared with its mining loop and its P2P network.
+#[derive(Clone)]
+pub struct NodeState {
+    pub chain: Arc<Mutex<Blockchain>>,
+    /// Transactions submitted to this node, and waiting to be mined
+    pub mempool: Arc<Mutex<Mempool>>,
+    pub peers: Peers,
+    /// Transactions accepted by the API, as JSON, to publish on the P2P network
+    pub tx_local_transactions: tokio::sync::mpsc::Sender<String>,
+}
+
+/// Returns the port on which the API of this node listens.
+///
+/// Several nodes running on the same machine must each use a different `NODE_API_PORT`.
+pub fn api_port() -> Result<u16, Box<dyn Error>> {
+    match std::env::var("NODE_API_PORT") {
+        Ok(port) => Ok(port.parse().map_err(|_| format!("Invalid NODE_API_PORT: {port}"))?),
+        Err(_) => Ok(DEFAULT_API_PORT),
+    }
+}
+
+/// Starts the HTTP API of the node in its own thread.
+///
+/// Supported end-points
+/// * POST /transactions   : adds the transaction given as JSON in the body to the mempool of the
+///                          node, if it is valid on top of its main chain, and publishes it to
+///                          the other nodes. Known transactions are answered with 409, invalid
+///                          ones with 422, and 503 is answered while the P2P network is too busy
+///                          to publish it.
+///
+/// * GET /mempool         : returns the pending transactions, the highest fee rate first.
+///
+/// * GET /blocks/HASH     : returns the block of the main chain with the given hash.
+///
+/// * GET /blocks/height/N : returns the block of the main chain at the given height.
+///
+/// * GET /tip             : returns the last block of the main chain, with its hash and height.
+///
+/// * GET /status          : returns the height and the tip of the main chain, the number of
+///                          pending transactions and of peers.
+///
+/// * GET /peers           : returns the peer ids of the nodes discovered on the network.
+///
+/// The API runs until a message is sent on the returned sender; the thread then finishes the
+/// requests being processed.
+pub fn spawn_api(state: NodeState, port: u16) -> Result<(JoinHandle<()>, mpsc::Sender<()>), Box<dyn Error>> {
+    let web_server = rouille::Server::new(("localhost", port), move |request| handle_request(&state, request))
+        .map_err(|e| e as Box<dyn Error>)?;
+    log::info!("API listening on http://{}", web_server.server_addr());
+    Ok(web_server.stoppable())
+}
+
+fn handle_request(state: &NodeState, request: &Request) -> Response {
+    router!(request,
+        (POST) (/transactions) => {
+            let tx: Transaction = match read_json(request, MAX_TRANSACTION_BODY_LEN) {
+                Ok(tx) => tx,
+                Err(response) => return response,
+            };
+            let id = tx.id();
+            let as_json = serde_json::to_string(&tx).unwrap();
+            let chain = state.chain.lock().unwrap();
+            let mut mempool = state.mempool.lock().unwrap();
+            if mempool.contains(&tx) || chain.has_transaction(&tx) {
+                return error(409, "known_transaction", "the transaction is already pending or written");
+            }
+            // Room is taken in the channel first, so that every accepted transaction is published
+            let Ok(permit) = state.tx_local_transactions.try_reserve() else {
+                return error(503, "network_busy", "the transaction can't be published now, retry later");
+            };
+            match mempool.submit(tx, &chain) {
+                Ok(()) => {
+                    permit.send(as_json);
+                    Response::json(&json!({ "id": id })).with_status_code(202)
+                },
+                Err(e) => {
+                    log::warn!("Refused a transaction: {e}");
+                    error(422, "invalid_transaction", &e.to_string())
+                }
+            }
+        },
+
+        (GET) (/mempool) => {
+            let mempool = state.mempool.lock().unwrap();
+            Response::json(&mempool.best(mempool.len()))
+        },
+
+        (GET) (/blocks/height/{height: u64}) => {
+            match state.chain.lock().unwrap().blocks().get(height as usize) {
+                Some(block) => Response::json(block),
+                None => error(404, "unknown_block", "no block of the main chain matches"),
+            }
+        },
+
+        (GET) (/blocks/{hash: String}) => {
+            match state.chain.lock().unwrap().block_by_hash(&hash) {
+                Some(block) => Response::json(block),
+                None => error(404, "unknown_block", "no block of the main chain matches"),
+            }
+        },
+
+        (GET) (/tip) => {
+            let chain = state.chain.lock().unwrap();
+            let tip = chain.last_block();
+            Response::json(&json!({ "height": tip.index_in_chain(), "hash": tip.hash(), "block": tip }))
+        },
+
+        (GET) (/status) => {
+            let chain = state.chain.lock().unwrap();
+            let status = json!({
+                "height": chain.last_block().index_in_chain(),
+                "tip": chain.last_block().hash(),
+                "difficulty": DIFFICULTY,
+                "mempool": state.mempool.lock().unwrap().len(),
+                "peers": state.peers.lock().unwrap().len(),
+            });
+            Response::json(&status)
+        },
+
+        (GET) (/peers) => {
+            let peers: Vec<String> = state.peers.lock().unwrap().iter().map(ToString::to_string).collect();
+            Response::json(&peers)
+        },
+
+        _ => error(404, "not_found", "unknown route")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use rouille::{Request, Response};
+    use tokio::sync::mpsc::{channel, Receiver};
+    use repyh::block::Block;
+    use repyh::blockchain::Blockchain;
+    use repyh::client::Client;
+    use repyh::mempool::Mempool;
+    use repyh::simple_transaction::SimpleTransaction;
+    use repyh::transaction::Transaction;
+    use crate::http::{status_and_code, status_and_json};
+    use crate::node_api::{handle_request, NodeState};
+    use crate::p2p_network::Peers;
+
+    /// Returns the state of a node on a new chain, and the transactions it publishes, of which at
+    /// most `capacity` can wait.
+    fn node(capacity: usize) -> (NodeState, Receiver<String>) {
+        let (tx_local_transactions, rx_local_transactions) = channel(capacity);
+        let state = NodeState {
+            chain: Arc::new(Mutex::new(Blockchain::new())),
+            mempool: Arc::new(Mutex::new(Mempool::new(10))),
+            peers: Peers::default(),
+            tx_local_transactions,
+        };
+        (state, rx_local_transactions)
+    }
+
+    fn post_transaction(state: &NodeState, tx: &Transaction) -> Response {
+        let headers = vec![("Content-Type".to_string(), "application/json".to_string())];
+        let body = serde_json::to_vec(tx).unwrap();
+        handle_request(state, &Request::fake_http("POST", "/transactions", headers, body))
+    }
+
+    fn get(state: &NodeState, url: &str) -> Response {
+        handle_request(state, &Request::fake_http("GET", url, vec![], vec![]))
+    }
+
+    #[test]
+    fn test_accepted_transaction_is_published() {
+        let (state, mut published) = node(1);
+        let tx: Transaction = SimpleTransaction::from_str("hello").into();
+
+        let (status, body) = status_and_json(post_transaction(&state, &tx));
+        assert_eq!((202, tx.id()), (status, body["id"].as_str().unwrap().to_string()));
+        assert_eq!(Ok(serde_json::to_string(&tx).unwrap()), published.try_recv());
+        assert_eq!(true, state.mempool.lock().unwrap().contains(&tx));
+
+        let response = post_transaction(&state, &tx);
+        assert_eq!((409, "known_transaction".to_string()), status_and_code(response));
+        assert_eq!(true, published.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_invalid_transaction_is_answered_with_422() {
+        let (state, mut published) = node(1);
+        let tx: Transaction = Client::new().transfer(Client::new().address(), 5).into();
+
+        let response = post_transaction(&state, &tx);
+        assert_eq!((422, "invalid_transaction".to_string()), status_and_code(response));
+        assert_eq!(true, published.try_recv().is_err());
+        assert_eq!(0, state.mempool.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_transaction_is_refused_while_it_cant_be_published() {
+        let (state, _published) = node(1);
+        let first: Transaction = SimpleTransaction::from_str("first").into();
+        let second: Transaction = SimpleTransaction::from_str("second").into();
+        assert_eq!(202, post_transaction(&state, &first).status_code);
+
+        let response = post_transaction(&state, &second);
+        assert_eq!((503, "network_busy".to_string()), status_and_code(response));
+        assert_eq!(false, state.mempool.lock().unwrap().contains(&second));
+    }
+
+    #[test]
+    fn test_blocks_of_the_main_chain() {
+        let (state, _published) = node(1);
+        let genesis = serde_json::to_value(Block::genesis()).unwrap();
+
+        assert_eq!((200, genesis.clone()), status_and_json(get(&state, "/blocks/height/0")));
+        assert_eq!((404, "unknown_block".to_string()), status_and_code(get(&state, "/blocks/height/1")));
+        let url = format!("/blocks/{}", Block::genesis().hash());
+        assert_eq!((200, genesis), status_and_json(get(&state, &url)));
+        assert_eq!((404, "unknown_block".to_string()), status_and_code(get(&state, "/blocks/unknown")));
+    }
+
+    #[test]
+    fn test_tip_and_status() {
+        let (state, _published) = node(1);
+        state.mempool.lock().unwrap().submit(SimpleTransaction::from_str("pending").into(), &Blockchain::new()).unwrap();
+
+        let (status, tip) = status_and_json(get(&state, "/tip"));
+        assert_eq!(200, status);
+        assert_eq!(0, tip["height"]);
+        assert_eq!(Block::genesis().hash(), tip["hash"]);
+
+        let (status, node_status) = status_and_json(get(&state, "/status"));
+        assert_eq!(200, status);
+        assert_eq!(0, node_status["height"]);
+        assert_eq!(Block::genesis().hash(), node_status["tip"]);
+        assert_eq!(1, node_status["mempool"]);
+        assert_eq!(0, node_status["peers"]);
+    }
+
+    #[test]
+    fn test_unknown_route_is_answered_with_404() {
+        let (state, _published) = node(1);
+        assert_eq!((404, "not_found".to_string()), status_and_code(get(&state, "/nothing")));
+    }
+}
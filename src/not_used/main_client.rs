@@ -1,13 +1,22 @@
 use std::error::Error;
+use repyh::simple_transaction::SimpleTransaction;
+use repyh::transaction::Transaction;
 
 /// A client to submit a transaction to the server
 fn main() -> Result<(), Box<dyn Error>> {
-    
+
     if let Some(text) = std::env::args().nth(1) {
         println!("Sending to server: {text}");
-        let resp = reqwest::blocking::get(format!("http://localhost:8000/submit_transaction/{}", text))?.text()?;
+        // A random nonce lets the same text be written again
+        let tx = Transaction::from(SimpleTransaction::with_nonce(&text, rand::random()));
+        let resp = reqwest::blocking::Client::new()
+            .post("http://localhost:8000/transactions")
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_string(&tx)?)
+            .send()?
+            .text()?;
         println!("Received: {:#?}", resp);
     }
-    
+
     Ok(())
-}
\ No newline at end of file
+}
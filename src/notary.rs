@@ -0,0 +1,173 @@
+use std::fmt::{Display, Formatter};
+use serde::{Deserialize, Serialize};
+use crate::block::{Block, BlockHeader};
+use crate::merkle::{verify_merkle_path, MerkleStep};
+use crate::signed_transaction::Payload;
+use crate::transaction::{Transaction, TransactionError};
+
+/// Maximum length, in bytes, of the metadata of a notarization.
+pub const MAX_METADATA_LEN: usize = 256;
+
+/// Checks that a notarization holds a SHA-256 digest in lowercase hex, and short metadata.
+pub fn check_notarization(digest: &str, metadata: &str) -> Result<(), TransactionError> {
+    let is_digest = digest.len() == 64 && digest.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'));
+    if !is_digest || metadata.len() > MAX_METADATA_LEN {
+        return Err(TransactionError::Malformed);
+    }
+    Ok(())
+}
+
+/// Returns the digest anchored by a transaction, if it is a notarization.
+pub fn notarized_digest(tx: &Transaction) -> Option<&str> {
+    match tx {
+        Transaction::Signed(signed) => match signed.payload() {
+            Payload::Notarize { digest, .. } => Some(digest),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Reasons for a proof of existence to be refused.
+#[derive(Debug, PartialEq)]
+pub enum ProofError {
+    /// The transaction of the proof does not notarize the document
+    NotANotarization,
+    /// The headers do not start at the genesis, are not linked, or lack the proof of work
+    InvalidHeaderChain,
+    /// The block of the proof is not in the header chain
+    UnknownBlock,
+    /// The Merkle path does not lead from the transaction to the root of the block
+    InvalidMerklePath,
+}
+
+impl std::error::Error for ProofError {}
+
+impl Display for ProofError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProofError::NotANotarization => write!(f, "The transaction does not notarize the document"),
+            ProofError::InvalidHeaderChain => write!(f, "Invalid header chain"),
+            ProofError::UnknownBlock => write!(f, "The block is not in the header chain"),
+            ProofError::InvalidMerklePath => write!(f, "Invalid Merkle path"),
+        }
+    }
+}
+
+/// A proof that a document was notarized in a block of the main chain.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct ExistenceProof {
+    /// The notarization transaction of the document
+    pub transaction: Transaction,
+    pub block_hash: String,
+    pub height: u64,
+    /// Number of blocks from the block of the notarization to the head, both included
+    pub confirmations: u64,
+    /// Path from the bytes of the transaction to the Merkle root of the block
+    pub path: Vec<MerkleStep>,
+}
+
+impl ExistenceProof {
+    /// Returns the digest of the notarized document.
+    pub fn digest(&self) -> Option<&str> {
+        notarized_digest(&self.transaction)
+    }
+
+    /// Checks the proof against a chain of headers starting at the genesis, without trusting the
+    /// node that sent the proof or the headers. Every header must hold a proof of work of the
+    /// given difficulty.
+    ///
+    /// Returns the number of confirmations of the notarization in this header chain.
+    pub fn verify(&self, digest: &str, headers: &[BlockHeader], difficulty: usize) -> Result<u64, ProofError> {
+        if self.digest() != Some(digest) || self.transaction.verify().is_err() {
+            return Err(ProofError::NotANotarization);
+        }
+        check_header_chain(headers, difficulty)?;
+        let height = headers
+            .iter()
+            .position(|header| header.hash() == self.block_hash)
+            .ok_or(ProofError::UnknownBlock)?;
+        if !verify_merkle_path(&self.transaction.to_bytes(), &self.path, &headers[height].merkle_root) {
+            return Err(ProofError::InvalidMerklePath);
+        }
+        Ok((headers.len() - height) as u64)
+    }
+}
+
+/// Checks that the headers start at the genesis, that each of them is linked to the previous one,
+/// and that each block after the genesis holds a proof of work.
+pub fn check_header_chain(headers: &[BlockHeader], difficulty: usize) -> Result<(), ProofError> {
+    if headers.first() != Some(&Block::genesis().header()) {
+        return Err(ProofError::InvalidHeaderChain);
+    }
+    for pair in headers.windows(2) {
+        if pair[1].previous_hash.as_ref() != Some(&pair[0].hash()) || !pair[1].is_hash_valid(difficulty) {
+            return Err(ProofError::InvalidHeaderChain);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sha256::digest;
+    use crate::blockchain::Blockchain;
+    use crate::client::Client;
+    use crate::mining::mine_sync;
+    use crate::notary::ProofError;
+    use crate::signed_transaction::Payload;
+    use crate::transaction::{Transaction, TransactionError};
+
+    const DIFFICULTY: usize = 1;
+
+    fn notarize(client: &mut Client, document: &str) -> Transaction {
+        let payload = Payload::Notarize { digest: digest(document), metadata: "contract.pdf".to_string() };
+        client.sign_payload(payload, 0).into()
+    }
+
+    /// Mines a block holding the transactions on top of the chain.
+    fn mine(chain: &mut Blockchain, transactions: Vec<Transaction>) {
+        let mut block = chain.get_rewarded_candidate_block(transactions, &Client::new().address());
+        mine_sync(&mut block, DIFFICULTY);
+        assert_eq!(true, chain.add_block_safe(block));
+    }
+
+    #[test]
+    fn test_proof_of_existence() {
+        let mut alice = Client::new();
+        let mut chain = Blockchain::new();
+        let document = "All that is gold does not glitter";
+        let others = (0..4).map(|i| alice.sign(&format!("message {i}")).into()).collect();
+        mine(&mut chain, others);
+        mine(&mut chain, vec![alice.sign("before").into(), notarize(&mut alice, document), alice.sign("after").into()]);
+        mine(&mut chain, vec![alice.sign("next").into()]);
+
+        let proof = chain.prove_document(&digest(document)).unwrap();
+        assert_eq!(2, proof.height);
+        assert_eq!(2, proof.confirmations);
+
+        // The proof is checked with the headers alone, and gains confirmations as the chain grows
+        let headers = chain.headers();
+        assert_eq!(Ok(2), proof.verify(&digest(document), &headers, DIFFICULTY));
+        mine(&mut chain, vec![alice.sign("later").into()]);
+        assert_eq!(Ok(3), proof.verify(&digest(document), &chain.headers(), DIFFICULTY));
+
+        // It proves nothing about another document, or on a header chain without proof of work
+        assert_eq!(Err(ProofError::NotANotarization), proof.verify(&digest("forged"), &headers, DIFFICULTY));
+        assert_eq!(Err(ProofError::InvalidHeaderChain), proof.verify(&digest(document), &headers, 64));
+        let mut tampered = proof.clone();
+        tampered.path.pop();
+        assert_eq!(Err(ProofError::InvalidMerklePath), tampered.verify(&digest(document), &headers, DIFFICULTY));
+        assert_eq!(Err(ProofError::UnknownBlock), proof.verify(&digest(document), &headers[..2], DIFFICULTY));
+    }
+
+    #[test]
+    fn test_invalid_notarizations_are_refused() {
+        let mut alice = Client::new();
+        let chain = Blockchain::new();
+        let tx = alice.sign_payload(Payload::Notarize { digest: "not a digest".to_string(), metadata: String::new() }, 0);
+        assert_eq!(Err(TransactionError::Malformed), chain.check_transaction(&tx.into()));
+        assert!(chain.prove_document(&digest("unknown")).is_none());
+    }
+}
@@ -1,14 +1,20 @@
 // Taken from https://github.com/libp2p/rust-libp2p/blob/master/examples/chat/src/main.rs
 
 use futures::stream::StreamExt;
-use libp2p::{gossipsub, mdns, noise, swarm::NetworkBehaviour, swarm::SwarmEvent, tcp, yamux, Swarm};
+use libp2p::{gossipsub, identity, mdns, noise, swarm::NetworkBehaviour, swarm::SwarmEvent, tcp, yamux, PeerId, Swarm};
 use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
 use std::error::Error;
+use std::fs;
+use std::io::Write;
 use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::{io, io::AsyncBufReadExt, select};
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::{io, select};
+use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 use tracing_subscriber::EnvFilter;
 
 // We create a custom network behaviour that combines Gossipsub and Mdns.
@@ -18,21 +24,99 @@ struct P2PBlockSharingBehavior {
     mdns: mdns::tokio::Behaviour,
 }
 
+/// Capacity of the channels between the swarm and the rest of the node.
+///
+/// When the worker does not keep up with the blocks received from the network, the swarm waits
+/// for room in the channel, so that no block is lost, unless the node shuts down. Local blocks
+/// wait for room as well.
+pub const CHANNEL_CAPACITY: usize = 64;
+
+/// Period at which the depth of the channels is logged.
+const METRICS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Name of the file, inside the data directory of a node, in which its identity is stored.
+const IDENTITY_FILE: &str = "identity.key";
+
+/// Loads the ed25519 identity of this node from its data directory.
+///
+/// The first time a node starts, a new keypair is generated and written to the data directory, so
+/// that the node keeps the same `PeerId` across restarts.
+pub fn load_or_create_identity(data_dir: &Path) -> Result<identity::Keypair, Box<dyn Error>> {
+    let path = data_dir.join(IDENTITY_FILE);
+    if path.exists() {
+        let bytes = fs::read(&path)?;
+        let keypair = identity::Keypair::from_protobuf_encoding(&bytes)?;
+        return Ok(keypair);
+    }
+
+    let keypair = identity::Keypair::generate_ed25519();
+    fs::create_dir_all(data_dir)?;
+
+    // The key file is a secret: only the owner of the node should be able to read it, from the
+    // moment it is created.
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&path)?.write_all(&keypair.to_protobuf_encoding()?)?;
+
+    log::info!("Generated a new identity in {}", path.display());
+    Ok(keypair)
+}
+
+/// Peers discovered on the local network, and that did not leave it.
+pub type Peers = Arc<Mutex<BTreeSet<PeerId>>>;
+
+/// Channels between the swarm and the rest of the node, carrying blocks and transactions as JSON.
+pub struct SwarmChannels {
+    /// Blocks mined locally, to publish on the network
+    pub rx_local_blocks: Receiver<String>,
+    /// Blocks received from the network, for the worker
+    pub tx_network_blocks: Sender<String>,
+    /// Transactions accepted by the API of this node, to publish on the network
+    pub rx_local_transactions: Receiver<String>,
+    /// Transactions received from the network, for the mempool of this node
+    pub tx_network_transactions: Sender<String>,
+}
+
+/// Gossipsub topics the node subscribes to.
+struct Topics {
+    blocks: gossipsub::IdentTopic,
+    transactions: gossipsub::IdentTopic,
+    /// Nodes that leave the network publish their peer id on this topic
+    departures: gossipsub::IdentTopic,
+}
+
 /// Joins the P2P local network
+///
+/// The returned task runs until the `shutdown` token is cancelled, after which it announces to the
+/// peers that this node leaves and closes its connections. `peers` is kept up to date meanwhile.
 pub fn join_p2p_network(
-    rx_local_blocks: UnboundedReceiver<String>,
-    tx_network_blocks: UnboundedSender<String>
+    keypair: identity::Keypair,
+    channels: SwarmChannels,
+    peers: Peers,
+    shutdown: CancellationToken,
 ) -> Result<JoinHandle<()>, Box<dyn Error>> 
 {
     let _ = tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env())
         .try_init();
 
-    let mut swarm = build_libp2p_swarm()?;
+    let mut swarm = build_libp2p_swarm(keypair)?;
+    log::info!("Local peer id: {}", swarm.local_peer_id());
 
-    // Create a Gossipsub topic
-    let topic = gossipsub::IdentTopic::new("blockchain-net");
-    swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+    // Create the Gossipsub topics
+    let topics = Topics {
+        blocks: gossipsub::IdentTopic::new("blockchain-net"),
+        transactions: gossipsub::IdentTopic::new("blockchain-net-transactions"),
+        departures: gossipsub::IdentTopic::new("blockchain-net-departures"),
+    };
+    for topic in [&topics.blocks, &topics.transactions, &topics.departures] {
+        swarm.behaviour_mut().gossipsub.subscribe(topic)?;
+    }
 
     // Listen on all interfaces and whatever port the OS assigns
     swarm.listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse()?)?;
@@ -40,33 +124,98 @@ pub fn join_p2p_network(
 
     // Spawn a new thread of this the P2P network
     let future = tokio::spawn(async move {
-        handle_swarm(swarm, topic, rx_local_blocks, tx_network_blocks).await;
+        handle_swarm(swarm, topics, channels, peers, shutdown).await;
     });
 
-    Ok((future))
+    Ok(future)
+}
+
+/// Counters about the messages going through the swarm, to monitor the channels with the worker.
+#[derive(Default)]
+struct QueueMetrics {
+    /// Blocks mined locally and published on the network
+    published: u64,
+    /// Blocks received from the network and forwarded to the worker
+    forwarded: u64,
+    /// Transactions accepted locally and published on the network
+    published_transactions: u64,
+    /// Transactions received from the network and forwarded to the mempool
+    forwarded_transactions: u64,
+    /// Maximum number of local blocks that were waiting to be published
+    max_outgoing_depth: usize,
+    /// Maximum number of network blocks that were waiting to be processed by the worker
+    max_incoming_depth: usize,
 }
 
+impl QueueMetrics {
+    fn record_depths(&mut self, outgoing: usize, incoming: usize) {
+        self.max_outgoing_depth = self.max_outgoing_depth.max(outgoing);
+        self.max_incoming_depth = self.max_incoming_depth.max(incoming);
+    }
+
+    fn log(&self, outgoing: usize, incoming: usize) {
+        log::info!(
+            "Queues: outgoing {outgoing} (max {}), incoming {incoming} (max {}), published {}, forwarded {}, \
+             transactions published {}, transactions forwarded {}",
+            self.max_outgoing_depth, self.max_incoming_depth, self.published, self.forwarded,
+            self.published_transactions, self.forwarded_transactions
+        );
+    }
+}
+
+/// Event loop of the swarm.
+///
+/// It reacts as soon as an event arrives from the network or a block or a transaction has to be
+/// published, and never waits for anything else.
 async fn handle_swarm(
     mut swarm: Swarm<P2PBlockSharingBehavior>,
-    topic: gossipsub::IdentTopic,
-    mut rx_local_blocks: UnboundedReceiver<String>,
-    mut tx_network_blocks: UnboundedSender<String>
+    topics: Topics,
+    channels: SwarmChannels,
+    peers: Peers,
+    shutdown: CancellationToken,
 ) 
 {
+    let SwarmChannels {
+        mut rx_local_blocks,
+        tx_network_blocks,
+        mut rx_local_transactions,
+        tx_network_transactions,
+    } = channels;
     log::info!("Joining swarm ...");
 
-    // Read full lines from stdin
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
+    let mut metrics = QueueMetrics::default();
+    let mut metrics_interval = tokio::time::interval(METRICS_INTERVAL);
 
     // Kick it off
     loop {
-        tokio::time::sleep(Duration::from_secs(1)).await;
+        let outgoing_depth = rx_local_blocks.len();
+        let incoming_depth = tx_network_blocks.max_capacity() - tx_network_blocks.capacity();
+        metrics.record_depths(outgoing_depth, incoming_depth);
+
         select! {
+            _ = shutdown.cancelled() => {
+                leave_swarm(&mut swarm, &topics.departures).await;
+                return;
+            }
+            _ = metrics_interval.tick() => {
+                metrics.log(outgoing_depth, incoming_depth);
+            }
             Some(msg) = rx_local_blocks.recv() => {
                 if let Err(e) = swarm
                     .behaviour_mut().gossipsub
-                    .publish(topic.clone(), msg.as_bytes()) {
+                    .publish(topics.blocks.clone(), msg.as_bytes()) {
                     println!("Publish error: {e:?}");
+                } else {
+                    metrics.published += 1;
+                }
+            }
+            Some(msg) = rx_local_transactions.recv() => {
+                if let Err(e) = swarm
+                    .behaviour_mut().gossipsub
+                    .publish(topics.transactions.clone(), msg.as_bytes()) {
+                    log::warn!("Could not publish a transaction: {e:?}");
+                } else {
+                    metrics.published_transactions += 1;
                 }
             }
             event = swarm.select_next_some() => match event {
@@ -74,22 +223,54 @@ async fn handle_swarm(
                     for (peer_id, _multiaddr) in list {
                         println!("mDNS discovered a new peer: {peer_id}");
                         swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+                        peers.lock().unwrap().insert(peer_id);
                     }
                 },
                 SwarmEvent::Behaviour(P2PBlockSharingBehaviorEvent::Mdns(mdns::Event::Expired(list))) => {
                     for (peer_id, _multiaddr) in list {
                         println!("mDNS discover peer has expired: {peer_id}");
                         swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                        peers.lock().unwrap().remove(&peer_id);
                     }
                 },
                 SwarmEvent::Behaviour(P2PBlockSharingBehaviorEvent::Gossipsub(gossipsub::Event::Message {
-                    propagation_source: peer_id,
-                    message_id: id,
-                    message})) => {
+                    message, ..
+                })) => {
                     let msg = String::from_utf8_lossy(&message.data).to_string();
-                    // println!("Got message: '{msg}' with id: {id} from peer: {peer_id}");
+                    if message.topic == topics.departures.hash() {
+                        log::info!("Peer {msg} left the network");
+                        if let Ok(peer_id) = msg.parse::<PeerId>() {
+                            swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
+                            peers.lock().unwrap().remove(&peer_id);
+                        }
+                        continue;
+                    }
+                    if message.topic == topics.transactions.hash() {
+                        select! {
+                            sent = tx_network_transactions.send(msg) => match sent {
+                                Ok(()) => metrics.forwarded_transactions += 1,
+                                Err(_) => log::warn!("Transaction received while the node is shutting down"),
+                            },
+                            _ = shutdown.cancelled() => {
+                                leave_swarm(&mut swarm, &topics.departures).await;
+                                return;
+                            }
+                        }
+                        continue;
+                    }
                     // Communicate to the working thread that another node is proposing a node...
-                    tx_network_blocks.send(msg).unwrap();
+                    // A slow worker slows the swarm down rather than losing blocks, but the node
+                    // can still be stopped meanwhile
+                    select! {
+                        sent = tx_network_blocks.send(msg) => match sent {
+                            Ok(()) => metrics.forwarded += 1,
+                            Err(_) => log::warn!("Block received while the node is shutting down"),
+                        },
+                        _ = shutdown.cancelled() => {
+                            leave_swarm(&mut swarm, &topics.departures).await;
+                            return;
+                        }
+                    }
                 }
                 SwarmEvent::NewListenAddr { address, .. } => {
                     println!("Local node is listening on {address}");
@@ -101,9 +282,46 @@ async fn handle_swarm(
 
 }
 
-/// Returns the configured and build swarm
-fn build_libp2p_swarm() -> Result<Swarm<P2PBlockSharingBehavior>, Box<dyn Error>> {
-    let swarm = libp2p::SwarmBuilder::with_new_identity()
+/// Announces to the peers that this node leaves, and closes all the connections.
+async fn leave_swarm(swarm: &mut Swarm<P2PBlockSharingBehavior>, departures: &gossipsub::IdentTopic) {
+    log::info!("Leaving swarm ...");
+    let local_peer_id = swarm.local_peer_id().to_string();
+    if let Err(e) = swarm
+        .behaviour_mut().gossipsub
+        .publish(departures.clone(), local_peer_id.as_bytes()) {
+        log::warn!("Could not announce our departure: {e:?}");
+    }
+
+    // Messages are only sent while the swarm is polled, so give it some time to flush.
+    let flush = tokio::time::sleep(Duration::from_millis(500));
+    tokio::pin!(flush);
+    loop {
+        select! {
+            _ = &mut flush => break,
+            _ = swarm.select_next_some() => {}
+        }
+    }
+
+    let peers: Vec<PeerId> = swarm.connected_peers().copied().collect();
+    for peer_id in peers {
+        let _ = swarm.disconnect_peer_id(peer_id);
+    }
+
+    // Wait for the connections to be closed, but don't hang if a peer does not answer.
+    let timeout = tokio::time::sleep(Duration::from_secs(2));
+    tokio::pin!(timeout);
+    while swarm.connected_peers().next().is_some() {
+        select! {
+            _ = &mut timeout => break,
+            _ = swarm.select_next_some() => {}
+        }
+    }
+    log::info!("Swarm closed.");
+}
+
+/// Returns the configured and build swarm, using the given identity
+fn build_libp2p_swarm(keypair: identity::Keypair) -> Result<Swarm<P2PBlockSharingBehavior>, Box<dyn Error>> {
+    let swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
         .with_tokio()
         .with_tcp(
             tcp::Config::default(),
@@ -0,0 +1,137 @@
+use crate::simulation::{NodeId, Simulation, SimulationConfig};
+
+/// A network partition to replay in a simulation.
+///
+/// The network first runs connected, is then split in groups that mine independently, and is
+/// finally healed. Nodes must then agree on a single chain.
+#[derive(Clone, Debug)]
+pub struct PartitionScenario {
+    pub config: SimulationConfig,
+    /// Number of ticks during which the network runs before being split
+    pub warmup_ticks: u64,
+    /// Groups of nodes which can only communicate among themselves during the partition
+    pub groups: Vec<Vec<NodeId>>,
+    /// Number of ticks during which the network stays split
+    pub partition_ticks: u64,
+    /// Maximum number of blocks that can be mined after the heal before all nodes must agree
+    pub max_blocks_to_converge: u64,
+}
+
+/// What happened after the partition was healed.
+#[derive(Debug)]
+pub struct ScenarioReport {
+    /// True if all the nodes agreed on the same head within `max_blocks_to_converge` blocks
+    pub converged: bool,
+    /// True if the nodes agreed on the chain which was the heaviest when the partition was healed.
+    /// When several groups had chains of the same length, any of them is accepted.
+    pub converged_on_heaviest: bool,
+    /// Number of blocks that had to be mined after the heal for all nodes to agree
+    pub blocks_to_converge: u64,
+    /// Length of the main chain of the first node at the end of the scenario
+    pub final_length: usize,
+    /// For every node, the depth of each of its reorganizations after the heal
+    pub reorg_depths: Vec<Vec<usize>>,
+}
+
+impl ScenarioReport {
+    /// Returns the depth of the deepest reorganization after the heal.
+    pub fn max_reorg_depth(&self) -> usize {
+        self.reorg_depths.iter().flatten().copied().max().unwrap_or(0)
+    }
+}
+
+impl PartitionScenario {
+    /// Replays the scenario and reports how the nodes converged.
+    pub fn run(&self) -> ScenarioReport {
+        let mut simulation = Simulation::new(self.config.clone());
+        simulation.run(self.warmup_ticks);
+
+        // Let each group mine on its own
+        simulation.partition(&self.groups);
+        simulation.run(self.partition_ticks);
+        simulation.drain();
+
+        // The heads that the nodes should converge to: all those with the longest chain.
+        let heaviest_length = simulation.nodes().iter().map(|node| node.chain().len()).max().unwrap_or(0);
+        let heaviest_tips: Vec<String> = simulation.nodes()
+            .iter()
+            .filter(|node| node.chain().len() == heaviest_length)
+            .map(|node| node.chain().last_block().hash())
+            .collect();
+        let reorgs_before_heal: Vec<usize> = simulation.nodes()
+            .iter()
+            .map(|node| node.reorg_depths().len())
+            .collect();
+
+        simulation.heal();
+        simulation.drain();
+
+        // Chains of the same length may remain: nodes take turns to mine until one is longer.
+        let mut blocks_to_converge = 0;
+        while !simulation.has_converged() && blocks_to_converge < self.max_blocks_to_converge {
+            let miner = blocks_to_converge as usize % simulation.nodes().len();
+            simulation.mine_on(miner);
+            simulation.drain();
+            blocks_to_converge += 1;
+        }
+
+        let first_chain = simulation.nodes()[0].chain();
+        let converged_on_heaviest = first_chain.blocks()
+            .iter()
+            .any(|block| heaviest_tips.contains(&block.hash()));
+
+        ScenarioReport {
+            converged: simulation.has_converged(),
+            converged_on_heaviest,
+            blocks_to_converge,
+            final_length: first_chain.len(),
+            reorg_depths: simulation.nodes()
+                .iter()
+                .zip(reorgs_before_heal)
+                .map(|(node, before)| node.reorg_depths()[before..].to_vec())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scenario::PartitionScenario;
+    use crate::simulation::SimulationConfig;
+
+    fn scenario(seed: u64, groups: Vec<Vec<usize>>) -> PartitionScenario {
+        PartitionScenario {
+            config: SimulationConfig { nodes: 4, seed, ..Default::default() },
+            warmup_ticks: 100,
+            groups,
+            partition_ticks: 300,
+            max_blocks_to_converge: 5,
+        }
+    }
+
+    #[test]
+    fn test_two_groups_converge_after_heal() {
+        let report = scenario(1, vec![vec![0, 1], vec![2, 3]]).run();
+        assert!(report.converged);
+        assert!(report.converged_on_heaviest);
+
+        // At least one of the groups had to abandon the blocks it mined during the partition
+        assert!(report.max_reorg_depth() > 0);
+    }
+
+    #[test]
+    fn test_isolated_node_joins_the_majority() {
+        let report = scenario(2, vec![vec![0, 1, 2], vec![3]]).run();
+        assert!(report.converged);
+        assert!(report.converged_on_heaviest);
+        assert!(report.reorg_depths[3].iter().sum::<usize>() > 0);
+    }
+
+    #[test]
+    fn test_three_groups_converge_after_heal() {
+        let report = scenario(3, vec![vec![0], vec![1], vec![2, 3]]).run();
+        assert!(report.converged);
+        assert!(report.converged_on_heaviest);
+        assert!(report.blocks_to_converge <= 5);
+    }
+}
@@ -0,0 +1,457 @@
+use std::fmt::{Display, Formatter};
+use ed25519_dalek::VerifyingKey;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::address::Address;
+use crate::signed_transaction::verify_signature;
+use crate::transaction::push_field;
+
+/// Maximum number of operations of a script.
+pub const MAX_SCRIPT_OPS: usize = 201;
+
+/// Maximum length of an element of the stack.
+pub const MAX_ELEMENT_LEN: usize = 520;
+
+/// Maximum number of elements on the stack.
+pub const MAX_STACK_SIZE: usize = 100;
+
+/// Maximum number of signatures checked while evaluating a spend.
+pub const MAX_SIG_CHECKS: usize = 20;
+
+/// Domain of the hash of a script, so that the address of a script can't be the address of a key.
+const SCRIPT_HASH_TAG: &[u8] = b"script";
+
+/// An operation of a script.
+///
+/// There are no loops nor jumps: every operation runs at most once, so the cost of a script is
+/// bounded by its length.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum Op {
+    /// Pushes bytes on the stack
+    Push(Vec<u8>),
+    /// Pushes a number, as 8 bytes in big endian
+    Number(u64),
+    Dup,
+    Drop,
+    Swap,
+    /// Replaces the two top elements by true if they are equal, false otherwise
+    Equal,
+    /// Fails unless the two top elements are equal, and removes them
+    EqualVerify,
+    /// Replaces the top element by its SHA-256 hash
+    Sha256,
+    /// Pops a public key, then a signature, and pushes true if the signature of the transaction
+    /// by this key is valid
+    CheckSig,
+    /// Same as `CheckSig`, but fails instead of pushing false
+    CheckSigVerify,
+    /// Pops a height, and fails if the transaction is written in an earlier block
+    CheckHeightVerify,
+    /// Pops a timestamp, and fails if the transaction is written in an earlier block
+    CheckTimeVerify,
+    Not,
+    BoolAnd,
+    BoolOr,
+    /// Fails unless the top element is true, and removes it
+    Verify,
+    /// Pops a condition, and only runs the following operations if it is true
+    If,
+    /// Runs the following operations only if the condition of the `If` was false
+    Else,
+    EndIf,
+    /// Fails immediately
+    Fail,
+}
+
+impl Op {
+    fn is_push(&self) -> bool {
+        matches!(self, Op::Push(_) | Op::Number(_))
+    }
+}
+
+/// A program deciding whether an output can be spent.
+///
+/// An output locked by a script is paid to the address of its locking script. To spend it, the
+/// input reveals the locking script, with an unlocking script pushing its arguments, such as
+/// signatures or secrets. The unlocking script runs first, then the locking script on the same
+/// stack: the spend is valid if the top of the stack is true at the end.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct Script(Vec<Op>);
+
+impl Script {
+    pub fn new(ops: Vec<Op>) -> Self {
+        Self(ops)
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.0
+    }
+
+    /// Returns a hash-time-locked contract: `receiver` can spend the output by revealing the
+    /// preimage of `hash`, and `sender` can take it back from the block `timeout` on.
+    ///
+    /// The receiver unlocks it with `[signature, preimage, true]`, the sender with
+    /// `[signature, false]`.
+    pub fn hash_time_lock(receiver: &VerifyingKey, hash: [u8; 32], sender: &VerifyingKey, timeout: u64) -> Self {
+        Self(vec![
+            Op::If,
+            Op::Sha256,
+            Op::Push(hash.to_vec()),
+            Op::EqualVerify,
+            Op::Push(receiver.to_bytes().to_vec()),
+            Op::Else,
+            Op::Number(timeout),
+            Op::CheckHeightVerify,
+            Op::Push(sender.to_bytes().to_vec()),
+            Op::EndIf,
+            Op::CheckSig,
+        ])
+    }
+
+    /// Returns the canonical encoding of the script.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(self.0.len() as u32).to_be_bytes());
+        for op in &self.0 {
+            match op {
+                Op::Push(data) => {
+                    bytes.push(0);
+                    push_field(&mut bytes, data);
+                }
+                Op::Number(number) => {
+                    bytes.push(1);
+                    bytes.extend_from_slice(&number.to_be_bytes());
+                }
+                Op::Dup => bytes.push(2),
+                Op::Drop => bytes.push(3),
+                Op::Swap => bytes.push(4),
+                Op::Equal => bytes.push(5),
+                Op::EqualVerify => bytes.push(6),
+                Op::Sha256 => bytes.push(7),
+                Op::CheckSig => bytes.push(8),
+                Op::CheckSigVerify => bytes.push(9),
+                Op::CheckHeightVerify => bytes.push(10),
+                Op::CheckTimeVerify => bytes.push(11),
+                Op::Not => bytes.push(12),
+                Op::BoolAnd => bytes.push(13),
+                Op::BoolOr => bytes.push(14),
+                Op::Verify => bytes.push(15),
+                Op::If => bytes.push(16),
+                Op::Else => bytes.push(17),
+                Op::EndIf => bytes.push(18),
+                Op::Fail => bytes.push(19),
+            }
+        }
+        bytes
+    }
+
+    /// Returns the address to which outputs locked by this script are paid.
+    pub fn address(&self) -> Address {
+        Address::from_hash_of(&[SCRIPT_HASH_TAG, &self.to_bytes()].concat())
+    }
+
+    /// Checks the limits that do not depend on the evaluation.
+    pub fn check(&self) -> Result<(), ScriptError> {
+        if self.0.len() > MAX_SCRIPT_OPS {
+            return Err(ScriptError::TooLong);
+        }
+        if self.0.iter().any(|op| matches!(op, Op::Push(data) if data.len() > MAX_ELEMENT_LEN)) {
+            return Err(ScriptError::ElementTooLarge);
+        }
+        Ok(())
+    }
+}
+
+/// What a script can see of the transaction being validated.
+pub struct ScriptContext<'a> {
+    /// What the signatures sign
+    pub signed_bytes: &'a [u8],
+    /// Height of the block in which the transaction is written
+    pub height: u64,
+    /// Timestamp of the block in which the transaction is written
+    pub timestamp: u64,
+}
+
+/// Runs the unlocking script, then the locking script, and succeeds if they leave true on the top
+/// of the stack. The unlocking script can only push data.
+pub fn verify_script(unlocking: &Script, locking: &Script, context: &ScriptContext) -> Result<(), ScriptError> {
+    unlocking.check()?;
+    locking.check()?;
+    if !unlocking.ops().iter().all(Op::is_push) {
+        return Err(ScriptError::NotPushOnly);
+    }
+
+    let mut machine = Machine { stack: Vec::new(), sig_checks: 0, context };
+    machine.run(unlocking)?;
+    machine.run(locking)?;
+    match machine.stack.last() {
+        Some(top) if is_true(top) => Ok(()),
+        _ => Err(ScriptError::Failed),
+    }
+}
+
+fn is_true(element: &[u8]) -> bool {
+    element.iter().any(|byte| *byte != 0)
+}
+
+fn from_bool(value: bool) -> Vec<u8> {
+    if value { vec![1] } else { Vec::new() }
+}
+
+struct Machine<'a> {
+    stack: Vec<Vec<u8>>,
+    sig_checks: usize,
+    context: &'a ScriptContext<'a>,
+}
+
+impl Machine<'_> {
+    fn run(&mut self, script: &Script) -> Result<(), ScriptError> {
+        // For each enclosing `If`, whether its current branch runs
+        let mut branches: Vec<bool> = Vec::new();
+        for op in script.ops() {
+            let running = branches.iter().all(|runs| *runs);
+            match op {
+                Op::If => {
+                    let condition = running && is_true(&self.pop()?);
+                    branches.push(condition);
+                }
+                Op::Else => {
+                    let enclosing = branches.len().checked_sub(1).ok_or(ScriptError::UnbalancedConditional)?;
+                    let outer_running = branches[..enclosing].iter().all(|runs| *runs);
+                    // The condition was popped only if the `If` itself was running
+                    branches[enclosing] = outer_running && !branches[enclosing];
+                }
+                Op::EndIf => {
+                    branches.pop().ok_or(ScriptError::UnbalancedConditional)?;
+                }
+                _ if !running => {}
+                _ => self.step(op)?,
+            }
+            if self.stack.len() > MAX_STACK_SIZE {
+                return Err(ScriptError::StackOverflow);
+            }
+        }
+        if !branches.is_empty() {
+            return Err(ScriptError::UnbalancedConditional);
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, op: &Op) -> Result<(), ScriptError> {
+        match op {
+            Op::Push(data) => self.stack.push(data.clone()),
+            Op::Number(number) => self.stack.push(number.to_be_bytes().to_vec()),
+            Op::Dup => {
+                let top = self.stack.last().ok_or(ScriptError::StackUnderflow)?.clone();
+                self.stack.push(top);
+            }
+            Op::Drop => {
+                self.pop()?;
+            }
+            Op::Swap => {
+                let first = self.pop()?;
+                let second = self.pop()?;
+                self.stack.push(first);
+                self.stack.push(second);
+            }
+            Op::Equal => {
+                let equal = self.pop()? == self.pop()?;
+                self.stack.push(from_bool(equal));
+            }
+            Op::EqualVerify => {
+                if self.pop()? != self.pop()? {
+                    return Err(ScriptError::VerifyFailed);
+                }
+            }
+            Op::Sha256 => {
+                let top = self.pop()?;
+                self.stack.push(Sha256::digest(&top).to_vec());
+            }
+            Op::CheckSig => {
+                let valid = self.check_signature()?;
+                self.stack.push(from_bool(valid));
+            }
+            Op::CheckSigVerify => {
+                if !self.check_signature()? {
+                    return Err(ScriptError::VerifyFailed);
+                }
+            }
+            Op::CheckHeightVerify => {
+                if self.context.height < self.pop_number()? {
+                    return Err(ScriptError::Locked);
+                }
+            }
+            Op::CheckTimeVerify => {
+                if self.context.timestamp < self.pop_number()? {
+                    return Err(ScriptError::Locked);
+                }
+            }
+            Op::Not => {
+                let top = self.pop()?;
+                self.stack.push(from_bool(!is_true(&top)));
+            }
+            Op::BoolAnd => {
+                let (a, b) = (self.pop()?, self.pop()?);
+                self.stack.push(from_bool(is_true(&a) && is_true(&b)));
+            }
+            Op::BoolOr => {
+                let (a, b) = (self.pop()?, self.pop()?);
+                self.stack.push(from_bool(is_true(&a) || is_true(&b)));
+            }
+            Op::Verify => {
+                if !is_true(&self.pop()?) {
+                    return Err(ScriptError::VerifyFailed);
+                }
+            }
+            Op::Fail => return Err(ScriptError::Failed),
+            Op::If | Op::Else | Op::EndIf => unreachable!("Conditionals are handled by run"),
+        }
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<Vec<u8>, ScriptError> {
+        self.stack.pop().ok_or(ScriptError::StackUnderflow)
+    }
+
+    fn pop_number(&mut self) -> Result<u64, ScriptError> {
+        let bytes: [u8; 8] = self.pop()?.try_into().map_err(|_| ScriptError::InvalidNumber)?;
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    /// Pops a public key and a signature, and returns whether the signature is valid.
+    fn check_signature(&mut self) -> Result<bool, ScriptError> {
+        self.sig_checks += 1;
+        if self.sig_checks > MAX_SIG_CHECKS {
+            return Err(ScriptError::TooManySigChecks);
+        }
+        let key = self.pop()?;
+        let signature = self.pop()?;
+        let Some(key) = <[u8; 32]>::try_from(key).ok().and_then(|key| VerifyingKey::from_bytes(&key).ok()) else {
+            return Ok(false);
+        };
+        Ok(verify_signature(&key, self.context.signed_bytes, &signature).is_ok())
+    }
+}
+
+/// Reasons why a script refuses a spend.
+#[derive(Debug, PartialEq)]
+pub enum ScriptError {
+    /// The script has more than `MAX_SCRIPT_OPS` operations
+    TooLong,
+    /// A pushed element is longer than `MAX_ELEMENT_LEN`
+    ElementTooLarge,
+    /// The stack holds more than `MAX_STACK_SIZE` elements
+    StackOverflow,
+    /// An operation needs more elements than the stack holds
+    StackUnderflow,
+    /// More than `MAX_SIG_CHECKS` signatures are checked
+    TooManySigChecks,
+    /// A height or a timestamp is not encoded on 8 bytes
+    InvalidNumber,
+    UnbalancedConditional,
+    /// The unlocking script does something else than pushing data
+    NotPushOnly,
+    /// A `Verify` operation failed
+    VerifyFailed,
+    /// The transaction is written before the height or the time required by the script
+    Locked,
+    /// The script failed, or did not end with true on the stack
+    Failed,
+}
+
+impl Display for ScriptError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScriptError::TooLong => write!(f, "Script too long"),
+            ScriptError::ElementTooLarge => write!(f, "Element too large"),
+            ScriptError::StackOverflow => write!(f, "Stack overflow"),
+            ScriptError::StackUnderflow => write!(f, "Stack underflow"),
+            ScriptError::TooManySigChecks => write!(f, "Too many signature checks"),
+            ScriptError::InvalidNumber => write!(f, "Invalid number"),
+            ScriptError::UnbalancedConditional => write!(f, "Unbalanced conditional"),
+            ScriptError::NotPushOnly => write!(f, "Unlocking script is not push only"),
+            ScriptError::VerifyFailed => write!(f, "Verify failed"),
+            ScriptError::Locked => write!(f, "Still locked"),
+            ScriptError::Failed => write!(f, "Script failed"),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::Signer;
+    use sha2::{Digest, Sha256};
+    use crate::blockchain::Blockchain;
+    use crate::client::Client;
+    use crate::script::{verify_script, Op, Script, ScriptContext, ScriptError, MAX_STACK_SIZE};
+    use crate::simple_transaction::SimpleTransaction;
+    use crate::state::Ledger;
+    use crate::transaction::{Transaction, TransactionError};
+    use crate::utxo::{OutPoint, TxInput, TxOutput, UtxoSet, UtxoTransaction, Witness, GENESIS_TX_ID};
+    use crate::validity::Validity;
+
+    /// Spends the output locked by `locking` to `receiver`, unlocking it with the signature of
+    /// `signer` followed by `arguments`.
+    fn spend(locking: &Script, signer: &Client, arguments: Vec<Op>, receiver: &Client) -> Transaction {
+        let previous_output = OutPoint { tx_id: GENESIS_TX_ID.to_string(), index: 0 };
+        let outputs = vec![TxOutput { amount: 10, owner: receiver.address() }];
+        let data = UtxoTransaction::signed_bytes(std::slice::from_ref(&previous_output), &outputs, &Validity::default());
+        let signature = signer.signing_key().sign(&data).to_vec();
+        let unlocking = Script::new([vec![Op::Push(signature)], arguments].concat());
+        let witness = Witness::Script { locking: locking.clone(), unlocking };
+        UtxoTransaction::new(vec![TxInput { previous_output, witness }], outputs).into()
+    }
+
+    #[test]
+    fn test_hash_time_locked_contract() {
+        let alice = Client::new();
+        let bob = Client::new();
+        let secret = b"open sesame".to_vec();
+        let htlc = Script::hash_time_lock(&bob.public_key(), Sha256::digest(&secret).into(), &alice.public_key(), 3);
+        let utxos = UtxoSet::with_allocations([TxOutput { amount: 10, owner: htlc.address() }]);
+        let mut chain = Blockchain::with_genesis(Ledger::new(), utxos);
+
+        // Bob needs the secret, and alice must wait for the timeout
+        let wrong_secret = spend(&htlc, &bob, vec![Op::Push(b"guess".to_vec()), Op::Number(1)], &bob);
+        assert_eq!(Err(TransactionError::Script(ScriptError::VerifyFailed)), chain.check_transaction(&wrong_secret));
+        let refund = spend(&htlc, &alice, vec![Op::Number(0)], &alice);
+        assert_eq!(Err(TransactionError::Script(ScriptError::Locked)), chain.check_transaction(&refund));
+        let stolen = spend(&htlc, &alice, vec![Op::Push(secret.clone()), Op::Number(1)], &alice);
+        assert_eq!(Err(TransactionError::Script(ScriptError::Failed)), chain.check_transaction(&stolen));
+
+        let claim = spend(&htlc, &bob, vec![Op::Push(secret), Op::Number(1)], &bob);
+        chain.add_test_block(vec![claim]);
+        assert_eq!(10, chain.utxos().balance(&bob.address()));
+    }
+
+    #[test]
+    fn test_refund_after_timeout() {
+        let alice = Client::new();
+        let bob = Client::new();
+        let htlc = Script::hash_time_lock(&bob.public_key(), [0; 32], &alice.public_key(), 2);
+        let utxos = UtxoSet::with_allocations([TxOutput { amount: 10, owner: htlc.address() }]);
+        let mut chain = Blockchain::with_genesis(Ledger::new(), utxos);
+        chain.add_test_block(vec![SimpleTransaction::from_str("1").into()]);
+
+        let refund = spend(&htlc, &alice, vec![Op::Number(0)], &alice);
+        chain.add_test_block(vec![refund]);
+        assert_eq!(10, chain.utxos().balance(&alice.address()));
+    }
+
+    #[test]
+    fn test_resource_limits() {
+        let context = ScriptContext { signed_bytes: &[], height: 0, timestamp: 0 };
+        let flood = Script::new(vec![Op::Number(1); MAX_STACK_SIZE + 1]);
+        assert_eq!(Err(ScriptError::StackOverflow), verify_script(&Script::default(), &flood, &context));
+
+        let not_push_only = Script::new(vec![Op::Number(1), Op::Dup]);
+        assert_eq!(Err(ScriptError::NotPushOnly), verify_script(&not_push_only, &Script::default(), &context));
+
+        let unbalanced = Script::new(vec![Op::If, Op::Number(1)]);
+        assert_eq!(Err(ScriptError::UnbalancedConditional), verify_script(&Script::new(vec![Op::Number(1)]), &unbalanced, &context));
+    }
+}
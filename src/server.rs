@@ -1,15 +1,34 @@
-use std::collections::VecDeque;
+use std::error::Error;
+use std::io;
+use std::path::Path;
+use std::sync::mpsc::Receiver;
 use std::sync::{Arc, Mutex};
-use rand::Rng;
-use rouille::{router, Response};
-use repyh::block::Block;
-use repyh::blockchain::Blockchain;
+use rouille::{router, Request, Response};
+use serde_json::json;
+use repyh::address::Address;
+use repyh::block::{Block, MAX_BLOCK_SIZE};
+use repyh::blockchain::{Blockchain, Placement};
+use repyh::mempool::Mempool;
+use repyh::mining::DIFFICULTY;
+use repyh::signed_transaction::SignedTransaction;
 use repyh::simple_transaction::SimpleTransaction;
+use repyh::transaction::{Transaction, TransactionError};
+use crate::http::{error, read_json, MAX_TRANSACTION_BODY_LEN};
+
+/// Maximum number of transactions waiting in the mempool.
+const MEMPOOL_CAPACITY: usize = 1000;
+
+/// Number of transactions given to a worker for each block it mines.
+const TRANSACTIONS_PER_BLOCK: usize = 20;
+
+/// Maximum length, in bytes, of the JSON body of a submitted block, which takes several times the
+/// size of the block.
+const MAX_BLOCK_BODY_LEN: u64 = 8 * MAX_BLOCK_SIZE as u64;
 
 /// Server in charge of keeping track of the pending transactions
 pub struct Server {
-    /// Pool of pending transactions
-    mempool: VecDeque<SimpleTransaction>,
+    /// Pool of pending transactions, ordered by fee rate
+    mempool: Mempool,
     /// The server holds a representation of blockchain that it keeps building using all the blocks
     /// received by workers.
     /// Keeping track of the blockchain allows the server to safely remove pending transactions, once
@@ -21,29 +40,62 @@ pub struct Server {
 impl Server {
     pub fn new() -> Self {
         Self {
-            mempool: VecDeque::new(),
+            mempool: Mempool::new(MEMPOOL_CAPACITY),
             blockchain: Blockchain::new()
         }
     }
 
-    pub fn submit_transaction(&mut self, tx: SimpleTransaction) {
-        self.mempool.push_back(tx)
+    /// Creates a server which starts from a chain saved by a previous run.
+    pub fn with_blockchain(blockchain: Blockchain) -> Self {
+        Self {
+            mempool: Mempool::new(MEMPOOL_CAPACITY),
+            blockchain
+        }
+    }
+
+    /// Writes the representation of the blockchain of the server to a file.
+    pub fn save_blockchain(&self, path: &Path) -> io::Result<()> {
+        self.blockchain.save(path)
     }
 
-    /// Returns one of the transaction in the pool
-    fn get_pending_transaction(&self) -> Option<SimpleTransaction> {
-        if self.mempool.is_empty() {
-            None
-        } else {
-            let num = rand::thread_rng().gen_range(0..self.mempool.len());
-            Some(self.mempool[num].clone())
+    /// Adds a transaction to the pool, if its signature is valid, if it can be written in the next
+    /// block, if its sender can afford it, and if its fee is high enough to enter the pool.
+    pub fn submit_transaction(&mut self, tx: impl Into<Transaction>) -> Result<(), TransactionError> {
+        self.mempool.submit(tx.into(), &self.blockchain)
+    }
+
+    /// Returns true if the transaction is already in the pool, or written in the main chain.
+    pub fn knows_transaction(&self, tx: &Transaction) -> bool {
+        self.mempool.contains(tx) || self.blockchain.has_transaction(tx)
+    }
+
+    /// Adds a block mined by a worker to the representation of the blockchain of the server, if
+    /// it holds a valid proof of work, and returns where it was placed.
+    pub fn submit_block(&mut self, block: Block) -> Result<Placement, TransactionError> {
+        if !block.is_hash_valid(DIFFICULTY) {
+            return Err(TransactionError::InvalidProofOfWork);
         }
+        let placement = self.blockchain.add_block(block)?;
+        self.blockchain.resolve_pending_forks();
+        self.blockchain.print_chain();
+        self.resolve_safe_transactions();
+        log::info!("Remaining transaction in the pool: {:?}", self.mempool.len());
+        Ok(placement)
+    }
+
+    pub fn mempool_len(&self) -> usize {
+        self.mempool.len()
+    }
+
+    /// Returns the transactions of the pool that pay the most, the highest fee rate first.
+    fn get_pending_transactions(&self) -> Vec<Transaction> {
+        self.mempool.best(TRANSACTIONS_PER_BLOCK)
     }
     
     /// Checks if some of the transaction on the pool is safely written in the chain, 
-    /// and if so remove it from the pool.
+    /// and if so remove it from the pool. Expired transactions are removed as well.
     fn resolve_safe_transactions(&mut self) {
-        self.mempool.retain(|tx| !self.blockchain.is_transaction_safely_written(&tx))
+        self.mempool.prune(&self.blockchain)
     }
     
 }
@@ -55,47 +107,270 @@ const REJECTED: &str = "Rejected";
 /// Launch a webserver, associated with a transaction server, that will answer to workers and external clients.
 /// 
 /// Supported end-points
-/// * /submit_transaction/DATA : registers a new transaction in the mempool to be saved
+/// * POST /transactions       : registers the transaction given as JSON in the body in the
+///                              mempool. Answers 202 with its id, 409 if the transaction is
+///                              already known, and 422 if it is refused.
 /// 
-/// * /get_transaction/        : returns a single transaction that is in the mempool.
+/// * POST /blocks             : registers a new block, given as JSON in the body, mined by one of
+///                              the workers. The server keeps track of the blocks submitted by the
+///                              workers to have its own version of the blockchain. Answers with
+///                              where the block was placed, 409 if the block is already known, and
+///                              422 if it is refused.
+///
+/// * /get_transactions/       : returns the transactions of the mempool paying the highest fee
+///                              rates, as a JSON array, to fill the next block.
 /// 
-/// * /submit_block/DATA       : registers a new block mined by one of the worker. 
-///                              The server keeps track of the nodes submitted by the workers to 
-///                              have its own version of the blockchain.
-pub fn run_web_server(server: Arc<Mutex<Server>>) {
-    rouille::start_server("localhost:8000", move |request| {
-        router!(request,
-            (GET) (/submit_transaction/{data: String}) => {
-                // Worker ask for a random transaction in the list from the pending ones
-                println!("A client submitted a new transaction: {data}");
-                server.lock().unwrap().submit_transaction(SimpleTransaction::from_str(&data));
-                Response::text("submitted")
-            },
-
-            (GET) (/get_transaction) => {
-                // Worker ask for a random transaction in the list from the pending ones
-                if let Some(transaction) = server.lock().unwrap().get_pending_transaction() {
-                    let as_json = serde_json::to_string(&transaction).unwrap();
-                    Response::text(as_json)
-                } else {
-                    Response::text("")
+/// * /account/ADDRESS         : returns the balance and the nonce of an account, what its
+///                              unspent outputs hold, and its tokens, as JSON.
+///
+/// * /history/ADDRESS         : returns the transactions of the main chain involving an account,
+///                              with the height of their block, as JSON.
+///
+/// * /name/NAME              : returns the owner, the record and the expiry of a registered
+///                              name, as JSON.
+///
+/// * /blob/DIGEST            : returns the blob of the given SHA-256 digest, reassembled from its
+///                              chunks.
+///
+/// * /chunk/DIGEST           : returns the height of the block storing the chunk of the given
+///                              SHA-256 digest, and its length, as JSON.
+///
+/// * /proof/DIGEST           : returns a proof that the document of the given SHA-256 digest
+///                              was notarized in the main chain, as JSON.
+///
+/// * /headers                 : returns the headers of the main chain, against which proofs are
+///                              checked, as JSON.
+///
+/// The bodies of the `POST` requests must be `application/json`, and are limited in size. Refused
+/// requests are answered with a JSON body `{"error": {"code": ..., "message": ...}}`.
+///
+/// With `legacy_routes`, the server also accepts the data in the path of `GET` requests, as it
+/// used to (see `legacy_route`).
+///
+/// The webserver runs until a message is received on `shutdown`. Requests being processed at that
+/// time are completed before this function returns.
+pub fn run_web_server(server: Arc<Mutex<Server>>, legacy_routes: bool, shutdown: Receiver<()>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let web_server = rouille::Server::new("localhost:8000", move |request| {
+        handle_request(&server, legacy_routes, request)
+    })?;
+
+    let (handle, stop) = web_server.stoppable();
+    let _ = shutdown.recv();
+    log::info!("Stopping the web server...");
+    let _ = stop.send(());
+    handle.join().map_err(|_| "The web server thread panicked")?;
+    Ok(())
+}
+
+fn handle_request(server: &Mutex<Server>, legacy_routes: bool, request: &Request) -> Response {
+    if legacy_routes {
+        if let Some(response) = legacy_route(server, request) {
+            return response;
+        }
+    }
+    router!(request,
+        (POST) (/transactions) => {
+            let transaction: Transaction = match read_json(request, MAX_TRANSACTION_BODY_LEN) {
+                Ok(transaction) => transaction,
+                Err(response) => return response,
+            };
+            let id = transaction.id();
+            let mut server = server.lock().unwrap();
+            if server.knows_transaction(&transaction) {
+                return error(409, "known_transaction", "the transaction is already pending or written");
+            }
+            match server.submit_transaction(transaction) {
+                Ok(()) => Response::json(&json!({ "id": id })).with_status_code(202),
+                Err(e) => {
+                    log::warn!("Refused a transaction: {e}");
+                    error(422, "invalid_transaction", &e.to_string())
                 }
-            },
-
-            (GET) (/submit_block/{data: String}) => {
-                // Parse the block sent by the client
-                let received: Block = serde_json::from_str(&data).unwrap();
-                log::info!("Server received block.");
-                server.lock().unwrap().blockchain.add_block_safe(received);
-                server.lock().unwrap().blockchain.resolve_pending_forks();
-                server.lock().unwrap().blockchain.print_chain();
-                server.lock().unwrap().resolve_safe_transactions();
-                log::info!("Remaining transaction in the pool: {:?}", server.lock().unwrap().mempool.len());
-                Response::text("")
-            },
-
-            _ => Response::empty_404()
-        )
-    });
+            }
+        },
+
+        (POST) (/blocks) => {
+            let block: Block = match read_json(request, MAX_BLOCK_BODY_LEN) {
+                Ok(block) => block,
+                Err(response) => return response,
+            };
+            let hash = block.hash();
+            log::info!("Server received block.");
+            match server.lock().unwrap().submit_block(block) {
+                Ok(placement) => Response::json(&json!({ "hash": hash, "placement": format!("{placement:?}") })),
+                Err(TransactionError::KnownBlock) => error(409, "known_block", "the block is already known"),
+                Err(e) => error(422, "invalid_block", &e.to_string()),
+            }
+        },
+
+        (GET) (/get_transactions) => {
+            // Worker ask for the transactions to put in its next block
+            let transactions = server.lock().unwrap().get_pending_transactions();
+            Response::text(serde_json::to_string(&transactions).unwrap())
+        },
+
+        (GET) (/account/{address: String}) => {
+            let Ok(address) = address.parse::<Address>() else {
+                return Response::text(REJECTED).with_status_code(400);
+            };
+            let server = server.lock().unwrap();
+            let account = json!({
+                "address": address,
+                "balance": server.blockchain.ledger().balance(&address),
+                "nonce": server.blockchain.ledger().nonce(&address),
+                "unspent": server.blockchain.utxos().balance(&address),
+                "tokens": server.blockchain.ledger().tokens().balances_of(&address),
+            });
+            Response::text(account.to_string())
+        },
+
+        (GET) (/history/{address: String}) => {
+            let Ok(address) = address.parse::<Address>() else {
+                return Response::text(REJECTED).with_status_code(400);
+            };
+            let server = server.lock().unwrap();
+            let history: Vec<_> = server.blockchain
+                .history(&address)
+                .into_iter()
+                .map(|(height, tx)| json!({ "height": height, "id": tx.id(), "transaction": tx }))
+                .collect();
+            Response::text(serde_json::to_string(&history).unwrap())
+        },
+
+        (GET) (/name/{name: String}) => {
+            match server.lock().unwrap().blockchain.resolve_name(&name) {
+                Some(entry) => Response::text(serde_json::to_string(entry).unwrap()),
+                None => Response::empty_404(),
+            }
+        },
 
+        (GET) (/blob/{digest: String}) => {
+            match server.lock().unwrap().blockchain.get_blob(&digest) {
+                Ok(blob) => Response::from_data("application/octet-stream", blob),
+                Err(e) => Response::text(e.to_string()).with_status_code(404),
+            }
+        },
+
+        (GET) (/chunk/{digest: String}) => {
+            match server.lock().unwrap().blockchain.ledger().blobs().chunk(&digest) {
+                Some(location) => Response::text(serde_json::to_string(&location).unwrap()),
+                None => Response::empty_404(),
+            }
+        },
+
+        (GET) (/proof/{digest: String}) => {
+            match server.lock().unwrap().blockchain.prove_document(&digest) {
+                Some(proof) => Response::text(serde_json::to_string(&proof).unwrap()),
+                None => Response::empty_404(),
+            }
+        },
+
+        (GET) (/headers) => {
+            let headers = server.lock().unwrap().blockchain.headers();
+            Response::text(serde_json::to_string(&headers).unwrap())
+        },
+
+        _ => error(404, "not_found", "unknown route")
+    )
+}
+
+/// Answers the routes of the previous versions of the server, which take their data in the path
+/// of `GET` requests, or returns `None` for other routes.
+///
+/// * /submit_transaction/DATA : registers a new plain text transaction in the mempool
+///
+/// * /submit_signed_transaction/DATA : registers a new signed transaction, given as JSON, in the
+///                              mempool. The transaction is rejected if its signature is invalid.
+///
+/// * /submit_block/DATA       : registers a new block, given as JSON, mined by one of the workers.
+// The fallback route of `router!` can't be written with `?`
+#[allow(clippy::question_mark)]
+fn legacy_route(server: &Mutex<Server>, request: &Request) -> Option<Response> {
+    router!(request,
+        (GET) (/submit_transaction/{data: String}) => {
+            // Worker ask for a random transaction in the list from the pending ones
+            println!("A client submitted a new transaction: {data}");
+            // A random nonce lets the same text be written again.
+            let transaction = SimpleTransaction::with_nonce(&data, rand::random());
+            match server.lock().unwrap().submit_transaction(transaction) {
+                Ok(()) => Some(Response::text("submitted")),
+                Err(e) => {
+                    log::warn!("Refused a transaction: {e}");
+                    Some(Response::text(REJECTED).with_status_code(400))
+                }
+            }
+        },
+
+        (GET) (/submit_signed_transaction/{data: String}) => {
+            let Ok(transaction) = serde_json::from_str::<SignedTransaction>(&data) else {
+                return Some(Response::text(REJECTED).with_status_code(400));
+            };
+            match server.lock().unwrap().submit_transaction(transaction) {
+                Ok(()) => Some(Response::text(ACCEPTED)),
+                Err(e) => {
+                    log::warn!("Refused a transaction: {e}");
+                    Some(Response::text(REJECTED).with_status_code(400))
+                }
+            }
+        },
+
+        (GET) (/submit_block/{data: String}) => {
+            // Parse the block sent by the client
+            let Ok(received) = serde_json::from_str::<Block>(&data) else {
+                return Some(Response::text(REJECTED).with_status_code(400));
+            };
+            log::info!("Server received block.");
+            let _ = server.lock().unwrap().submit_block(received);
+            Some(Response::text(""))
+        },
+
+        _ => None
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use rouille::{Request, Response};
+    use repyh::block::Block;
+    use repyh::mining::DIFFICULTY;
+    use crate::http::status_and_code;
+    use crate::server::{handle_request, Server};
+
+    fn post_block(server: &Mutex<Server>, block: &Block) -> Response {
+        let headers = vec![("Content-Type".to_string(), "application/json".to_string())];
+        let body = serde_json::to_vec(block).unwrap();
+        handle_request(server, false, &Request::fake_http("POST", "/blocks", headers, body))
+    }
+
+    #[test]
+    fn test_known_block_is_answered_with_409() {
+        let server = Mutex::new(Server::new());
+        let response = post_block(&server, &Block::genesis());
+        assert_eq!((409, "known_block".to_string()), status_and_code(response));
+    }
+
+    #[test]
+    fn test_invalid_block_is_answered_with_422() {
+        let server = Mutex::new(Server::new());
+        let mut block = Block::genesis();
+        block.set_nonce(block.nonce() + 1);
+        assert_eq!(false, block.is_hash_valid(DIFFICULTY));
+
+        let response = post_block(&server, &block);
+        assert_eq!((422, "invalid_block".to_string()), status_and_code(response));
+    }
+
+    #[test]
+    fn test_legacy_routes_are_only_answered_when_enabled() {
+        let server = Mutex::new(Server::new());
+        let request = || Request::fake_http("GET", "/submit_transaction/hello", vec![], vec![]);
+
+        let response = handle_request(&server, false, &request());
+        assert_eq!((404, "not_found".to_string()), status_and_code(response));
+        assert_eq!(0, server.lock().unwrap().mempool_len());
+
+        let response = handle_request(&server, true, &request());
+        assert_eq!(200, response.status_code);
+        assert_eq!(1, server.lock().unwrap().mempool_len());
+    }
 }
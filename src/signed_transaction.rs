@@ -0,0 +1,294 @@
+use std::fmt::{Debug, Formatter};
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use crate::address::Address;
+use crate::blob::Manifest;
+use crate::message::EncryptedMessage;
+use crate::names::NameRecord;
+use crate::transaction::{push_field, TransactionError};
+use crate::validity::Validity;
+
+/// What a signed transaction does.
+#[derive(Clone, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum Payload {
+    /// Writes a text on the chain
+    Message(String),
+    /// Moves `amount` from the account of the sender to the account of the receiver
+    Transfer { receiver: Address, amount: u64 },
+    /// Deploys a WebAssembly contract, at the address given by the sender and the nonce
+    Deploy { code: Vec<u8>, gas_limit: u64 },
+    /// Runs the `call` function of a contract with the given input
+    Call { contract: Address, input: Vec<u8>, gas_limit: u64 },
+    /// Creates the asset of the symbol, or mints more of it if the sender is its issuer
+    Issue { symbol: String, amount: u64 },
+    /// Moves `amount` tokens of an asset from the sender to the receiver
+    TokenTransfer { symbol: String, receiver: Address, amount: u64 },
+    /// Destroys `amount` tokens of an asset held by the sender
+    Burn { symbol: String, amount: u64 },
+    /// Anchors the SHA-256 digest of a document, given in hex, with a short description
+    Notarize { digest: String, metadata: String },
+    /// Registers a free name, pointing to the record
+    RegisterName { name: String, record: NameRecord },
+    /// Changes the record of a name owned by the sender, and renews it
+    UpdateName { name: String, record: NameRecord },
+    /// Gives a name owned by the sender to another account
+    TransferName { name: String, new_owner: Address },
+    /// Frees a name owned by the sender before it expires
+    ReleaseName { name: String },
+    /// Stores a chunk of a blob, addressed by its digest
+    Chunk(Vec<u8>),
+    /// Lists the chunks of a blob, which must be stored before
+    Manifest(Manifest),
+    /// A message that only its recipient can read
+    Encrypted(EncryptedMessage),
+}
+
+impl Payload {
+    /// Returns the canonical encoding of the payload.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self {
+            Payload::Message(message) => {
+                bytes.push(0);
+                push_field(&mut bytes, message.as_bytes());
+            }
+            Payload::Transfer { receiver, amount } => {
+                bytes.push(1);
+                bytes.extend_from_slice(receiver.as_bytes());
+                bytes.extend_from_slice(&amount.to_be_bytes());
+            }
+            Payload::Deploy { code, gas_limit } => {
+                bytes.push(2);
+                push_field(&mut bytes, code);
+                bytes.extend_from_slice(&gas_limit.to_be_bytes());
+            }
+            Payload::Call { contract, input, gas_limit } => {
+                bytes.push(3);
+                bytes.extend_from_slice(contract.as_bytes());
+                push_field(&mut bytes, input);
+                bytes.extend_from_slice(&gas_limit.to_be_bytes());
+            }
+            Payload::Issue { symbol, amount } => {
+                bytes.push(4);
+                push_field(&mut bytes, symbol.as_bytes());
+                bytes.extend_from_slice(&amount.to_be_bytes());
+            }
+            Payload::TokenTransfer { symbol, receiver, amount } => {
+                bytes.push(5);
+                push_field(&mut bytes, symbol.as_bytes());
+                bytes.extend_from_slice(receiver.as_bytes());
+                bytes.extend_from_slice(&amount.to_be_bytes());
+            }
+            Payload::Burn { symbol, amount } => {
+                bytes.push(6);
+                push_field(&mut bytes, symbol.as_bytes());
+                bytes.extend_from_slice(&amount.to_be_bytes());
+            }
+            Payload::Notarize { digest, metadata } => {
+                bytes.push(7);
+                push_field(&mut bytes, digest.as_bytes());
+                push_field(&mut bytes, metadata.as_bytes());
+            }
+            Payload::RegisterName { name, record } => {
+                bytes.push(8);
+                push_field(&mut bytes, name.as_bytes());
+                record.push_bytes(&mut bytes);
+            }
+            Payload::UpdateName { name, record } => {
+                bytes.push(9);
+                push_field(&mut bytes, name.as_bytes());
+                record.push_bytes(&mut bytes);
+            }
+            Payload::TransferName { name, new_owner } => {
+                bytes.push(10);
+                push_field(&mut bytes, name.as_bytes());
+                bytes.extend_from_slice(new_owner.as_bytes());
+            }
+            Payload::ReleaseName { name } => {
+                bytes.push(11);
+                push_field(&mut bytes, name.as_bytes());
+            }
+            Payload::Chunk(data) => {
+                bytes.push(12);
+                push_field(&mut bytes, data);
+            }
+            Payload::Manifest(manifest) => {
+                bytes.push(13);
+                manifest.push_bytes(&mut bytes);
+            }
+            Payload::Encrypted(message) => {
+                bytes.push(14);
+                message.push_bytes(&mut bytes);
+            }
+        }
+        bytes
+    }
+}
+
+impl Debug for Payload {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Payload::Message(message) => write!(f, "\"{message}\""),
+            Payload::Transfer { amount, .. } => write!(f, "transfer of {amount}"),
+            Payload::Deploy { code, .. } => write!(f, "deploy of {} bytes", code.len()),
+            Payload::Call { contract, .. } => write!(f, "call of {contract}"),
+            Payload::Issue { symbol, amount } => write!(f, "issue of {amount} {symbol}"),
+            Payload::TokenTransfer { symbol, amount, .. } => write!(f, "transfer of {amount} {symbol}"),
+            Payload::Burn { symbol, amount } => write!(f, "burn of {amount} {symbol}"),
+            Payload::Notarize { digest, .. } => write!(f, "notarization of {digest}"),
+            Payload::RegisterName { name, .. } => write!(f, "registration of {name}"),
+            Payload::UpdateName { name, .. } => write!(f, "update of {name}"),
+            Payload::TransferName { name, new_owner } => write!(f, "transfer of {name} to {new_owner}"),
+            Payload::ReleaseName { name } => write!(f, "release of {name}"),
+            Payload::Chunk(data) => write!(f, "chunk of {} bytes", data.len()),
+            Payload::Manifest(manifest) => write!(f, "manifest of {}", manifest.digest),
+            Payload::Encrypted(message) => write!(f, "encrypted message to {}", message.recipient),
+        }
+    }
+}
+
+/// Checks an ed25519 signature of `data` by the owner of `key`.
+pub(crate) fn verify_signature(key: &VerifyingKey, data: &[u8], signature: &[u8]) -> Result<(), TransactionError> {
+    let signature = Signature::from_slice(signature)
+        .map_err(|_| TransactionError::InvalidSignature)?;
+    key.verify_strict(data, &signature)
+        .map_err(|_| TransactionError::InvalidSignature)
+}
+
+/// A transaction signed by its sender.
+///
+/// The signature is an ed25519 signature of the canonical encoding of the sender's
+/// public key, of the payload, of the fee and of the nonce, so that none of them can be changed.
+///
+/// The nonces of the transactions of an account must strictly increase along the chain, so that a
+/// transaction can't be replayed.
+#[derive(Clone, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct SignedTransaction {
+    /// Public key of the sender
+    sender: VerifyingKey,
+    payload: Payload,
+    /// Amount paid by the sender to the miner of the block, on top of the payload
+    #[serde(default)]
+    fee: u64,
+    /// Sequence number of the transaction among those of the sender
+    #[serde(default)]
+    nonce: u64,
+    /// The blocks in which the transaction can be written
+    #[serde(default)]
+    validity: Validity,
+    signature: Vec<u8>,
+}
+
+impl SignedTransaction {
+    /// Builds a transaction from an existing signature. Use a `Client` to create a new one.
+    pub fn new(sender: VerifyingKey, payload: Payload, fee: u64, nonce: u64, signature: Vec<u8>) -> Self {
+        Self { sender, payload, fee, nonce, validity: Validity::default(), signature }
+    }
+
+    /// Sets the validity of a transaction built with `new`. The validity is part of what the
+    /// sender signs.
+    pub fn with_validity(mut self, validity: Validity) -> Self {
+        self.validity = validity;
+        self
+    }
+
+    /// Returns the canonical encoding of what the sender signs.
+    pub fn signed_bytes(sender: &VerifyingKey, payload: &Payload, fee: u64, nonce: u64, validity: &Validity) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(sender.as_bytes());
+        bytes.extend_from_slice(&payload.to_bytes());
+        bytes.extend_from_slice(&fee.to_be_bytes());
+        bytes.extend_from_slice(&nonce.to_be_bytes());
+        bytes.extend_from_slice(&validity.to_bytes());
+        bytes
+    }
+
+    /// Checks that the signature was produced by the sender, for this payload, fee, nonce and
+    /// validity.
+    pub fn verify(&self) -> Result<(), TransactionError> {
+        verify_signature(&self.sender, &self.own_signed_bytes(), &self.signature)
+    }
+
+    fn own_signed_bytes(&self) -> Vec<u8> {
+        Self::signed_bytes(&self.sender, &self.payload, self.fee, self.nonce, &self.validity)
+    }
+
+    /// Returns the bytes representation of this transaction, including its signature.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.own_signed_bytes();
+        push_field(&mut bytes, &self.signature);
+        bytes
+    }
+
+    pub fn sender(&self) -> &VerifyingKey {
+        &self.sender
+    }
+
+    /// Returns the address of the account of the sender.
+    pub fn sender_address(&self) -> Address {
+        Address::from_public_key(&self.sender)
+    }
+
+    pub fn payload(&self) -> &Payload {
+        &self.payload
+    }
+
+    pub fn fee(&self) -> u64 {
+        self.fee
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    pub fn validity(&self) -> &Validity {
+        &self.validity
+    }
+
+    pub fn signature(&self) -> &[u8] {
+        &self.signature
+    }
+}
+
+impl Debug for SignedTransaction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} (signed)", self.payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::Client;
+    use crate::signed_transaction::Payload;
+    use crate::transaction::TransactionError;
+
+    #[test]
+    fn test_signature_is_verified() {
+        let mut client = Client::new();
+        let tx = client.sign("Hello world");
+        assert_eq!(Ok(()), tx.verify());
+    }
+
+    #[test]
+    fn test_tampered_transaction_is_rejected() {
+        let mut client = Client::new();
+        let mut tx = client.sign("Hello world");
+        tx.payload = Payload::Message("Goodbye world".to_string());
+        assert_eq!(Err(TransactionError::InvalidSignature), tx.verify());
+
+        // Someone else can't claim to have sent the message either
+        let mut tx = client.sign("Hello world");
+        tx.sender = Client::new().public_key();
+        assert_eq!(Err(TransactionError::InvalidSignature), tx.verify());
+
+        // Nor can the fee be lowered, or the nonce changed to replay the transaction
+        let mut tx = client.sign_payload(Payload::Message("Hello world".to_string()), 5);
+        tx.fee = 0;
+        assert_eq!(Err(TransactionError::InvalidSignature), tx.verify());
+        let mut tx = client.sign("Hello world");
+        tx.nonce += 1;
+        assert_eq!(Err(TransactionError::InvalidSignature), tx.verify());
+    }
+}
@@ -1,5 +1,7 @@
 use std::fmt::{Debug, Formatter};
 use serde::{Deserialize, Serialize};
+use crate::transaction::push_field;
+use crate::validity::Validity;
 
 /// A very simple transaction that can be shared on the network
 /// The goal of saving this transaction on the blockchain is to have its record written immutably across
@@ -8,25 +10,54 @@ use serde::{Deserialize, Serialize};
 #[derive(Serialize, Deserialize)]
 pub struct SimpleTransaction {
     message: String,
+    /// Distinguishes transactions carrying the same message, so that it can be written again
+    #[serde(default)]
+    nonce: u64,
+    #[serde(default)]
+    validity: Validity,
 }
 
 impl SimpleTransaction {
     pub fn new() -> Self {
         Self {
             message: Default::default(),
+            nonce: 0,
+            validity: Validity::default(),
         }
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
-        self.message.as_bytes().to_vec()
+        let mut bytes = Vec::new();
+        push_field(&mut bytes, self.message.as_bytes());
+        bytes.extend_from_slice(&self.nonce.to_be_bytes());
+        bytes.extend_from_slice(&self.validity.to_bytes());
+        bytes
     }
 
     pub fn from_str(text: &str) -> Self {
+        Self::with_nonce(text, 0)
+    }
+
+    /// Creates a transaction that differs from any other one carrying the same text, as long as
+    /// their nonces differ.
+    pub fn with_nonce(text: &str, nonce: u64) -> Self {
         Self {
             message: text.to_string(),
+            nonce,
+            validity: Validity::default(),
         }
     }
 
+    /// Restricts the blocks in which the transaction can be written.
+    pub fn with_validity(mut self, validity: Validity) -> Self {
+        self.validity = validity;
+        self
+    }
+
+    pub fn validity(&self) -> &Validity {
+        &self.validity
+    }
+
     pub fn to_string(&self) -> String {
         self.message.clone()
     }
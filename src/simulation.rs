@@ -0,0 +1,361 @@
+use std::collections::BTreeMap;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use ed25519_dalek::{SigningKey, SECRET_KEY_LENGTH};
+use crate::address::Address;
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::mining::mine_sync;
+use crate::simple_transaction::SimpleTransaction;
+
+/// Identifier of a node in a simulation
+pub type NodeId = usize;
+
+/// The medium through which the nodes of a simulation exchange blocks.
+///
+/// This plays the role of the gossipsub topic used by the real workers.
+pub trait Network {
+    /// Sends a block from `from` to every other node it can reach.
+    fn broadcast(&mut self, from: NodeId, block: &Block);
+
+    /// Returns all the blocks that reach their destination at the time `now`.
+    fn receive(&mut self, now: u64) -> Vec<(NodeId, Block)>;
+}
+
+/// Parameters of a simulation.
+#[derive(Clone, Debug)]
+pub struct SimulationConfig {
+    /// Number of nodes in the network
+    pub nodes: usize,
+    /// Seed of the random number generator, to replay a simulation exactly.
+    pub seed: u64,
+    /// Minimum number of ticks for a block to travel between two nodes
+    pub min_latency: u64,
+    /// Maximum number of ticks for a block to travel between two nodes
+    pub max_latency: u64,
+    /// Probability for a block sent to a node to be lost
+    pub packet_loss: f64,
+    /// Probability for each node to find a block at each tick
+    pub mining_probability: f64,
+    /// Difficulty of the proof-of-work. It is kept low so that simulations run fast.
+    pub difficulty: usize,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            nodes: 4,
+            seed: 0,
+            min_latency: 1,
+            max_latency: 3,
+            packet_loss: 0.0,
+            mining_probability: 0.02,
+            difficulty: 2,
+        }
+    }
+}
+
+/// A network living in memory, with a configurable latency, packet loss and partitions.
+pub struct SimulatedNetwork {
+    /// The group of each node. Two nodes can only communicate when they are in the same group.
+    groups: Vec<usize>,
+    min_latency: u64,
+    max_latency: u64,
+    packet_loss: f64,
+    /// Messages in flight, keyed by (delivery time, sequence number) so that they are delivered
+    /// in a deterministic order.
+    in_flight: BTreeMap<(u64, u64), (NodeId, Block)>,
+    sequence: u64,
+    /// Current time of the network, updated by `receive`
+    now: u64,
+    rng: StdRng,
+}
+
+impl SimulatedNetwork {
+    pub fn new(config: &SimulationConfig) -> Self {
+        Self {
+            groups: vec![0; config.nodes],
+            min_latency: config.min_latency,
+            max_latency: config.max_latency.max(config.min_latency),
+            packet_loss: config.packet_loss,
+            in_flight: BTreeMap::new(),
+            sequence: 0,
+            now: 0,
+            // Use a different stream than the one used for mining
+            rng: StdRng::seed_from_u64(config.seed.wrapping_add(1)),
+        }
+    }
+
+    /// Splits the network: nodes can only talk to the nodes of their own group.
+    /// Nodes that are not listed in any group are isolated together.
+    pub fn partition(&mut self, groups: &[Vec<NodeId>]) {
+        self.groups.iter_mut().for_each(|group| *group = groups.len());
+        for (i, group) in groups.iter().enumerate() {
+            for node in group {
+                self.groups[*node] = i;
+            }
+        }
+    }
+
+    /// Removes all partitions.
+    pub fn heal(&mut self) {
+        self.groups.iter_mut().for_each(|group| *group = 0);
+    }
+
+    pub fn can_communicate(&self, a: NodeId, b: NodeId) -> bool {
+        self.groups[a] == self.groups[b]
+    }
+
+    /// Returns true if there is no message waiting to be delivered.
+    pub fn is_idle(&self) -> bool {
+        self.in_flight.is_empty()
+    }
+}
+
+impl Network for SimulatedNetwork {
+    fn broadcast(&mut self, from: NodeId, block: &Block) {
+        for to in 0..self.groups.len() {
+            if to == from || !self.can_communicate(from, to) {
+                continue;
+            }
+            if self.rng.gen_bool(self.packet_loss) {
+                continue;
+            }
+            let latency = self.rng.gen_range(self.min_latency..=self.max_latency);
+            self.sequence += 1;
+            self.in_flight.insert((self.now + latency, self.sequence), (to, block.clone()));
+        }
+    }
+
+    fn receive(&mut self, now: u64) -> Vec<(NodeId, Block)> {
+        self.now = now;
+        let pending = self.in_flight.split_off(&(now + 1, 0));
+        let delivered = std::mem::replace(&mut self.in_flight, pending);
+        delivered.into_values().collect()
+    }
+}
+
+/// A node of the simulation: it does what `main_worker.rs` does, without the async machinery.
+pub struct SimulatedNode {
+    chain: Blockchain,
+    /// Address to which the node claims the reward of the blocks it mines
+    miner: Address,
+    /// Number of blocks mined by this node
+    mined: u64,
+    /// Depth of every reorganization of the main chain of this node
+    reorg_depths: Vec<usize>,
+}
+
+impl SimulatedNode {
+    fn new(id: NodeId) -> Self {
+        // The key of each node only depends on its id, so that runs can be reproduced
+        let mut secret = [0; SECRET_KEY_LENGTH];
+        secret[..8].copy_from_slice(&(id as u64).to_be_bytes());
+        Self {
+            chain: Blockchain::new(),
+            miner: Address::from_public_key(&SigningKey::from_bytes(&secret).verifying_key()),
+            mined: 0,
+            reorg_depths: Vec::new(),
+        }
+    }
+
+    pub fn chain(&self) -> &Blockchain {
+        &self.chain
+    }
+
+    pub fn reorg_depths(&self) -> &[usize] {
+        &self.reorg_depths
+    }
+
+    /// Validates a block coming from the network and adds it to the local chain.
+    fn on_block(&mut self, block: Block, difficulty: usize) {
+        if block.is_hash_valid(difficulty) {
+            self.chain.add_block_safe(block);
+            self.resolve();
+        }
+    }
+
+    fn resolve(&mut self) {
+        if let Some(reorg) = self.chain.resolve_pending_forks() {
+            self.reorg_depths.push(reorg.depth());
+        }
+    }
+
+    /// Mines a new block on top of the main chain of this node.
+    fn mine(&mut self, id: NodeId, tick: u64, difficulty: usize) -> Block {
+        let transaction = SimpleTransaction::from_str(&format!("node {id} at tick {tick}"));
+        let mut block = self.chain.get_rewarded_candidate_block(vec![transaction.into()], &self.miner);
+        // Blocks are timestamped with the simulated clock, so that runs can be reproduced
+        block.set_timestamp(tick);
+        mine_sync(&mut block, difficulty);
+        self.chain.add_block_unsafe(block.clone()).expect("a candidate block extends the main chain");
+        self.resolve();
+        self.mined += 1;
+        block
+    }
+}
+
+/// Runs many nodes in a single process, on top of a simulated network and a simulated clock.
+///
+/// Given the same configuration, a simulation always produces the same result.
+pub struct Simulation {
+    config: SimulationConfig,
+    nodes: Vec<SimulatedNode>,
+    network: SimulatedNetwork,
+    /// Simulated clock, in ticks
+    now: u64,
+    /// Whether nodes are currently allowed to mine
+    mining: bool,
+    rng: StdRng,
+}
+
+impl Simulation {
+    pub fn new(config: SimulationConfig) -> Self {
+        Self {
+            nodes: (0..config.nodes).map(SimulatedNode::new).collect(),
+            network: SimulatedNetwork::new(&config),
+            now: 0,
+            mining: true,
+            rng: StdRng::seed_from_u64(config.seed),
+            config,
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn nodes(&self) -> &[SimulatedNode] {
+        &self.nodes
+    }
+
+    pub fn network_mut(&mut self) -> &mut SimulatedNetwork {
+        &mut self.network
+    }
+
+    /// Splits the network in groups of nodes that can only talk among themselves.
+    pub fn partition(&mut self, groups: &[Vec<NodeId>]) {
+        self.network.partition(groups);
+    }
+
+    /// Removes the partitions.
+    ///
+    /// Blocks mined while the network was split never reached the other groups, so every node
+    /// announces its main chain to let its peers catch up. Blocks already known are ignored.
+    pub fn heal(&mut self) {
+        self.network.heal();
+        for (id, node) in self.nodes.iter().enumerate() {
+            for block in node.chain.blocks().iter().skip(1) {
+                self.network.broadcast(id, block);
+            }
+        }
+    }
+
+    /// Allows or forbids nodes to mine new blocks.
+    pub fn set_mining(&mut self, mining: bool) {
+        self.mining = mining;
+    }
+
+    /// Advances the simulation by a single tick.
+    ///
+    /// Nodes first process the blocks delivered at this time, and then try to mine.
+    pub fn step(&mut self) {
+        for (to, block) in self.network.receive(self.now) {
+            self.nodes[to].on_block(block, self.config.difficulty);
+        }
+
+        if self.mining {
+            for id in 0..self.nodes.len() {
+                if self.rng.gen_bool(self.config.mining_probability) {
+                    let block = self.nodes[id].mine(id, self.now, self.config.difficulty);
+                    self.network.broadcast(id, &block);
+                }
+            }
+        }
+
+        self.now += 1;
+    }
+
+    /// Makes the given node mine a block right now, and broadcast it.
+    ///
+    /// This is useful to break ties between chains of the same length.
+    pub fn mine_on(&mut self, id: NodeId) {
+        let block = self.nodes[id].mine(id, self.now, self.config.difficulty);
+        self.network.broadcast(id, &block);
+    }
+
+    /// Advances the simulation by `ticks` ticks.
+    pub fn run(&mut self, ticks: u64) {
+        for _ in 0..ticks {
+            self.step();
+        }
+    }
+
+    /// Stops mining and lets all the messages in flight be delivered.
+    pub fn drain(&mut self) {
+        self.set_mining(false);
+        while !self.network.is_idle() {
+            self.step();
+        }
+    }
+
+    /// Returns the hash of the head of the main chain of every node.
+    pub fn tips(&self) -> Vec<String> {
+        self.nodes.iter().map(|node| node.chain.last_block().hash()).collect()
+    }
+
+    /// Returns true if all the nodes agree on the head of the main chain.
+    pub fn has_converged(&self) -> bool {
+        let tips = self.tips();
+        tips.iter().all(|tip| tip == &tips[0])
+    }
+
+    /// Returns the total number of blocks mined during the simulation.
+    pub fn mined_blocks(&self) -> u64 {
+        self.nodes.iter().map(|node| node.mined).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simulation::{Simulation, SimulationConfig};
+
+    #[test]
+    fn test_simulation_is_deterministic() {
+        let config = SimulationConfig { seed: 42, ..Default::default() };
+        let mut first = Simulation::new(config.clone());
+        let mut second = Simulation::new(config);
+        first.run(200);
+        second.run(200);
+        assert_eq!(first.tips(), second.tips());
+        assert_eq!(first.mined_blocks(), second.mined_blocks());
+    }
+
+    #[test]
+    fn test_nodes_converge_on_the_same_tip() {
+        let mut simulation = Simulation::new(SimulationConfig { seed: 7, ..Default::default() });
+        simulation.run(300);
+        simulation.drain();
+
+        // Mining may have stopped while two chains had the same length: one more block breaks the tie.
+        simulation.mine_on(0);
+        simulation.drain();
+
+        assert!(simulation.mined_blocks() > 0);
+        assert!(simulation.has_converged());
+        assert!(simulation.nodes()[0].chain().len() > 1);
+    }
+
+    #[test]
+    fn test_isolated_nodes_do_not_receive_blocks() {
+        let mut simulation = Simulation::new(SimulationConfig { nodes: 2, seed: 3, ..Default::default() });
+        simulation.partition(&[vec![0], vec![1]]);
+        simulation.run(300);
+        simulation.drain();
+
+        // Each node only knows about the blocks it mined itself
+        for node in simulation.nodes() {
+            assert_eq!(node.mined + 1, node.chain().len() as u64);
+        }
+    }
+}
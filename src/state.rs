@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+use crate::address::Address;
+use crate::blob::BlobStore;
+use crate::block::Block;
+use crate::contract::{ContractState, Execution, GAS_PRICE};
+use crate::message::MAX_MESSAGE_LEN;
+use crate::names::NameRegistry;
+use crate::notary::check_notarization;
+use crate::signed_transaction::{Payload, SignedTransaction};
+use crate::token::TokenLedger;
+use crate::transaction::{Transaction, TransactionError};
+
+/// Balances and nonces of all the accounts, their tokens, the names, the blobs and the contracts,
+/// obtained by replaying the signed transactions of the main chain.
+///
+/// An account is identified by its address.
+#[derive(Clone, Default)]
+pub struct Ledger {
+    /// Balances given to some accounts at the genesis, before any block
+    allocations: HashMap<Address, u64>,
+    balances: HashMap<Address, u64>,
+    /// Nonce of the last transaction of each account
+    nonces: HashMap<Address, u64>,
+    contracts: ContractState,
+    tokens: TokenLedger,
+    names: NameRegistry,
+    blobs: BlobStore,
+    /// Height of the last block applied
+    height: u64,
+}
+
+impl Ledger {
+    /// Creates a ledger in which all accounts are empty.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a ledger in which some accounts start with a balance.
+    pub fn with_allocations(allocations: impl IntoIterator<Item = (Address, u64)>) -> Self {
+        let allocations: HashMap<Address, u64> = allocations.into_iter().collect();
+        Self {
+            balances: allocations.clone(),
+            allocations,
+            ..Self::default()
+        }
+    }
+
+    pub fn balance(&self, account: &Address) -> u64 {
+        self.balances.get(account).copied().unwrap_or(0)
+    }
+
+    /// Returns the nonce of the last transaction of the account, or 0 if it never sent any.
+    pub fn nonce(&self, account: &Address) -> u64 {
+        self.nonces.get(account).copied().unwrap_or(0)
+    }
+
+    /// Returns the contracts deployed on the chain, and their storage.
+    pub fn contracts(&self) -> &ContractState {
+        &self.contracts
+    }
+
+    /// Returns the assets issued on the chain, and the tokens held by each account.
+    pub fn tokens(&self) -> &TokenLedger {
+        &self.tokens
+    }
+
+    /// Returns the names registered on the chain.
+    pub fn names(&self) -> &NameRegistry {
+        &self.names
+    }
+
+    /// Returns the chunks and the manifests of the blobs stored on the chain.
+    pub fn blobs(&self) -> &BlobStore {
+        &self.blobs
+    }
+
+    /// Returns the height of the last block applied to the ledger.
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    /// Checks that the transaction can be applied on the current balances, and that its nonce is
+    /// higher than the one of the last transaction of its sender. Deploys and calls of contracts
+    /// are executed: the sender must be able to pay for all the gas they are given, and an
+    /// execution that fails is still written. Names are checked at the height of the next block.
+    pub fn check_transaction(&self, tx: &Transaction) -> Result<(), TransactionError> {
+        if let Transaction::Signed(signed) = tx {
+            self.check_signed(signed)?;
+        }
+        Ok(())
+    }
+
+    /// Checks a signed transaction, and returns the execution of its contract, if any.
+    fn check_signed(&self, signed: &SignedTransaction) -> Result<Option<Execution>, TransactionError> {
+        let sender = signed.sender_address();
+        if signed.nonce() <= self.nonce(&sender) {
+            return Err(TransactionError::InvalidNonce);
+        }
+        if self.balance(&sender) < Self::debit(signed.payload(), signed.fee(), Self::gas_limit(signed.payload()))? {
+            return Err(TransactionError::NotEnoughSold);
+        }
+        self.tokens.check(&sender, signed.payload())?;
+        self.names.check(&sender, signed.payload(), self.height + 1)?;
+        self.blobs.check(signed.payload())?;
+        match signed.payload() {
+            Payload::Notarize { digest, metadata } => check_notarization(digest, metadata)?,
+            Payload::Encrypted(message) if message.len() > MAX_MESSAGE_LEN => return Err(TransactionError::Malformed),
+            _ => {}
+        }
+        let execution = match signed.payload() {
+            Payload::Deploy { code, gas_limit } => Some(self.contracts.deploy(&sender, signed.nonce(), code, *gas_limit)?),
+            Payload::Call { contract, input, gas_limit } => Some(self.contracts.call(&sender, contract, input, *gas_limit)?),
+            _ => None,
+        };
+        Ok(execution)
+    }
+
+    /// Updates the balances with the given transaction.
+    /// Nothing changes if the transaction would overdraw an account.
+    ///
+    /// The fee leaves the account of the sender: it is paid to the miner by the coinbase. The gas
+    /// used by a contract is paid as well, even if its execution failed, and is burned.
+    pub fn apply_transaction(&mut self, tx: &Transaction) -> Result<(), TransactionError> {
+        if let Transaction::Signed(signed) = tx {
+            let execution = self.check_signed(signed)?;
+            self.tokens.apply(&signed.sender_address(), signed.payload())?;
+            self.names.apply(&signed.sender_address(), signed.payload(), self.height + 1)?;
+            self.blobs.apply(signed.payload(), self.height + 1)?;
+            let gas_used = execution.as_ref().map_or(0, |execution| execution.gas_used);
+            let debit = Self::debit(signed.payload(), signed.fee(), gas_used)?;
+            *self.balances.entry(signed.sender_address()).or_default() -= debit;
+            self.nonces.insert(signed.sender_address(), signed.nonce());
+            if let Payload::Transfer { receiver, amount } = signed.payload() {
+                *self.balances.entry(*receiver).or_default() += amount;
+            }
+            if let Some(execution) = execution {
+                self.contracts.commit(execution);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns what a signed transaction takes from the account of its sender, when its contract
+    /// uses the given gas.
+    fn debit(payload: &Payload, fee: u64, gas: u64) -> Result<u64, TransactionError> {
+        // Every payload is listed, so that a new one moving coins can't be forgotten here
+        let amount = match payload {
+            Payload::Transfer { amount, .. } => *amount,
+            Payload::Message(_)
+            | Payload::Deploy { .. }
+            | Payload::Call { .. }
+            | Payload::Issue { .. }
+            | Payload::TokenTransfer { .. }
+            | Payload::Burn { .. }
+            | Payload::Notarize { .. }
+            | Payload::RegisterName { .. }
+            | Payload::UpdateName { .. }
+            | Payload::TransferName { .. }
+            | Payload::ReleaseName { .. }
+            | Payload::Chunk(_)
+            | Payload::Manifest(_)
+            | Payload::Encrypted(_) => 0,
+        };
+        gas.checked_mul(GAS_PRICE)
+            .and_then(|price| price.checked_add(amount))
+            .and_then(|debit| debit.checked_add(fee))
+            .ok_or(TransactionError::Malformed)
+    }
+
+    /// Returns the gas given to the contract deployed or called by a transaction.
+    fn gas_limit(payload: &Payload) -> u64 {
+        match payload {
+            Payload::Deploy { gas_limit, .. } | Payload::Call { gas_limit, .. } => *gas_limit,
+            _ => 0,
+        }
+    }
+
+    /// Updates the balances with all the transactions of the block, and checks that the state
+    /// root of the block commits to the contracts obtained.
+    /// Nothing changes if one of the transactions is refused.
+    pub fn apply_block(&mut self, block: &Block) -> Result<(), TransactionError> {
+        let mut ledger = self.clone();
+        for tx in block.transactions() {
+            ledger.apply_transaction(tx)?;
+        }
+        if ledger.contracts.root() != block.state_root() {
+            return Err(TransactionError::InvalidStateRoot);
+        }
+        ledger.height = block.index_in_chain();
+        *self = ledger;
+        Ok(())
+    }
+
+    /// Returns the balances obtained by replaying the given chain, starting from the allocations
+    /// of the genesis.
+    pub fn replay(&self, blocks: &[Block]) -> Result<Ledger, TransactionError> {
+        let mut ledger = Ledger::with_allocations(self.allocations.clone());
+        for block in blocks {
+            ledger.apply_block(block)?;
+        }
+        Ok(ledger)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::Block;
+    use crate::blockchain::{Blockchain, Placement};
+    use crate::client::Client;
+    use crate::state::Ledger;
+    use crate::transaction::TransactionError;
+
+    #[test]
+    fn test_transfer_updates_balances() {
+        let mut alice = Client::new();
+        let bob = Client::new();
+        let mut ledger = Ledger::with_allocations([(alice.address(), 100)]);
+
+        let tx = alice.transfer(bob.address(), 30).into();
+        assert_eq!(Ok(()), ledger.apply_transaction(&tx));
+        assert_eq!(70, ledger.balance(&alice.address()));
+        assert_eq!(30, ledger.balance(&bob.address()));
+    }
+
+    #[test]
+    fn test_overdraw_is_refused() {
+        let miner = Client::new().address();
+        let mut alice = Client::new();
+        let bob = Client::new();
+        let mut chain = Blockchain::with_allocations([(alice.address(), 10)]);
+
+        let block = Block::new_rewarded_after_block(alice.transfer(bob.address(), 11), chain.last_block(), &miner);
+        assert_eq!(false, chain.add_block_safe(block));
+        assert_eq!(1, chain.len());
+        assert_eq!(10, chain.ledger().balance(&alice.address()));
+    }
+
+    #[test]
+    fn test_balances_follow_reorgs() {
+        let miner = Client::new().address();
+        let mut alice = Client::new();
+        let bob = Client::new();
+        let carol = Client::new();
+        let mut chain = Blockchain::with_allocations([(alice.address(), 10)]);
+        let genesis = chain.last_block().clone();
+
+        // On the main chain, alice pays bob
+        chain.add_test_block(vec![alice.transfer(bob.address(), 10).into()]);
+        assert_eq!(10, chain.ledger().balance(&bob.address()));
+
+        // On a longer fork, she pays carol instead
+        let to_carol = Block::new_rewarded_after_block(alice.transfer(carol.address(), 10), &genesis, &miner);
+        let next = Block::new_rewarded_after_block(alice.sign("next"), &to_carol, &miner);
+        assert_eq!(Ok(Placement::Fork), chain.add_block(to_carol));
+        assert_eq!(Ok(Placement::Fork), chain.add_block(next));
+        assert_eq!(1, chain.resolve_pending_forks().unwrap().depth());
+
+        assert_eq!(0, chain.ledger().balance(&alice.address()));
+        assert_eq!(0, chain.ledger().balance(&bob.address()));
+        assert_eq!(10, chain.ledger().balance(&carol.address()));
+    }
+
+    #[test]
+    fn test_invalid_fork_is_abandoned() {
+        let miner = Client::new().address();
+        let mut alice = Client::new();
+        let bob = Client::new();
+        let mut chain = Blockchain::with_allocations([(alice.address(), 10)]);
+        let genesis = chain.last_block().clone();
+        chain.add_test_block(vec![alice.sign("main").into()]);
+
+        // A longer fork that spends more than alice owns
+        let b1 = Block::new_rewarded_after_block(alice.transfer(bob.address(), 10), &genesis, &miner);
+        let b2 = Block::new_rewarded_after_block(alice.transfer(bob.address(), 5), &b1, &miner);
+        assert_eq!(Ok(Placement::Fork), chain.add_block(b1));
+        assert_eq!(Ok(Placement::Fork), chain.add_block(b2));
+
+        assert!(chain.resolve_pending_forks().is_none());
+        assert_eq!(2, chain.len());
+        assert_eq!(Err(TransactionError::NotEnoughSold), chain.ledger().check_transaction(&alice.transfer(bob.address(), 11).into()));
+    }
+
+    #[test]
+    fn test_replayed_transaction_is_refused() {
+        let miner = Client::new().address();
+        let mut alice = Client::new();
+        let bob = Client::new();
+        let mut chain = Blockchain::with_allocations([(alice.address(), 10)]);
+
+        let first = alice.transfer(bob.address(), 3);
+        let second = alice.transfer(bob.address(), 3);
+        chain.add_test_block(vec![second.clone().into()]);
+
+        // Neither the same transaction, nor an older one, can be written after it
+        assert_eq!(Err(TransactionError::InvalidNonce), chain.check_transaction(&second.clone().into()));
+        assert_eq!(false, chain.add_block_safe(Block::new_rewarded_after_block(second, chain.last_block(), &miner)));
+        assert_eq!(false, chain.add_block_safe(Block::new_rewarded_after_block(first, chain.last_block(), &miner)));
+        assert_eq!(7, chain.ledger().balance(&alice.address()));
+        assert_eq!(2, chain.ledger().nonce(&alice.address()));
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use crate::address::Address;
+use crate::signed_transaction::Payload;
+
+/// Maximum length of the symbol of an asset.
+pub const MAX_SYMBOL_LEN: usize = 12;
+
+/// Reasons for a token transaction to be refused.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TokenError {
+    /// The symbol is empty, too long, or not made of uppercase letters and digits
+    InvalidSymbol,
+    /// No asset was issued with this symbol
+    UnknownAsset,
+    /// The asset exists, and the sender is not its issuer
+    NotIssuer,
+    /// The sender holds fewer tokens than it moves
+    NotEnoughTokens,
+    /// The supply of the asset would overflow
+    SupplyOverflow,
+}
+
+impl std::error::Error for TokenError {}
+
+impl Display for TokenError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenError::InvalidSymbol => write!(f, "invalid symbol"),
+            TokenError::UnknownAsset => write!(f, "unknown asset"),
+            TokenError::NotIssuer => write!(f, "only the issuer can mint"),
+            TokenError::NotEnoughTokens => write!(f, "not enough tokens"),
+            TokenError::SupplyOverflow => write!(f, "supply overflow"),
+        }
+    }
+}
+
+/// A fungible token issued on the chain.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Asset {
+    /// Account which issued the asset, the only one allowed to mint more of it
+    pub issuer: Address,
+    /// Tokens in circulation: minted, minus burnt
+    pub supply: u64,
+}
+
+/// Checks that a symbol is made of 1 to `MAX_SYMBOL_LEN` uppercase letters and digits.
+pub fn check_symbol(symbol: &str) -> Result<(), TokenError> {
+    let valid = !symbol.is_empty()
+        && symbol.len() <= MAX_SYMBOL_LEN
+        && symbol.bytes().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit());
+    if !valid {
+        return Err(TokenError::InvalidSymbol);
+    }
+    Ok(())
+}
+
+/// The assets issued on the main chain, and the balance of every account in each of them.
+///
+/// The first `Issue` of a symbol creates the asset, and makes its sender the issuer: only the
+/// issuer can mint more tokens later. Anyone can transfer or burn the tokens they hold.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TokenLedger {
+    assets: HashMap<String, Asset>,
+    balances: HashMap<(String, Address), u64>,
+}
+
+impl TokenLedger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn asset(&self, symbol: &str) -> Option<&Asset> {
+        self.assets.get(symbol)
+    }
+
+    pub fn balance(&self, symbol: &str, account: &Address) -> u64 {
+        self.balances.get(&(symbol.to_string(), *account)).copied().unwrap_or(0)
+    }
+
+    /// Returns the tokens held by an account, by symbol.
+    pub fn balances_of(&self, account: &Address) -> BTreeMap<String, u64> {
+        self.balances
+            .iter()
+            .filter(|((_, owner), amount)| owner == account && **amount > 0)
+            .map(|((symbol, _), amount)| (symbol.clone(), *amount))
+            .collect()
+    }
+
+    /// Checks that the sender can issue, transfer or burn the tokens of the payload. Other payloads
+    /// are always accepted.
+    pub fn check(&self, sender: &Address, payload: &Payload) -> Result<(), TokenError> {
+        match payload {
+            Payload::Issue { symbol, amount } => {
+                check_symbol(symbol)?;
+                match self.assets.get(symbol) {
+                    Some(asset) if asset.issuer != *sender => Err(TokenError::NotIssuer),
+                    Some(asset) => asset.supply.checked_add(*amount).map(|_| ()).ok_or(TokenError::SupplyOverflow),
+                    None => Ok(()),
+                }
+            }
+            Payload::TokenTransfer { symbol, amount, .. } | Payload::Burn { symbol, amount } => {
+                if !self.assets.contains_key(symbol) {
+                    return Err(TokenError::UnknownAsset);
+                }
+                if self.balance(symbol, sender) < *amount {
+                    return Err(TokenError::NotEnoughTokens);
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Applies the tokens of the payload. Nothing changes if it is refused.
+    pub fn apply(&mut self, sender: &Address, payload: &Payload) -> Result<(), TokenError> {
+        self.check(sender, payload)?;
+        match payload {
+            Payload::Issue { symbol, amount } => {
+                let asset = self.assets
+                    .entry(symbol.clone())
+                    .or_insert(Asset { issuer: *sender, supply: 0 });
+                asset.supply += amount;
+                *self.balances.entry((symbol.clone(), *sender)).or_default() += amount;
+            }
+            Payload::TokenTransfer { symbol, receiver, amount } => {
+                *self.balances.entry((symbol.clone(), *sender)).or_default() -= amount;
+                *self.balances.entry((symbol.clone(), *receiver)).or_default() += amount;
+            }
+            Payload::Burn { symbol, amount } => {
+                *self.balances.entry((symbol.clone(), *sender)).or_default() -= amount;
+                self.assets.get_mut(symbol).expect("checked above").supply -= amount;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::Block;
+    use crate::blockchain::Blockchain;
+    use crate::client::Client;
+    use crate::signed_transaction::Payload;
+    use crate::token::{check_symbol, TokenError};
+    use crate::transaction::TransactionError;
+
+    fn issue(symbol: &str, amount: u64) -> Payload {
+        Payload::Issue { symbol: symbol.to_string(), amount }
+    }
+
+    #[test]
+    fn test_issue_transfer_and_burn() {
+        let mut alice = Client::new();
+        let mut bob = Client::new();
+        let mut chain = Blockchain::new();
+
+        chain.add_test_block(vec![alice.sign_payload(issue("GOLD", 100), 0).into()]);
+        let transfer = Payload::TokenTransfer { symbol: "GOLD".to_string(), receiver: bob.address(), amount: 40 };
+        chain.add_test_block(vec![alice.sign_payload(transfer, 0).into()]);
+        let burn = Payload::Burn { symbol: "GOLD".to_string(), amount: 10 };
+        chain.add_test_block(vec![bob.sign_payload(burn, 0).into()]);
+
+        let tokens = chain.ledger().tokens();
+        assert_eq!(60, tokens.balance("GOLD", &alice.address()));
+        assert_eq!(30, tokens.balance("GOLD", &bob.address()));
+        assert_eq!(90, tokens.asset("GOLD").unwrap().supply);
+        assert_eq!(alice.address(), tokens.asset("GOLD").unwrap().issuer);
+
+        // Bob can't move more than he holds
+        let transfer = Payload::TokenTransfer { symbol: "GOLD".to_string(), receiver: alice.address(), amount: 31 };
+        let tx = bob.sign_payload(transfer, 0).into();
+        assert_eq!(Err(TransactionError::Token(TokenError::NotEnoughTokens)), chain.check_transaction(&tx));
+    }
+
+    #[test]
+    fn test_only_the_issuer_can_mint() {
+        let miner = Client::new().address();
+        let mut alice = Client::new();
+        let mut mallory = Client::new();
+        let mut chain = Blockchain::new();
+        chain.add_test_block(vec![alice.sign_payload(issue("GOLD", 100), 0).into()]);
+
+        let forged = mallory.sign_payload(issue("GOLD", 1000), 0);
+        assert_eq!(Err(TransactionError::Token(TokenError::NotIssuer)), chain.check_transaction(&forged.clone().into()));
+        assert_eq!(false, chain.add_block_safe(Block::new_rewarded_after_block(forged, chain.last_block(), &miner)));
+
+        // The issuer can mint more
+        chain.add_test_block(vec![alice.sign_payload(issue("GOLD", 50), 0).into()]);
+        assert_eq!(150, chain.ledger().tokens().asset("GOLD").unwrap().supply);
+        assert_eq!(0, chain.ledger().tokens().balance("GOLD", &mallory.address()));
+    }
+
+    #[test]
+    fn test_symbols() {
+        assert_eq!(Ok(()), check_symbol("USD2"));
+        assert_eq!(Err(TokenError::InvalidSymbol), check_symbol(""));
+        assert_eq!(Err(TokenError::InvalidSymbol), check_symbol("gold"));
+        assert_eq!(Err(TokenError::InvalidSymbol), check_symbol("ABCDEFGHIJKLM"));
+    }
+}
@@ -0,0 +1,263 @@
+use std::fmt::{Debug, Display, Formatter};
+use serde::{Deserialize, Serialize};
+use sha256::digest;
+use crate::address::Address;
+use crate::blob::BlobError;
+use crate::signed_transaction::{Payload, SignedTransaction};
+use crate::coinbase::Coinbase;
+use crate::contract::ContractError;
+use crate::names::NameError;
+use crate::script::ScriptError;
+use crate::simple_transaction::SimpleTransaction;
+use crate::token::TokenError;
+use crate::utxo::UtxoTransaction;
+use crate::validity::Validity;
+
+/// A transaction that can be written in a block.
+#[derive(Clone, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum Transaction {
+    /// Plain text, which anyone can submit
+    Simple(SimpleTransaction),
+    /// A transaction signed by its sender
+    Signed(Box<SignedTransaction>),
+    /// A transaction spending unspent outputs
+    Utxo(UtxoTransaction),
+    /// The reward of the miner of a block
+    Coinbase(Coinbase),
+}
+
+impl Transaction {
+    /// Returns the bytes representation of this transaction, used to compute the hash of blocks.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Transaction::Simple(tx) => [vec![SIMPLE_TAG], tx.to_bytes()].concat(),
+            Transaction::Signed(tx) => [vec![SIGNED_TAG], tx.to_bytes()].concat(),
+            Transaction::Utxo(tx) => [vec![UTXO_TAG], tx.to_bytes()].concat(),
+            Transaction::Coinbase(tx) => [vec![COINBASE_TAG], tx.to_bytes()].concat(),
+        }
+    }
+
+    /// Returns the canonical id of the transaction.
+    ///
+    /// UTXO transactions and coinbases keep the id by which their outputs are referenced. The id
+    /// of the other transactions is the hash of their bytes representation.
+    pub fn id(&self) -> String {
+        match self {
+            Transaction::Utxo(tx) => tx.id(),
+            Transaction::Coinbase(tx) => tx.id(),
+            _ => digest(self.to_bytes()),
+        }
+    }
+
+    /// Returns true if the account sends or receives something with this transaction.
+    pub fn involves(&self, address: &Address) -> bool {
+        match self {
+            Transaction::Simple(_) => false,
+            Transaction::Signed(tx) => {
+                tx.sender_address() == *address
+                    || matches!(tx.payload(), Payload::Transfer { receiver, .. } if receiver == address)
+                    || matches!(tx.payload(), Payload::Call { contract, .. } if contract == address)
+                    || matches!(tx.payload(), Payload::TokenTransfer { receiver, .. } if receiver == address)
+                    || matches!(tx.payload(), Payload::TransferName { new_owner, .. } if new_owner == address)
+                    || matches!(tx.payload(), Payload::Encrypted(message) if message.recipient == *address)
+            }
+            Transaction::Utxo(tx) => {
+                tx.inputs().iter().any(|input| input.witness.owner() == *address)
+                    || tx.outputs().iter().any(|output| output.owner == *address)
+            }
+            Transaction::Coinbase(tx) => tx.output().owner == *address,
+        }
+    }
+
+    /// Returns the blocks in which the transaction can be written. A coinbase is only limited by
+    /// its height.
+    pub fn validity(&self) -> Validity {
+        match self {
+            Transaction::Simple(tx) => *tx.validity(),
+            Transaction::Signed(tx) => *tx.validity(),
+            Transaction::Utxo(tx) => *tx.validity(),
+            Transaction::Coinbase(_) => Validity::default(),
+        }
+    }
+
+    /// Checks that this transaction can be accepted: signed transactions must carry a valid
+    /// signature from their sender.
+    ///
+    /// The signatures of UTXO transactions can only be checked against the outputs they spend,
+    /// so only their structure is checked here.
+    pub fn verify(&self) -> Result<(), TransactionError> {
+        match self {
+            Transaction::Simple(_) => Ok(()),
+            Transaction::Signed(tx) => tx.verify(),
+            Transaction::Utxo(tx) => tx.verify(),
+            Transaction::Coinbase(_) => Ok(()),
+        }
+    }
+}
+
+/// First byte of the bytes representation of a simple transaction.
+const SIMPLE_TAG: u8 = 0;
+
+/// First byte of the bytes representation of a signed transaction.
+const SIGNED_TAG: u8 = 1;
+
+/// First byte of the bytes representation of a UTXO transaction.
+const UTXO_TAG: u8 = 2;
+
+/// First byte of the bytes representation of a coinbase.
+const COINBASE_TAG: u8 = 3;
+
+impl From<SimpleTransaction> for Transaction {
+    fn from(tx: SimpleTransaction) -> Self {
+        Transaction::Simple(tx)
+    }
+}
+
+impl From<SignedTransaction> for Transaction {
+    fn from(tx: SignedTransaction) -> Self {
+        Transaction::Signed(Box::new(tx))
+    }
+}
+
+impl From<UtxoTransaction> for Transaction {
+    fn from(tx: UtxoTransaction) -> Self {
+        Transaction::Utxo(tx)
+    }
+}
+
+impl From<Coinbase> for Transaction {
+    fn from(tx: Coinbase) -> Self {
+        Transaction::Coinbase(tx)
+    }
+}
+
+impl Debug for Transaction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transaction::Simple(tx) => write!(f, "{tx:?}"),
+            Transaction::Signed(tx) => write!(f, "{tx:?}"),
+            Transaction::Utxo(tx) => write!(f, "{tx:?}"),
+            Transaction::Coinbase(tx) => write!(f, "{tx:?}"),
+        }
+    }
+}
+
+impl From<ScriptError> for TransactionError {
+    fn from(e: ScriptError) -> Self {
+        TransactionError::Script(e)
+    }
+}
+
+impl From<ContractError> for TransactionError {
+    fn from(e: ContractError) -> Self {
+        TransactionError::Contract(e)
+    }
+}
+
+impl From<TokenError> for TransactionError {
+    fn from(e: TokenError) -> Self {
+        TransactionError::Token(e)
+    }
+}
+
+impl From<NameError> for TransactionError {
+    fn from(e: NameError) -> Self {
+        TransactionError::Name(e)
+    }
+}
+
+impl From<BlobError> for TransactionError {
+    fn from(e: BlobError) -> Self {
+        TransactionError::Blob(e)
+    }
+}
+
+/// Appends a field to a canonical encoding: its length, followed by its bytes.
+///
+/// Prefixing each field with its length makes the encoding unambiguous.
+pub(crate) fn push_field(bytes: &mut Vec<u8>, field: &[u8]) {
+    bytes.extend_from_slice(&(field.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(field);
+}
+
+/// Reasons for a transaction to be refused.
+#[derive(Debug, PartialEq)]
+pub enum TransactionError {
+    InvalidSignature,
+    NotEnoughSold,
+    /// The transaction spends an output that does not exist, or that was already spent
+    MissingOutput,
+    /// The same output is spent twice in a transaction or in a block
+    DoubleSpend,
+    /// The transaction does not follow the format of its type
+    Malformed,
+    /// The coinbase is misplaced, or claims more than the subsidy and the fees of its block
+    InvalidCoinbase,
+    /// The transaction spends the output of a coinbase that does not have enough confirmations
+    ImmatureCoinbase,
+    /// The mempool is full of transactions paying a higher fee rate
+    FeeTooLow,
+    /// The nonce is not higher than the one of the last transaction of the sender
+    InvalidNonce,
+    /// A multisig output is spent with fewer signatures than its policy requires
+    NotEnoughSignatures,
+    /// The transaction can't be written before a later block
+    NotYetValid,
+    /// The transaction could only be written before an earlier block
+    Expired,
+    /// The block is older than its parent, or too far in the future
+    InvalidTimestamp,
+    /// A script refused to unlock an output
+    Script(ScriptError),
+    /// A contract could not be deployed, or its call failed
+    Contract(ContractError),
+    /// A token transaction breaks the rules of its asset
+    Token(TokenError),
+    /// A name operation is refused by the registry
+    Name(NameError),
+    /// A chunk or a manifest is refused by the blob store
+    Blob(BlobError),
+    /// The transaction is already written in the main chain, or appears twice in the block
+    DuplicateTransaction,
+    /// The transactions of the block are larger than `MAX_BLOCK_SIZE`
+    BlockTooLarge,
+    /// The state root of the block is not the one obtained by applying its transactions
+    InvalidStateRoot,
+    /// The hash of the block does not start with enough zeros
+    InvalidProofOfWork,
+    /// The block is already in the main chain or in one of the forks
+    KnownBlock,
+}
+
+impl std::error::Error for TransactionError {}
+
+impl Display for TransactionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransactionError::InvalidSignature => write!(f, "Invalid signature"),
+            TransactionError::NotEnoughSold => write!(f, "Not enough sold"),
+            TransactionError::MissingOutput => write!(f, "Spends a missing output"),
+            TransactionError::DoubleSpend => write!(f, "Double spend"),
+            TransactionError::Malformed => write!(f, "Malformed transaction"),
+            TransactionError::InvalidCoinbase => write!(f, "Invalid coinbase"),
+            TransactionError::ImmatureCoinbase => write!(f, "Spends an immature coinbase"),
+            TransactionError::FeeTooLow => write!(f, "Fee too low"),
+            TransactionError::InvalidNonce => write!(f, "Nonce already used"),
+            TransactionError::NotEnoughSignatures => write!(f, "Not enough signatures"),
+            TransactionError::NotYetValid => write!(f, "Not valid yet"),
+            TransactionError::Expired => write!(f, "Expired"),
+            TransactionError::InvalidTimestamp => write!(f, "Invalid block timestamp"),
+            TransactionError::Script(e) => write!(f, "Script error: {e}"),
+            TransactionError::Contract(e) => write!(f, "Contract error: {e}"),
+            TransactionError::Token(e) => write!(f, "Token error: {e}"),
+            TransactionError::Name(e) => write!(f, "Name error: {e}"),
+            TransactionError::Blob(e) => write!(f, "Blob error: {e}"),
+            TransactionError::DuplicateTransaction => write!(f, "Transaction already written"),
+            TransactionError::BlockTooLarge => write!(f, "Block too large"),
+            TransactionError::InvalidStateRoot => write!(f, "Invalid state root"),
+            TransactionError::InvalidProofOfWork => write!(f, "Invalid proof of work"),
+            TransactionError::KnownBlock => write!(f, "Block already known"),
+        }
+    }
+}
@@ -0,0 +1,602 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Formatter};
+use serde::{Deserialize, Serialize};
+use sha256::digest;
+use crate::block::Block;
+use crate::coinbase::{Coinbase, RewardSchedule};
+use ed25519_dalek::VerifyingKey;
+use crate::address::Address;
+use crate::multisig::{MultisigPolicy, PartialSignature};
+use crate::script::{verify_script, Script, ScriptContext};
+use crate::signed_transaction::verify_signature;
+use crate::transaction::{push_field, Transaction, TransactionError};
+use crate::validity::Validity;
+
+/// Id of the pseudo-transaction holding the outputs allocated at the genesis.
+pub const GENESIS_TX_ID: &str = "genesis";
+
+/// Reference to an output of a previous transaction.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Serialize, Deserialize)]
+pub struct OutPoint {
+    /// Id of the transaction which created the output
+    pub tx_id: String,
+    /// Position of the output in this transaction
+    pub index: u32,
+}
+
+/// Spends an output of a previous transaction.
+#[derive(Clone, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct TxInput {
+    pub previous_output: OutPoint,
+    pub witness: Witness,
+}
+
+/// Proves that the spender of an output is allowed to: the hash of the key, of the policy or of
+/// the locking script must be the address of the output, and the signatures or the scripts must
+/// be valid.
+#[derive(Clone, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum Witness {
+    /// Signature of the transaction by the owner of an output paid to the address of a key
+    Key { public_key: VerifyingKey, signature: Vec<u8> },
+    /// Signatures of the transaction by enough keys of the policy of an output paid to its address
+    Multisig { policy: MultisigPolicy, signatures: Vec<PartialSignature> },
+    /// The locking script of an output paid to its address, and the script unlocking it
+    Script { locking: Script, unlocking: Script },
+}
+
+impl Witness {
+    /// Returns the address of the outputs that this witness can spend.
+    pub fn owner(&self) -> Address {
+        match self {
+            Witness::Key { public_key, .. } => Address::from_public_key(public_key),
+            Witness::Multisig { policy, .. } => policy.address(),
+            Witness::Script { locking, .. } => locking.address(),
+        }
+    }
+
+    /// Checks the signatures of the transaction, or runs the scripts, in the given context.
+    fn verify(&self, context: &ScriptContext) -> Result<(), TransactionError> {
+        match self {
+            Witness::Key { public_key, signature } => verify_signature(public_key, context.signed_bytes, signature),
+            Witness::Multisig { policy, signatures } => policy.verify(context.signed_bytes, signatures),
+            Witness::Script { locking, unlocking } => Ok(verify_script(unlocking, locking, context)?),
+        }
+    }
+
+    fn push_bytes(&self, bytes: &mut Vec<u8>) {
+        match self {
+            Witness::Key { public_key, signature } => {
+                bytes.push(0);
+                bytes.extend_from_slice(public_key.as_bytes());
+                push_field(bytes, signature);
+            }
+            Witness::Multisig { policy, signatures } => {
+                bytes.push(1);
+                push_field(bytes, &policy.to_bytes());
+                bytes.extend_from_slice(&(signatures.len() as u32).to_be_bytes());
+                for partial in signatures {
+                    bytes.extend_from_slice(partial.key.as_bytes());
+                    push_field(bytes, &partial.signature);
+                }
+            }
+            Witness::Script { locking, unlocking } => {
+                bytes.push(2);
+                push_field(bytes, &locking.to_bytes());
+                push_field(bytes, &unlocking.to_bytes());
+            }
+        }
+    }
+}
+
+/// An amount that only the owner of the address can spend.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct TxOutput {
+    pub amount: u64,
+    pub owner: Address,
+}
+
+/// A transaction in the UTXO model: it consumes unspent outputs, and creates new ones.
+///
+/// The sum of the outputs can't exceed the sum of the inputs.
+#[derive(Clone, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct UtxoTransaction {
+    inputs: Vec<TxInput>,
+    outputs: Vec<TxOutput>,
+    /// The blocks in which the transaction can be written
+    #[serde(default)]
+    validity: Validity,
+}
+
+impl UtxoTransaction {
+    /// Builds a transaction from existing signatures. Use `Client::spend` to create a new one.
+    pub fn new(inputs: Vec<TxInput>, outputs: Vec<TxOutput>) -> Self {
+        Self { inputs, outputs, validity: Validity::default() }
+    }
+
+    /// Sets the validity of a transaction built with `new`. The validity is part of what the
+    /// owners of the inputs sign.
+    pub fn with_validity(mut self, validity: Validity) -> Self {
+        self.validity = validity;
+        self
+    }
+
+    /// Returns the canonical encoding of what the owners of the inputs sign: all the spent
+    /// outputs, all the created outputs, and the validity.
+    pub fn signed_bytes(inputs: &[OutPoint], outputs: &[TxOutput], validity: &Validity) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(inputs.len() as u32).to_be_bytes());
+        for input in inputs {
+            push_field(&mut bytes, input.tx_id.as_bytes());
+            bytes.extend_from_slice(&input.index.to_be_bytes());
+        }
+        bytes.extend_from_slice(&(outputs.len() as u32).to_be_bytes());
+        for output in outputs {
+            bytes.extend_from_slice(&output.amount.to_be_bytes());
+            bytes.extend_from_slice(output.owner.as_bytes());
+        }
+        bytes.extend_from_slice(&validity.to_bytes());
+        bytes
+    }
+
+    fn own_signed_bytes(&self) -> Vec<u8> {
+        let inputs: Vec<OutPoint> = self.inputs.iter().map(|input| input.previous_output.clone()).collect();
+        Self::signed_bytes(&inputs, &self.outputs, &self.validity)
+    }
+
+    /// Returns the id of the transaction, used to reference its outputs.
+    ///
+    /// Signatures are not part of the id, so that they can't be altered to change it.
+    pub fn id(&self) -> String {
+        digest(self.own_signed_bytes())
+    }
+
+    /// Returns the bytes representation of this transaction, including its witnesses.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.own_signed_bytes();
+        for input in &self.inputs {
+            input.witness.push_bytes(&mut bytes);
+        }
+        bytes
+    }
+
+    /// Checks the rules that do not depend on the chain: the transaction spends and creates
+    /// something, never spends the same output twice, its multisig policies can be satisfied, and
+    /// its scripts are within the limits.
+    pub fn verify(&self) -> Result<(), TransactionError> {
+        if self.inputs.is_empty() || self.outputs.is_empty() {
+            return Err(TransactionError::Malformed);
+        }
+        for input in &self.inputs {
+            match &input.witness {
+                Witness::Key { .. } => {}
+                Witness::Multisig { policy, .. } => policy.check()?,
+                Witness::Script { locking, unlocking } => {
+                    locking.check()?;
+                    unlocking.check()?;
+                }
+            }
+        }
+        let mut spent = HashSet::new();
+        if !self.inputs.iter().all(|input| spent.insert(&input.previous_output)) {
+            return Err(TransactionError::DoubleSpend);
+        }
+        Ok(())
+    }
+
+    pub fn inputs(&self) -> &[TxInput] {
+        &self.inputs
+    }
+
+    pub fn outputs(&self) -> &[TxOutput] {
+        &self.outputs
+    }
+
+    pub fn validity(&self) -> &Validity {
+        &self.validity
+    }
+
+    /// Returns a reference to each output created by this transaction.
+    fn created_outputs(&self) -> impl Iterator<Item = (OutPoint, &TxOutput)> {
+        let tx_id = self.id();
+        self.outputs.iter().enumerate().map(move |(index, output)| {
+            (OutPoint { tx_id: tx_id.clone(), index: index as u32 }, output)
+        })
+    }
+}
+
+impl Debug for UtxoTransaction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let amount: u64 = self.outputs.iter().map(|output| output.amount).sum();
+        write!(f, "{} inputs -> {} outputs ({amount})", self.inputs.len(), self.outputs.len())
+    }
+}
+
+/// The set of all unspent outputs of the main chain.
+///
+/// It is updated incrementally when blocks are connected to or disconnected from the main chain.
+#[derive(Clone, Default)]
+pub struct UtxoSet {
+    unspent: HashMap<OutPoint, TxOutput>,
+    /// For each connected block, the outputs it spent, to restore them if it is disconnected
+    undo: HashMap<String, Vec<(OutPoint, TxOutput)>>,
+    /// Height of the block of each coinbase of the main chain, keyed by the id of the coinbase
+    coinbases: HashMap<String, u64>,
+}
+
+impl UtxoSet {
+    /// Creates an empty set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a set holding the given outputs, allocated at the genesis.
+    pub fn with_allocations(allocations: impl IntoIterator<Item = TxOutput>) -> Self {
+        let unspent = allocations
+            .into_iter()
+            .enumerate()
+            .map(|(index, output)| (OutPoint { tx_id: GENESIS_TX_ID.to_string(), index: index as u32 }, output))
+            .collect();
+        Self { unspent, undo: HashMap::new(), coinbases: HashMap::new() }
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&TxOutput> {
+        self.unspent.get(outpoint)
+    }
+
+    /// Returns all the unspent outputs that the given key can spend.
+    pub fn outputs_of(&self, owner: &Address) -> Vec<(OutPoint, TxOutput)> {
+        self.unspent
+            .iter()
+            .filter(|(_, output)| &output.owner == owner)
+            .map(|(outpoint, output)| (outpoint.clone(), output.clone()))
+            .collect()
+    }
+
+    pub fn balance(&self, owner: &Address) -> u64 {
+        self.outputs_of(owner).iter().map(|(_, output)| output.amount).sum()
+    }
+
+    /// Checks that the transaction only spends unspent and mature outputs, with the signature of
+    /// their owner, enough signatures of their multisig policy, or scripts unlocking them, and that
+    /// it does not create more than it spends.
+    ///
+    /// `height` and `timestamp` are those of the block in which the transaction would be written.
+    /// A coinbase is never valid on its own: it can only be the first transaction of a block.
+    pub fn check_transaction(&self, tx: &Transaction, height: u64, timestamp: u64, rewards: &RewardSchedule) -> Result<(), TransactionError> {
+        match tx {
+            Transaction::Utxo(tx) => self.check_utxo_transaction(tx, &[], height, timestamp, rewards).map(|_| ()),
+            Transaction::Coinbase(_) => Err(TransactionError::InvalidCoinbase),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the fee paid by a transaction to the miner. Signed transactions declare their fee,
+    /// while UTXO transactions pay what they spend and do not create. Invalid ones pay no fee.
+    pub fn fee(&self, tx: &Transaction) -> u64 {
+        let tx = match tx {
+            Transaction::Utxo(tx) => tx,
+            Transaction::Signed(tx) => return tx.fee(),
+            _ => return 0,
+        };
+        let total_in = tx.inputs()
+            .iter()
+            .filter_map(|input| self.unspent.get(&input.previous_output))
+            .map(|output| output.amount)
+            .sum::<u64>();
+        let total_out = tx.outputs().iter().map(|output| output.amount).sum::<u64>();
+        total_in.saturating_sub(total_out)
+    }
+
+    /// Returns the fee of the transaction if it is valid.
+    ///
+    /// `spent` holds the outputs already spent by the previous transactions of the same block.
+    fn check_utxo_transaction(
+        &self,
+        tx: &UtxoTransaction,
+        spent: &[(OutPoint, TxOutput)],
+        height: u64,
+        timestamp: u64,
+        rewards: &RewardSchedule,
+    ) -> Result<u64, TransactionError> {
+        let signed_bytes = tx.own_signed_bytes();
+        let context = ScriptContext { signed_bytes: &signed_bytes, height, timestamp };
+        let mut total_in: u64 = 0;
+        for input in tx.inputs() {
+            let Some(output) = self.unspent.get(&input.previous_output) else {
+                return if spent.iter().any(|(outpoint, _)| outpoint == &input.previous_output) {
+                    Err(TransactionError::DoubleSpend)
+                } else {
+                    Err(TransactionError::MissingOutput)
+                };
+            };
+            if let Some(created) = self.coinbases.get(&input.previous_output.tx_id) {
+                if height < created + rewards.maturity {
+                    return Err(TransactionError::ImmatureCoinbase);
+                }
+            }
+            if input.witness.owner() != output.owner {
+                return Err(TransactionError::InvalidSignature);
+            }
+            input.witness.verify(&context)?;
+            total_in = total_in.checked_add(output.amount).ok_or(TransactionError::Malformed)?;
+        }
+
+        let total_out = tx.outputs()
+            .iter()
+            .try_fold(0u64, |total, output| total.checked_add(output.amount))
+            .ok_or(TransactionError::Malformed)?;
+        if total_out > total_in {
+            return Err(TransactionError::NotEnoughSold);
+        }
+        Ok(total_in - total_out)
+    }
+
+    /// Checks a transaction written in a block after those that spent the outputs of `spent`, and
+    /// applies it: the outputs it consumes are moved to `spent`, and those it creates are added.
+    /// Nothing changes if it is invalid.
+    ///
+    /// This is what `connect_block` does for each transaction, to fill a block one transaction at
+    /// a time. A coinbase is refused, since it can only be the first transaction of a block.
+    pub fn apply_transaction(
+        &mut self,
+        tx: &Transaction,
+        spent: &mut Vec<(OutPoint, TxOutput)>,
+        height: u64,
+        timestamp: u64,
+        rewards: &RewardSchedule,
+    ) -> Result<(), TransactionError> {
+        match tx {
+            Transaction::Utxo(tx) => self.apply_utxo_transaction(tx, spent, height, timestamp, rewards).map(|_| ()),
+            Transaction::Coinbase(_) => Err(TransactionError::InvalidCoinbase),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks and applies a UTXO transaction as `apply_transaction` does, and returns its fee.
+    fn apply_utxo_transaction(
+        &mut self,
+        tx: &UtxoTransaction,
+        spent: &mut Vec<(OutPoint, TxOutput)>,
+        height: u64,
+        timestamp: u64,
+        rewards: &RewardSchedule,
+    ) -> Result<u64, TransactionError> {
+        let fee = self.check_utxo_transaction(tx, spent, height, timestamp, rewards)?;
+        for input in tx.inputs() {
+            let output = self.unspent.remove(&input.previous_output).unwrap();
+            spent.push((input.previous_output.clone(), output));
+        }
+        for (outpoint, output) in tx.created_outputs() {
+            self.unspent.insert(outpoint, output.clone());
+        }
+        Ok(fee)
+    }
+
+    /// Spends the outputs consumed by the UTXO transactions of the block, and adds the outputs they
+    /// create, including the reward of the miner. Nothing changes if one of the transactions is
+    /// invalid, if the block has no coinbase, or if the coinbase claims more than the subsidy and
+    /// the fees of the block.
+    pub fn connect_block(&mut self, block: &Block, rewards: &RewardSchedule) -> Result<(), TransactionError> {
+        let height = block.index_in_chain();
+        let coinbase = block_coinbase(block)?;
+
+        let mut spent = Vec::new();
+        let mut connected = Vec::new();
+        // The fees of the signed transactions are checked by the ledger
+        let mut fees: u64 = block.transactions()
+            .iter()
+            .filter_map(|tx| match tx {
+                Transaction::Signed(tx) => Some(tx.fee()),
+                _ => None,
+            })
+            .fold(0, u64::saturating_add);
+        for tx in utxo_transactions(block) {
+            let fee = match self.apply_utxo_transaction(tx, &mut spent, height, block.timestamp(), rewards) {
+                Ok(fee) => fee,
+                Err(e) => {
+                    self.undo_transactions(&connected, spent);
+                    return Err(e);
+                }
+            };
+            fees = fees.saturating_add(fee);
+            connected.push(tx);
+        }
+
+        // The output of the coinbase is added last, so it can't be spent in its own block.
+        if coinbase.height() != height || coinbase.output().amount > rewards.subsidy(height).saturating_add(fees) {
+            self.undo_transactions(&connected, spent);
+            return Err(TransactionError::InvalidCoinbase);
+        }
+        self.unspent.insert(coinbase.outpoint(), coinbase.output().clone());
+        self.coinbases.insert(coinbase.id(), height);
+        self.undo.insert(block.hash(), spent);
+        Ok(())
+    }
+
+    /// Reverts `connect_block`: the outputs created by the block are removed, and the outputs it
+    /// spent are unspent again.
+    pub fn disconnect_block(&mut self, block: &Block) {
+        if let Some(spent) = self.undo.remove(&block.hash()) {
+            if let Ok(coinbase) = block_coinbase(block) {
+                self.unspent.remove(&coinbase.outpoint());
+                self.coinbases.remove(&coinbase.id());
+            }
+            let transactions: Vec<&UtxoTransaction> = utxo_transactions(block).collect();
+            self.undo_transactions(&transactions, spent);
+        }
+    }
+
+    fn undo_transactions(&mut self, transactions: &[&UtxoTransaction], spent: Vec<(OutPoint, TxOutput)>) {
+        // Restore first: an output created and spent in the same block is then removed below.
+        for (outpoint, output) in spent {
+            self.unspent.insert(outpoint, output);
+        }
+        for tx in transactions {
+            for (outpoint, _) in tx.created_outputs() {
+                self.unspent.remove(&outpoint);
+            }
+        }
+    }
+}
+
+/// Returns the coinbase of the block, which must be its first transaction and the only one.
+fn block_coinbase(block: &Block) -> Result<&Coinbase, TransactionError> {
+    let mut coinbases = block.transactions()
+        .iter()
+        .enumerate()
+        .filter_map(|(position, tx)| match tx {
+            Transaction::Coinbase(coinbase) => Some((position, coinbase)),
+            _ => None,
+        });
+    match (coinbases.next(), coinbases.next()) {
+        (Some((0, coinbase)), None) => Ok(coinbase),
+        _ => Err(TransactionError::InvalidCoinbase),
+    }
+}
+
+fn utxo_transactions(block: &Block) -> impl Iterator<Item = &UtxoTransaction> {
+    block.transactions().iter().filter_map(|tx| match tx {
+        Transaction::Utxo(tx) => Some(tx),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::Block;
+    use crate::blockchain::{Blockchain, Placement};
+    use crate::client::Client;
+    use crate::coinbase::{Coinbase, RewardSchedule};
+    use crate::state::Ledger;
+    use crate::transaction::{Transaction, TransactionError};
+    use crate::utxo::{OutPoint, TxOutput, UtxoSet, GENESIS_TX_ID};
+
+    fn genesis_output(index: u32) -> OutPoint {
+        OutPoint { tx_id: GENESIS_TX_ID.to_string(), index }
+    }
+
+    fn chain_with_allocation(client: &Client, amount: u64) -> Blockchain {
+        let utxos = UtxoSet::with_allocations([TxOutput { amount, owner: client.address() }]);
+        Blockchain::with_genesis(Ledger::new(), utxos)
+    }
+
+    #[test]
+    fn test_spend_creates_new_outputs() {
+        let alice = Client::new();
+        let bob = Client::new();
+        let mut chain = chain_with_allocation(&alice, 50);
+
+        let tx = alice.spend(vec![genesis_output(0)], vec![
+            TxOutput { amount: 20, owner: bob.address() },
+            TxOutput { amount: 30, owner: alice.address() },
+        ]);
+        chain.add_test_block(vec![tx.into()]);
+        assert_eq!(20, chain.utxos().balance(&bob.address()));
+        assert_eq!(30, chain.utxos().balance(&alice.address()));
+        assert!(chain.utxos().get(&genesis_output(0)).is_none());
+    }
+
+    #[test]
+    fn test_only_the_owner_can_spend() {
+        let alice = Client::new();
+        let bob = Client::new();
+        let chain = chain_with_allocation(&alice, 50);
+
+        let tx: Transaction = bob.spend(vec![genesis_output(0)], vec![TxOutput { amount: 50, owner: bob.address() }]).into();
+        assert_eq!(Err(TransactionError::InvalidSignature), chain.check_transaction(&tx));
+    }
+
+    #[test]
+    fn test_double_spend_in_the_same_block() {
+        let alice = Client::new();
+        let bob = Client::new();
+        let carol = Client::new();
+        let mut chain = chain_with_allocation(&alice, 50);
+
+        let to_bob = alice.spend(vec![genesis_output(0)], vec![TxOutput { amount: 50, owner: bob.address() }]);
+        let to_carol = alice.spend(vec![genesis_output(0)], vec![TxOutput { amount: 50, owner: carol.address() }]);
+        let coinbase = Coinbase::new(1, 0, Client::new().address());
+        let block = Block::with_transactions(vec![coinbase.into(), to_bob.into(), to_carol.into()], chain.last_block());
+
+        assert_eq!(false, chain.add_block_safe(block));
+        assert_eq!(50, chain.utxos().balance(&alice.address()));
+        assert_eq!(0, chain.utxos().balance(&bob.address()));
+    }
+
+    #[test]
+    fn test_transactions_applied_one_at_a_time() {
+        let alice = Client::new();
+        let bob = Client::new();
+        let mut utxos = UtxoSet::with_allocations([TxOutput { amount: 50, owner: alice.address() }]);
+        let rewards = RewardSchedule::default();
+        let mut spent = Vec::new();
+
+        let to_bob = alice.spend(vec![genesis_output(0)], vec![TxOutput { amount: 50, owner: bob.address() }]).into();
+        let again = alice.spend(vec![genesis_output(0)], vec![TxOutput { amount: 50, owner: alice.address() }]).into();
+        assert_eq!(Ok(()), utxos.apply_transaction(&to_bob, &mut spent, 1, 0, &rewards));
+        assert_eq!(Err(TransactionError::DoubleSpend), utxos.apply_transaction(&again, &mut spent, 1, 0, &rewards));
+        let coinbase = Coinbase::new(1, 0, alice.address()).into();
+        assert_eq!(Err(TransactionError::InvalidCoinbase), utxos.apply_transaction(&coinbase, &mut spent, 1, 0, &rewards));
+
+        assert_eq!(1, spent.len());
+        assert_eq!(50, utxos.balance(&bob.address()));
+        assert_eq!(0, utxos.balance(&alice.address()));
+    }
+
+    #[test]
+    fn test_double_spend_against_the_chain() {
+        let miner = Client::new().address();
+        let alice = Client::new();
+        let bob = Client::new();
+        let mut chain = chain_with_allocation(&alice, 50);
+
+        let to_bob = alice.spend(vec![genesis_output(0)], vec![TxOutput { amount: 50, owner: bob.address() }]);
+        chain.add_test_block(vec![to_bob.into()]);
+
+        let again: Transaction = alice.spend(vec![genesis_output(0)], vec![TxOutput { amount: 50, owner: alice.address() }]).into();
+        assert_eq!(Err(TransactionError::MissingOutput), chain.check_transaction(&again));
+        assert_eq!(false, chain.add_block_safe(Block::new_rewarded_after_block(again, chain.last_block(), &miner)));
+    }
+
+    #[test]
+    fn test_cannot_create_more_than_spent() {
+        let alice = Client::new();
+        let chain = chain_with_allocation(&alice, 50);
+        let tx: Transaction = alice.spend(vec![genesis_output(0)], vec![TxOutput { amount: 51, owner: alice.address() }]).into();
+        assert_eq!(Err(TransactionError::NotEnoughSold), chain.check_transaction(&tx));
+    }
+
+    #[test]
+    fn test_outputs_follow_reorgs() {
+        let miner = Client::new().address();
+        let mut alice = Client::new();
+        let bob = Client::new();
+        let carol = Client::new();
+        let mut chain = chain_with_allocation(&alice, 50);
+        let genesis = chain.last_block().clone();
+
+        // On the main chain, alice pays bob, who spends it in the same block
+        let to_bob = alice.spend(vec![genesis_output(0)], vec![TxOutput { amount: 50, owner: bob.address() }]);
+        let from_bob = bob.spend(vec![OutPoint { tx_id: to_bob.id(), index: 0 }], vec![TxOutput { amount: 50, owner: bob.address() }]);
+        let coinbase = Coinbase::new(1, 0, miner);
+        let block = Block::with_transactions(vec![coinbase.into(), to_bob.into(), from_bob.into()], &genesis);
+        assert_eq!(true, chain.add_block_safe(block));
+        assert_eq!(50, chain.utxos().balance(&bob.address()));
+
+        // On a longer fork, she pays carol instead
+        let to_carol = alice.spend(vec![genesis_output(0)], vec![TxOutput { amount: 50, owner: carol.address() }]);
+        let b1 = Block::new_rewarded_after_block(to_carol, &genesis, &miner);
+        let b2 = Block::new_rewarded_after_block(alice.sign("next"), &b1, &miner);
+        assert_eq!(Ok(Placement::Fork), chain.add_block(b1));
+        assert_eq!(Ok(Placement::Fork), chain.add_block(b2));
+        assert_eq!(1, chain.resolve_pending_forks().unwrap().depth());
+
+        assert_eq!(0, chain.utxos().balance(&bob.address()));
+        assert_eq!(50, chain.utxos().balance(&carol.address()));
+    }
+}
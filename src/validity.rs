@@ -0,0 +1,108 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use crate::transaction::TransactionError;
+
+/// How far in the future, in seconds, the timestamp of a received block can be.
+pub const MAX_FUTURE_DRIFT: u64 = 2 * 60 * 60;
+
+/// Returns the current time, in seconds since the Unix epoch.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// A point of the chain, given as the height of a block or as the timestamp of a block.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum Lock {
+    Height(u64),
+    /// Seconds since the Unix epoch
+    Timestamp(u64),
+}
+
+impl Lock {
+    /// Returns true if a block of the given height and timestamp is at or after this point.
+    pub fn is_reached(&self, height: u64, timestamp: u64) -> bool {
+        match self {
+            Lock::Height(lock) => height >= *lock,
+            Lock::Timestamp(lock) => timestamp >= *lock,
+        }
+    }
+
+    fn push_bytes(lock: &Option<Lock>, bytes: &mut Vec<u8>) {
+        match lock {
+            None => bytes.push(0),
+            Some(Lock::Height(height)) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&height.to_be_bytes());
+            }
+            Some(Lock::Timestamp(timestamp)) => {
+                bytes.push(2);
+                bytes.extend_from_slice(&timestamp.to_be_bytes());
+            }
+        }
+    }
+}
+
+/// The blocks in which a transaction can be written.
+///
+/// By default, a transaction is valid in any block.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct Validity {
+    /// The transaction can't be written in a block before this point
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub valid_after: Option<Lock>,
+    /// The transaction can't be written in a block at or after this point
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<Lock>,
+}
+
+impl Validity {
+    pub fn new(valid_after: Option<Lock>, expires_at: Option<Lock>) -> Self {
+        Self { valid_after, expires_at }
+    }
+
+    /// Checks that the transaction can be written in a block of the given height and timestamp.
+    pub fn check(&self, height: u64, timestamp: u64) -> Result<(), TransactionError> {
+        if self.valid_after.is_some_and(|lock| !lock.is_reached(height, timestamp)) {
+            return Err(TransactionError::NotYetValid);
+        }
+        if self.is_expired(height, timestamp) {
+            return Err(TransactionError::Expired);
+        }
+        Ok(())
+    }
+
+    /// Returns true if the transaction can't be written in a block of the given height and
+    /// timestamp, nor in any later block.
+    pub fn is_expired(&self, height: u64, timestamp: u64) -> bool {
+        self.expires_at.is_some_and(|lock| lock.is_reached(height, timestamp))
+    }
+
+    /// Returns the canonical encoding of the validity, which is part of what is signed.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        Lock::push_bytes(&self.valid_after, &mut bytes);
+        Lock::push_bytes(&self.expires_at, &mut bytes);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transaction::TransactionError;
+    use crate::validity::{Lock, Validity};
+
+    #[test]
+    fn test_validity_window() {
+        let validity = Validity::new(Some(Lock::Height(10)), Some(Lock::Timestamp(1000)));
+        assert_eq!(Err(TransactionError::NotYetValid), validity.check(9, 0));
+        assert_eq!(Ok(()), validity.check(10, 999));
+        assert_eq!(Err(TransactionError::Expired), validity.check(10, 1000));
+        assert_eq!(true, validity.is_expired(10, 1000));
+        assert_eq!(Ok(()), Validity::default().check(0, 0));
+    }
+}